@@ -0,0 +1,1102 @@
+//! The OpenAI-compatible chat completions API.
+
+use crate::cache::ResponseCache;
+use crate::message::{ChatGptMessage, Role, ToolCall};
+use crate::trace::{TraceEntry, TraceFile};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::ValueEnum;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::multipart::{Form, Part};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Serialize)]
+struct ChatGptRequest<'a> {
+    #[serde(flatten)]
+    params: &'a ChatGptParams,
+    messages: ApiMessages<'a>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+/// The model and sampling parameters sent with each request.
+#[derive(Clone, Default, Serialize)]
+pub struct ChatGptParams {
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// What reasoning models take in place of `max_tokens`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+    /// How many alternative responses to ask for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    /// Definitions of the tools the model may call.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<serde_json::Value>,
+    /// Backend-specific fields from --extra-param.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+/// Sampling fields that reasoning models reject.
+const SAMPLING_PARAMS: &[&str] = &[
+    "top_p",
+    "presence_penalty",
+    "frequency_penalty",
+    "logit_bias",
+];
+
+/// Whether a model is one of OpenAI's reasoning models, such as o1 or
+/// o3-mini, which reject sampling settings.
+pub fn is_reasoning_model(model: &str) -> bool {
+    let name = model.rsplit('/').next().unwrap_or(model);
+    let o_series = name
+        .strip_prefix('o')
+        .and_then(|rest| rest.chars().next())
+        .is_some_and(|c| c.is_ascii_digit());
+    o_series || (name.starts_with("gpt-5") && !name.starts_with("gpt-5-chat"))
+}
+
+impl ChatGptParams {
+    /// The most tokens a response may have, however it is sent.
+    pub fn token_limit(&self) -> Option<u32> {
+        self.max_tokens.or(self.max_completion_tokens)
+    }
+
+    /// Leaves out what a reasoning model would reject and renames
+    /// `max_tokens`, returning the fields left out.
+    pub fn adapt_to_reasoning_model(&mut self) -> Vec<String> {
+        if !is_reasoning_model(&self.model) {
+            return Vec::new();
+        }
+        self.max_completion_tokens = self.max_tokens.take();
+        let mut dropped = Vec::new();
+        if self.temperature.take().is_some() {
+            dropped.push("temperature".to_string());
+        }
+        for name in SAMPLING_PARAMS {
+            if self.extra.remove(*name).is_some() {
+                dropped.push(name.to_string());
+            }
+        }
+        dropped
+    }
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    #[serde(flatten)]
+    params: &'a ChatGptParams,
+    prompt: String,
+    stop: &'a [&'a str],
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    model: Option<String>,
+    choices: Vec<CompletionChoice>,
+    usage: Option<ChatGptUsage>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChoice {
+    text: String,
+    finish_reason: Option<String>,
+}
+
+impl From<CompletionResponse> for ChatGptResponse {
+    fn from(response: CompletionResponse) -> ChatGptResponse {
+        let choices = response
+            .choices
+            .into_iter()
+            .map(|choice| ChatGptChoice {
+                message: ChatGptMessage::new(
+                    Role::Assistant,
+                    choice.text.trim().to_string(),
+                ),
+                finish_reason: choice.finish_reason,
+            })
+            .collect();
+        ChatGptResponse {
+            model: response.model,
+            choices,
+            usage: response.usage,
+            rate_limits: None,
+        }
+    }
+}
+
+const COMPLETION_STOP: &[&str] = &["\nUser:", "\nSystem:"];
+
+/// Flattens a conversation into a single role-labelled prompt, ending with
+/// an open assistant turn for the model to complete, which a prefilled
+/// message at the end begins.
+fn completion_prompt(messages: &[&ChatGptMessage]) -> String {
+    let (prefill, messages) = match messages.split_last() {
+        Some((last, rest)) if last.prefill.is_some() => (Some(last), rest),
+        _ => (None, messages),
+    };
+    let mut prompt = String::new();
+    for message in messages {
+        let label = message.role.title();
+        prompt.push_str(&format!("{}: {}\n\n", label, message.content.trim()));
+    }
+    prompt.push_str("Assistant:");
+    if let Some(prefill) = prefill {
+        prompt.push(' ');
+        prompt.push_str(&prefill.content);
+    }
+    prompt
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatGptResponse {
+    pub model: Option<String>,
+    pub choices: Vec<ChatGptChoice>,
+    pub usage: Option<ChatGptUsage>,
+    #[serde(skip)]
+    pub rate_limits: Option<RateLimits>,
+}
+
+/// One of the account's rate limits, as of a response.
+#[derive(Debug)]
+pub struct RateLimit {
+    pub remaining: String,
+    pub limit: Option<String>,
+    pub reset: Option<String>,
+}
+
+/// What is left of the account's request and token rate limits, from the
+/// `x-ratelimit-*` headers that OpenAI sends with each response.
+#[derive(Debug)]
+pub struct RateLimits {
+    pub requests: Option<RateLimit>,
+    pub tokens: Option<RateLimit>,
+}
+
+impl RateLimits {
+    pub fn from_headers(headers: &HeaderMap) -> Option<RateLimits> {
+        let header = |name: String| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let limit = |kind: &str| {
+            Some(RateLimit {
+                remaining: header(format!("x-ratelimit-remaining-{}", kind))?,
+                limit: header(format!("x-ratelimit-limit-{}", kind)),
+                reset: header(format!("x-ratelimit-reset-{}", kind)),
+            })
+        };
+        let limits = RateLimits {
+            requests: limit("requests"),
+            tokens: limit("tokens"),
+        };
+        (limits.requests.is_some() || limits.tokens.is_some()).then_some(limits)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChatGptChoice {
+    pub message: ChatGptMessage,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatGptUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Deserialize)]
+struct ChatGptChunk {
+    model: Option<String>,
+    choices: Vec<ChatGptChunkChoice>,
+    usage: Option<ChatGptUsage>,
+}
+
+#[derive(Deserialize)]
+struct ChatGptChunkChoice {
+    delta: Option<ChatGptDelta>,
+    text: Option<String>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatGptDelta {
+    content: Option<String>,
+}
+
+/// A message as sent to the API, which rejects fields it doesn't know.
+#[derive(Serialize)]
+struct ApiMessage<'a> {
+    role: &'a Role,
+    content: Option<ApiContent<'a>>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tool_calls: &'a [ToolCall],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>,
+}
+
+/// Message content, which is given as parts when there are images.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ApiContent<'a> {
+    Text(&'a str),
+    Parts(Vec<ContentPart<'a>>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart<'a> {
+    Text {
+        text: Cow<'a, str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    ImageUrl {
+        image_url: ImageUrl<'a>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+}
+
+/// Marks the end of a prefix of the prompt the backend may cache, in the
+/// format Anthropic's models take, through proxies to them that speak this
+/// API.
+#[derive(Serialize)]
+struct CacheControl {
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+/// The most cache breakpoints Anthropic takes in one request.
+const MAX_CACHE_BREAKPOINTS: usize = 4;
+
+#[derive(Serialize)]
+struct ImageUrl<'a> {
+    url: &'a str,
+}
+
+/// The content of a message, with a cache breakpoint after it if `cache`.
+fn api_content(
+    message: &ChatGptMessage,
+    cache: bool,
+) -> Option<ApiContent<'_>> {
+    // An assistant message that only calls tools has no content.
+    if message.content.is_empty() && !message.tool_calls.is_empty() {
+        return None;
+    }
+    if message.images.is_empty() && !cache {
+        return Some(ApiContent::Text(&message.content));
+    }
+    let mut parts = vec![ContentPart::Text {
+        text: Cow::Borrowed(&message.content),
+        cache_control: None,
+    }];
+    for image in &message.images {
+        parts.push(match &image.data_url {
+            Some(url) => ContentPart::ImageUrl {
+                image_url: ImageUrl { url },
+                cache_control: None,
+            },
+            None => ContentPart::Text {
+                text: Cow::Owned(format!(
+                    "[an image attached here, {}, is no longer available]",
+                    image.path
+                )),
+                cache_control: None,
+            },
+        });
+    }
+    if cache {
+        let (ContentPart::Text { cache_control, .. }
+        | ContentPart::ImageUrl { cache_control, .. }) =
+            parts.last_mut().unwrap();
+        *cache_control = Some(CacheControl { kind: "ephemeral" });
+    }
+    Some(ApiContent::Parts(parts))
+}
+
+/// The messages of a request. With `cache_hints`, the last few cacheable
+/// messages each end a prefix the backend may cache, and the rest are
+/// covered by those prefixes.
+struct ApiMessages<'a> {
+    messages: &'a [&'a ChatGptMessage],
+    cache_hints: bool,
+}
+
+impl Serialize for ApiMessages<'_> {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let cacheable = self.messages.iter().filter(|m| m.cacheable).count();
+        let uncached = cacheable.saturating_sub(MAX_CACHE_BREAKPOINTS);
+        let mut seen = 0;
+        serializer.collect_seq(self.messages.iter().map(|m| {
+            let cache = self.cache_hints && m.cacheable && {
+                seen += 1;
+                seen > uncached
+            };
+            ApiMessage {
+                role: &m.role,
+                content: api_content(m, cache),
+                tool_calls: &m.tool_calls,
+                tool_call_id: m.tool_call_id.as_deref(),
+            }
+        }))
+    }
+}
+
+const MAX_RETRIES: u32 = 5;
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The moderation model used unless another is given.
+pub const DEFAULT_MODERATION_MODEL: &str = "omni-moderation-latest";
+
+#[derive(Serialize)]
+struct ModerationRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    categories: BTreeMap<String, bool>,
+}
+
+/// The embedding model used unless another is given.
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+    usage: Option<EmbeddingUsage>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+}
+
+/// The embeddings of some inputs, in the order they were given.
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f32>>,
+    pub usage: Option<EmbeddingUsage>,
+}
+
+/// The image model used unless another is given.
+pub const DEFAULT_IMAGE_MODEL: &str = "gpt-image-1";
+
+/// A request to the image generation endpoint.
+#[derive(Serialize)]
+pub struct ImageRequest<'a> {
+    pub model: &'a str,
+    pub prompt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    /// Only the DALL-E models take this, and they give URLs without it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct ImageResponse {
+    data: Vec<ImageData>,
+}
+
+#[derive(Deserialize)]
+struct ImageData {
+    b64_json: Option<String>,
+    url: Option<String>,
+    revised_prompt: Option<String>,
+}
+
+/// An image the image generation endpoint made.
+#[derive(Debug)]
+pub struct GeneratedImage {
+    /// The encoded image, PNG unless asked for otherwise.
+    pub data: Vec<u8>,
+    /// The prompt as the model rewrote it, if it did.
+    pub revised_prompt: Option<String>,
+}
+
+/// The text-to-speech model used to read responses aloud.
+pub const DEFAULT_SPEECH_MODEL: &str = "gpt-4o-mini-tts";
+
+/// A request to the text-to-speech endpoint.
+#[derive(Serialize)]
+pub struct SpeechRequest<'a> {
+    pub model: &'a str,
+    pub input: &'a str,
+    pub voice: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f32>,
+    /// Such as mp3 or wav.
+    pub response_format: &'a str,
+}
+
+/// The speech-to-text model used by `termgpt transcribe`.
+pub const DEFAULT_TRANSCRIPTION_MODEL: &str = "whisper-1";
+
+/// A request to the transcription endpoint, sent as a multipart form with
+/// the audio as its file.
+pub struct TranscriptionRequest<'a> {
+    pub model: &'a str,
+    /// The file's name, which the API takes the audio's format from.
+    pub filename: &'a str,
+    pub audio: Vec<u8>,
+    /// The language spoken, as an ISO-639-1 code such as `en`.
+    pub language: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+/// What the moderation endpoint made of some texts.
+#[derive(Debug)]
+pub struct Moderation {
+    pub flagged: bool,
+    /// The categories any of the texts was flagged for, such as
+    /// `harassment` or `violence/graphic`.
+    pub categories: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct ChatGptClient {
+    pub http: reqwest::Client,
+    pub api_key: String,
+    pub base_url: String,
+    /// Use the legacy completions endpoint with a flattened prompt.
+    pub completion_mode: bool,
+    /// Example exchanges sent after the system prompt in every request.
+    pub examples: Vec<ChatGptMessage>,
+    /// Print the rate limits left after each response.
+    pub show_limits: bool,
+    /// Log each request and response to a file.
+    pub trace: Option<Arc<TraceFile>>,
+    /// Mark cacheable messages with `cache_control`.
+    pub cache_hints: bool,
+    /// Answer requests sent before from their cached responses.
+    pub cache: Option<Arc<ResponseCache>>,
+}
+
+impl ChatGptClient {
+    pub fn new(
+        api_key: String,
+        base_url: String,
+        user_agent: &str,
+    ) -> ChatGptClient {
+        let http = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .build()
+            .expect("could not build HTTP client");
+        ChatGptClient {
+            http,
+            api_key,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            completion_mode: false,
+            show_limits: false,
+            examples: Vec::new(),
+            trace: None,
+            cache_hints: false,
+            cache: None,
+        }
+    }
+
+    /// Posts a request body to an API path, backing off and retrying when
+    /// rate limited.
+    pub async fn post<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<reqwest::Response, Box<dyn Error>> {
+        let (response, _) = self.post_traced(path, body, false).await?;
+        Ok(response)
+    }
+
+    /// `post`, for a response that may be streamed. With a trace file, the
+    /// entry for a successful stream is returned to be finished once the
+    /// stream has been read; every other response is traced here.
+    async fn post_traced<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &T,
+        stream: bool,
+    ) -> Result<(reqwest::Response, Option<TraceEntry>), Box<dyn Error>> {
+        let url = format!("{}/{}", self.base_url, path);
+        let mut delay = Duration::from_secs(1);
+        let mut attempt = 0;
+        loop {
+            let mut entry = self.trace.as_ref().map(|_| {
+                TraceEntry::new(
+                    &url,
+                    serde_json::to_value(body).unwrap_or_default(),
+                )
+            });
+            let result = self
+                .http
+                .post(&url)
+                .header("Authorization", format!("Bearer {}", self.api_key))
+                .json(body)
+                .send()
+                .await;
+            let mut response = match result {
+                Ok(response) => response,
+                Err(e) => {
+                    if let (Some(trace), Some(mut entry)) = (&self.trace, entry)
+                    {
+                        entry.set_error(&e);
+                        trace.record(&entry);
+                    }
+                    return Err(e.into());
+                }
+            };
+            if let Some(entry) = entry.as_mut() {
+                entry
+                    .set_status(response.status().as_u16(), response.headers());
+            }
+            let streamed = stream && response.status().is_success();
+            if let (Some(trace), Some(mut entry)) =
+                (&self.trace, entry.take_if(|_| !streamed))
+            {
+                response = read_into_trace(response, &mut entry).await;
+                trace.record(&entry);
+            }
+            if response.status() != StatusCode::TOO_MANY_REQUESTS
+                || attempt == MAX_RETRIES
+            {
+                return Ok((check_api_response(response).await?, entry));
+            }
+            let wait = retry_after(&response).unwrap_or(delay);
+            tokio::time::sleep(wait).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// This client without its example exchanges, for requests of termgpt's
+    /// own such as summaries, which the examples have nothing to do with.
+    pub fn without_examples(&self) -> ChatGptClient {
+        ChatGptClient {
+            examples: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Inserts the example exchanges between the leading system messages
+    /// and the rest of the conversation.
+    pub fn with_examples<'a>(
+        &'a self,
+        messages: &'a [ChatGptMessage],
+    ) -> Vec<&'a ChatGptMessage> {
+        let split = messages
+            .iter()
+            .position(|m| m.role != Role::System)
+            .unwrap_or(messages.len());
+        let (system, rest) = messages.split_at(split);
+        system.iter().chain(&self.examples).chain(rest).collect()
+    }
+
+    async fn post_chat(
+        &self,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+        stream: bool,
+        include_usage: bool,
+    ) -> Result<(reqwest::Response, Option<TraceEntry>), Box<dyn Error>> {
+        let stream_options = (stream && include_usage)
+            .then_some(StreamOptions { include_usage });
+        let messages = self.with_examples(messages);
+        if self.completion_mode {
+            let request = CompletionRequest {
+                params,
+                prompt: completion_prompt(&messages),
+                stop: COMPLETION_STOP,
+                stream,
+                stream_options,
+            };
+            self.post_traced("completions", &request, stream).await
+        } else {
+            let request = ChatGptRequest {
+                params,
+                messages: ApiMessages {
+                    messages: &messages,
+                    cache_hints: self.cache_hints,
+                },
+                stream,
+                stream_options,
+            };
+            self.post_traced("chat/completions", &request, stream).await
+        }
+    }
+
+    /// The key a chat request is cached under, which is that of the request
+    /// as it would be sent without streaming.
+    fn cache_key(
+        &self,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+    ) -> Result<String, Box<dyn Error>> {
+        let messages = self.with_examples(messages);
+        let (path, body) = if self.completion_mode {
+            let request = CompletionRequest {
+                params,
+                prompt: completion_prompt(&messages),
+                stop: COMPLETION_STOP,
+                stream: false,
+                stream_options: None,
+            };
+            ("completions", serde_json::to_vec(&request)?)
+        } else {
+            let request = ChatGptRequest {
+                params,
+                messages: ApiMessages {
+                    messages: &messages,
+                    cache_hints: self.cache_hints,
+                },
+                stream: false,
+                stream_options: None,
+            };
+            ("chat/completions", serde_json::to_vec(&request)?)
+        };
+        let url = format!("{}/{}", self.base_url, path);
+        Ok(ResponseCache::key(&url, &body))
+    }
+
+    /// The cached response to a chat request, if there is one, along with
+    /// the key to cache the response under if there isn't.
+    fn cached(
+        &self,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+    ) -> Result<(Option<ChatGptResponse>, Option<String>), Box<dyn Error>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok((None, None)),
+        };
+        let key = self.cache_key(params, messages)?;
+        match cache.get(&key) {
+            Some(response) => {
+                eprintln!(
+                    "termgpt: using a cached response (--no-cache to ask again)"
+                );
+                Ok((Some(response), None))
+            }
+            None => Ok((None, Some(key))),
+        }
+    }
+
+    /// Asks the moderation endpoint whether any of some texts breaks the
+    /// usage policies.
+    pub async fn moderate(
+        &self,
+        model: &str,
+        input: &[&str],
+    ) -> Result<Moderation, Box<dyn Error>> {
+        let request = ModerationRequest { model, input };
+        let response: ModerationResponse =
+            self.post("moderations", &request).await?.json().await?;
+        let mut categories = Vec::new();
+        for result in &response.results {
+            for (category, &flagged) in &result.categories {
+                if flagged && !categories.contains(category) {
+                    categories.push(category.clone());
+                }
+            }
+        }
+        Ok(Moderation {
+            flagged: response.results.iter().any(|r| r.flagged),
+            categories,
+        })
+    }
+
+    /// Asks the embeddings endpoint for a vector of each input.
+    pub async fn embed(
+        &self,
+        model: &str,
+        input: &[&str],
+    ) -> Result<Embeddings, Box<dyn Error>> {
+        let request = EmbeddingRequest { model, input };
+        let mut response: EmbeddingResponse =
+            self.post("embeddings", &request).await?.json().await?;
+        if response.data.len() != input.len() {
+            Err(format!(
+                "the API returned {} embeddings for {} inputs",
+                response.data.len(),
+                input.len()
+            ))?
+        }
+        response.data.sort_by_key(|data| data.index);
+        Ok(Embeddings {
+            vectors: response.data.into_iter().map(|d| d.embedding).collect(),
+            usage: response.usage,
+        })
+    }
+
+    /// Asks the text-to-speech endpoint to read some text, returning the
+    /// audio.
+    pub async fn speech(
+        &self,
+        request: &SpeechRequest<'_>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let response = self.post("audio/speech", request).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Asks the transcription endpoint for the text of some speech. The
+    /// form can't be traced as JSON, nor resent, so this is neither traced
+    /// nor retried.
+    pub async fn transcribe(
+        &self,
+        request: TranscriptionRequest<'_>,
+    ) -> Result<String, Box<dyn Error>> {
+        let file =
+            Part::bytes(request.audio).file_name(request.filename.to_string());
+        let mut form = Form::new()
+            .text("model", request.model.to_string())
+            .part("file", file);
+        if let Some(language) = request.language {
+            form = form.text("language", language.to_string());
+        }
+        let response = self
+            .http
+            .post(format!("{}/audio/transcriptions", self.base_url))
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .multipart(form)
+            .send()
+            .await?;
+        let response: TranscriptionResponse =
+            check_api_response(response).await?.json().await?;
+        Ok(response.text)
+    }
+
+    /// Asks the image generation endpoint for images of a prompt. Images
+    /// given as URLs rather than data are downloaded.
+    pub async fn generate_images(
+        &self,
+        request: &ImageRequest<'_>,
+    ) -> Result<Vec<GeneratedImage>, Box<dyn Error>> {
+        let response: ImageResponse = self
+            .post("images/generations", request)
+            .await?
+            .json()
+            .await?;
+        let mut images = Vec::new();
+        for image in response.data {
+            let data = match (image.b64_json, image.url) {
+                (Some(encoded), _) => STANDARD.decode(encoded.trim())?,
+                (None, Some(url)) => {
+                    let response = self.http.get(&url).send().await?;
+                    response.error_for_status()?.bytes().await?.to_vec()
+                }
+                (None, None) => Err("the API returned an image without data")?,
+            };
+            images.push(GeneratedImage {
+                data,
+                revised_prompt: image.revised_prompt,
+            });
+        }
+        Ok(images)
+    }
+}
+
+/// Sends a conversation to a chat model. `ChatGptClient` does this over
+/// HTTP; code that only needs the model's reply can take any `ChatApi`, so
+/// that tests can stand in a fake.
+pub trait ChatApi {
+    /// Waits for the whole response.
+    fn get_chatgpt_response(
+        &self,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+    ) -> impl Future<Output = Result<ChatGptResponse, Box<dyn Error>>>;
+
+    /// Passes each part of the response to `on_chunk` as it arrives,
+    /// returning the whole of it at the end. With `include_usage`, the
+    /// token usage is asked for as well.
+    fn stream_chatgpt_response<F>(
+        &self,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+        include_usage: bool,
+        on_chunk: F,
+    ) -> impl Future<Output = Result<ChatGptResponse, Box<dyn Error>>>
+    where
+        F: FnMut(&str) -> Result<(), Box<dyn Error>>;
+}
+
+impl ChatApi for ChatGptClient {
+    async fn get_chatgpt_response(
+        &self,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+    ) -> Result<ChatGptResponse, Box<dyn Error>> {
+        let (cached, key) = self.cached(params, messages)?;
+        if let Some(response) = cached {
+            return Ok(response);
+        }
+        let (response, _) =
+            self.post_chat(params, messages, false, false).await?;
+        let rate_limits = RateLimits::from_headers(response.headers());
+        let mut response: ChatGptResponse = if self.completion_mode {
+            let response: CompletionResponse = response.json().await?;
+            response.into()
+        } else {
+            response.json().await?
+        };
+        response.rate_limits = rate_limits;
+        if let Some((cache, key)) = self.cache.as_ref().zip(key) {
+            cache.put(&key, &response);
+        }
+        Ok(response)
+    }
+
+    async fn stream_chatgpt_response<F>(
+        &self,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+        include_usage: bool,
+        mut on_chunk: F,
+    ) -> Result<ChatGptResponse, Box<dyn Error>>
+    where
+        F: FnMut(&str) -> Result<(), Box<dyn Error>>,
+    {
+        let (cached, key) = self.cached(params, messages)?;
+        if let Some(response) = cached {
+            if let Some(choice) = response.choices.first() {
+                on_chunk(&choice.message.content)?;
+            }
+            return Ok(response);
+        }
+        let (mut response, entry) = self
+            .post_chat(params, messages, true, include_usage)
+            .await?;
+        let rate_limits = RateLimits::from_headers(response.headers());
+        let mut trace =
+            self.trace.as_deref().zip(entry).map(|(trace, entry)| {
+                StreamTrace {
+                    trace,
+                    entry,
+                    body: Vec::new(),
+                    finished: false,
+                }
+            });
+
+        let mut buffer = Vec::new();
+        let mut content = String::new();
+        let mut model = None;
+        let mut finish_reason = None;
+        let mut usage = None;
+
+        'stream: while let Some(bytes) = response.chunk().await? {
+            if let Some(trace) = trace.as_mut() {
+                trace.body.extend_from_slice(&bytes);
+            }
+            buffer.extend_from_slice(&bytes);
+            while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8(line)?;
+                let data = match line.trim().strip_prefix("data:") {
+                    Some(data) => data.trim(),
+                    None => continue,
+                };
+                if data == "[DONE]" {
+                    break 'stream;
+                }
+                let chunk: ChatGptChunk = serde_json::from_str(data)?;
+                if chunk.model.is_some() {
+                    model = chunk.model;
+                }
+                if chunk.usage.is_some() {
+                    usage = chunk.usage;
+                }
+                for choice in chunk.choices {
+                    let text =
+                        choice.delta.and_then(|d| d.content).or(choice.text);
+                    if let Some(text) = text {
+                        on_chunk(&text)?;
+                        content.push_str(&text);
+                    }
+                    if choice.finish_reason.is_some() {
+                        finish_reason = choice.finish_reason;
+                    }
+                }
+            }
+        }
+
+        if let Some(trace) = trace.as_mut() {
+            trace.finished = true;
+        }
+
+        let message = ChatGptMessage::new(Role::Assistant, content);
+        let response = ChatGptResponse {
+            model,
+            choices: vec![ChatGptChoice {
+                message,
+                finish_reason,
+            }],
+            usage,
+            rate_limits,
+        };
+        if let Some((cache, key)) = self.cache.as_ref().zip(key) {
+            cache.put(&key, &response);
+        }
+        Ok(response)
+    }
+}
+
+/// The trace of a streamed response, written when it is dropped: once the
+/// stream has been read, or when an error or interruption cuts it short.
+struct StreamTrace<'a> {
+    trace: &'a TraceFile,
+    entry: TraceEntry,
+    body: Vec<u8>,
+    finished: bool,
+}
+
+impl Drop for StreamTrace<'_> {
+    fn drop(&mut self) {
+        self.entry.set_response(&self.body);
+        if !self.finished {
+            self.entry.set_error(&"the stream was cut short");
+        }
+        self.trace.record(&self.entry);
+    }
+}
+
+/// Reads a response's body into its trace entry, returning a response
+/// with the same status, headers and body for the caller to read again.
+async fn read_into_trace(
+    response: reqwest::Response,
+    entry: &mut TraceEntry,
+) -> reqwest::Response {
+    let mut copy = http::Response::builder()
+        .status(response.status())
+        .version(response.version());
+    for (name, value) in response.headers() {
+        copy = copy.header(name, value);
+    }
+    let body = match response.bytes().await {
+        Ok(body) => {
+            entry.set_response(&body);
+            body
+        }
+        Err(e) => {
+            entry.set_error(&e);
+            Default::default()
+        }
+    };
+    copy.body(body).expect("copied response is valid").into()
+}
+
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    error: ApiError,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+}
+
+/// Turns a non-success response into an error carrying the API's message.
+async fn check_api_response(
+    response: reqwest::Response,
+) -> Result<reqwest::Response, Box<dyn Error>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await?;
+    match serde_json::from_str::<ApiErrorBody>(&body) {
+        Ok(body) => {
+            Err(format!("API error ({}): {}", status, body.error.message))?
+        }
+        Err(_) => Err(format!("API error ({}): {}", status, body.trim()))?,
+    }
+}
+
+/// USD prices per million prompt and completion tokens, by model prefix.
+pub const MODEL_PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4", 30.0, 60.0),
+    ("gpt-3.5-turbo", 0.5, 1.5),
+];
+
+pub fn estimate_cost(model: &str, usage: &ChatGptUsage) -> Option<f64> {
+    MODEL_PRICES
+        .iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))
+        .map(|(_, prompt_price, completion_price)| {
+            (usage.prompt_tokens as f64 * prompt_price
+                + usage.completion_tokens as f64 * completion_price)
+                / 1_000_000.0
+        })
+}
@@ -0,0 +1,234 @@
+use crate::print_rate_limits;
+use clap::{Args, ValueHint};
+use serde::{Deserialize, Serialize};
+use serde_jsonlines::JsonLinesWriter;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use termgpt::api::{ChatApi, ChatGptClient, ChatGptParams};
+use termgpt::message::{ChatGptMessage, Role};
+use tokio::signal;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+#[derive(Args)]
+pub struct BatchArgs {
+    /// File with one prompt per line, or JSONL with {"prompt": ...} objects
+    #[arg(value_hint = ValueHint::FilePath)]
+    file: String,
+
+    /// Write each response to a numbered file in this directory
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    out_dir: Option<PathBuf>,
+
+    /// Number of prompts to send concurrently
+    #[arg(short, long, value_name = "N", default_value_t = 1)]
+    jobs: usize,
+
+    /// Write results as they complete rather than in input order
+    #[arg(long)]
+    unordered: bool,
+}
+
+#[derive(Deserialize)]
+struct BatchPrompt {
+    prompt: String,
+}
+
+#[derive(Serialize)]
+struct BatchRecord<'a> {
+    index: usize,
+    prompt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+struct BatchOutcome {
+    prompt: String,
+    result: Result<String, String>,
+}
+
+fn parse_prompt(line: &str) -> Result<String, Box<dyn Error>> {
+    if line.starts_with('{') {
+        let prompt: BatchPrompt = serde_json::from_str(line)?;
+        Ok(prompt.prompt)
+    } else {
+        Ok(line.to_string())
+    }
+}
+
+fn read_lines(filename: &str) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(filename)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+async fn send_prompt(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    system: Option<&str>,
+    prompt: &str,
+) -> Result<String, Box<dyn Error>> {
+    let mut messages = Vec::new();
+    if let Some(system) = system {
+        messages.push(ChatGptMessage::new(Role::System, system.to_string()));
+    }
+    messages.push(ChatGptMessage::new(Role::User, prompt.to_string()));
+    let mut response = client.get_chatgpt_response(params, &messages).await?;
+    if client.show_limits {
+        print_rate_limits(&response.rate_limits);
+    }
+    let choice = response.choices.pop().ok_or("no choices returned")?;
+    Ok(choice.message.content)
+}
+
+async fn run_line(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    system: Option<&str>,
+    line: String,
+) -> BatchOutcome {
+    let prompt = match parse_prompt(&line) {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            return BatchOutcome {
+                prompt: line,
+                result: Err(e.to_string()),
+            }
+        }
+    };
+    let result = send_prompt(client, params, system, &prompt)
+        .await
+        .map_err(|e| e.to_string());
+    BatchOutcome { prompt, result }
+}
+
+struct BatchWriter {
+    out_dir: Option<PathBuf>,
+    width: usize,
+    stdout: JsonLinesWriter<io::Stdout>,
+}
+
+impl BatchWriter {
+    fn new(out_dir: Option<&Path>, count: usize) -> io::Result<BatchWriter> {
+        if let Some(dir) = out_dir {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(BatchWriter {
+            out_dir: out_dir.map(Path::to_path_buf),
+            width: count.to_string().len(),
+            stdout: JsonLinesWriter::new(io::stdout()),
+        })
+    }
+
+    fn write(
+        &mut self,
+        index: usize,
+        outcome: &BatchOutcome,
+    ) -> io::Result<()> {
+        match &self.out_dir {
+            Some(dir) => {
+                let (extension, content) = match &outcome.result {
+                    Ok(response) => ("txt", response),
+                    Err(e) => ("err", e),
+                };
+                let name =
+                    format!("{:0w$}.{}", index, extension, w = self.width);
+                fs::write(dir.join(name), content)
+            }
+            None => {
+                self.stdout.write(&BatchRecord {
+                    index,
+                    prompt: &outcome.prompt,
+                    response: outcome.result.as_deref().ok(),
+                    error: outcome.result.as_ref().err().map(String::as_str),
+                })?;
+                self.stdout.flush()
+            }
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn run_batch(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    system: Option<&str>,
+    args: &BatchArgs,
+) -> Result<(), Box<dyn Error>> {
+    let lines = read_lines(&args.file)?;
+    let total = lines.len();
+    let mut writer = BatchWriter::new(args.out_dir.as_deref(), total)?;
+
+    let semaphore = Arc::new(Semaphore::new(args.jobs.max(1)));
+    let mut tasks = JoinSet::new();
+    for (index, line) in lines.into_iter().enumerate() {
+        let client = client.clone();
+        let params = params.clone();
+        let system = system.map(String::from);
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let outcome = run_line(&client, &params, system.as_deref(), line);
+            (index + 1, outcome.await)
+        });
+    }
+
+    let mut pending = BTreeMap::new();
+    let mut next_index = 1;
+    let mut completed = 0;
+    let mut failed = 0;
+
+    let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let interrupted = loop {
+        let (index, outcome) = tokio::select! {
+            joined = tasks.join_next() => match joined {
+                Some(joined) => joined?,
+                None => break false,
+            },
+            _ = &mut ctrl_c => {
+                tasks.abort_all();
+                break true;
+            }
+        };
+        completed += 1;
+        if let Err(e) = &outcome.result {
+            eprintln!("prompt {}: {}", index, e);
+            failed += 1;
+        }
+        if args.unordered {
+            writer.write(index, &outcome)?;
+        } else {
+            pending.insert(index, outcome);
+            while let Some(outcome) = pending.remove(&next_index) {
+                writer.write(next_index, &outcome)?;
+                next_index += 1;
+            }
+        }
+    };
+
+    for (index, outcome) in pending {
+        writer.write(index, &outcome)?;
+    }
+
+    eprint!("batch: {} succeeded, {} failed", completed - failed, failed);
+    if interrupted {
+        eprintln!(", {} cancelled", total - completed);
+        Err("batch interrupted")?
+    }
+    eprintln!();
+    if failed > 0 {
+        Err(format!("{} of {} prompts failed", failed, total))?
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use termgpt::api::{ChatApi, ChatGptClient, ChatGptParams};
+use termgpt::message::ChatGptMessage;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// How many requests to send, and how.
+pub struct BenchOptions {
+    pub count: usize,
+    pub concurrency: usize,
+    pub stream: bool,
+}
+
+struct Sample {
+    latency: Duration,
+    first_token: Option<Duration>,
+    completion_tokens: Option<u32>,
+}
+
+async fn send_request(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    messages: &[ChatGptMessage],
+    stream: bool,
+) -> Result<Sample, String> {
+    let start = Instant::now();
+    let mut first_token = None;
+    let response = if stream {
+        let on_chunk = |_: &str| {
+            first_token.get_or_insert_with(|| start.elapsed());
+            Ok(())
+        };
+        client
+            .stream_chatgpt_response(params, messages, true, on_chunk)
+            .await
+    } else {
+        client.get_chatgpt_response(params, messages).await
+    };
+    let response = response.map_err(|e| e.to_string())?;
+    Ok(Sample {
+        latency: start.elapsed(),
+        first_token,
+        completion_tokens: response.usage.map(|u| u.completion_tokens),
+    })
+}
+
+/// The nearest-rank percentile of a sorted, non-empty list.
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn print_durations(label: &str, mut durations: Vec<Duration>) {
+    if durations.is_empty() {
+        return;
+    }
+    durations.sort();
+    println!(
+        "{:<12} min {:.3}s  p50 {:.3}s  p90 {:.3}s  p99 {:.3}s  max {:.3}s",
+        label,
+        durations[0].as_secs_f64(),
+        percentile(&durations, 50).as_secs_f64(),
+        percentile(&durations, 90).as_secs_f64(),
+        percentile(&durations, 99).as_secs_f64(),
+        durations[durations.len() - 1].as_secs_f64(),
+    );
+}
+
+/// Sends the same conversation repeatedly, discarding the responses, and
+/// prints latency percentiles and token throughput.
+#[tokio::main]
+pub async fn run_bench(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    messages: Vec<ChatGptMessage>,
+    options: &BenchOptions,
+) -> Result<(), Box<dyn Error>> {
+    let messages = Arc::new(messages);
+    let semaphore = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let start = Instant::now();
+    for _ in 0..options.count {
+        let client = client.clone();
+        let params = params.clone();
+        let messages = messages.clone();
+        let semaphore = semaphore.clone();
+        let stream = options.stream;
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            send_request(&client, &params, &messages, stream).await
+        });
+    }
+
+    let mut samples = Vec::new();
+    let mut failed = 0;
+    while let Some(joined) = tasks.join_next().await {
+        match joined? {
+            Ok(sample) => samples.push(sample),
+            Err(e) => {
+                eprintln!("request failed: {}", e);
+                failed += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:<12} {} succeeded, {} failed in {:.3}s",
+        "requests:",
+        samples.len(),
+        failed,
+        elapsed.as_secs_f64()
+    );
+    print_durations("latency:", samples.iter().map(|s| s.latency).collect());
+    print_durations(
+        "first token:",
+        samples.iter().filter_map(|s| s.first_token).collect(),
+    );
+
+    let tokens: Vec<(u32, Duration)> = samples
+        .iter()
+        .filter_map(|s| s.completion_tokens.map(|t| (t, s.latency)))
+        .collect();
+    if tokens.is_empty() {
+        println!("{:<12} unavailable, no usage reported", "tokens/sec:");
+    } else {
+        let total: u32 = tokens.iter().map(|(t, _)| t).sum();
+        let per_request = tokens
+            .iter()
+            .map(|(t, latency)| *t as f64 / latency.as_secs_f64())
+            .sum::<f64>()
+            / tokens.len() as f64;
+        println!(
+            "{:<12} {:.1} overall, {:.1} per request",
+            "tokens/sec:",
+            total as f64 / elapsed.as_secs_f64(),
+            per_request
+        );
+    }
+
+    if samples.is_empty() {
+        Err("every benchmark request failed")?
+    }
+    Ok(())
+}
@@ -0,0 +1,307 @@
+use crate::db::{self, Database};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use termgpt::listener::ChatMessageListener;
+use termgpt::message::{ChatMessages, Role};
+use termgpt::session::SessionAppendListener;
+
+/// The name of the session file given with --session, among its branches.
+const MAIN: &str = "main";
+
+/// How the session file given with --session is written, which its
+/// branches are written the same way as.
+pub struct SessionFile {
+    pub path: String,
+    pub sync: bool,
+    pub persist_system: bool,
+    /// The database, if there is one, where each branch is a conversation
+    /// of its own, named by its file as the session is.
+    pub database: Option<Database>,
+}
+
+/// The branches of a session, for `/branch`, `/branches` and `/switch`.
+/// Each is a session file of its own beside the main one, so that
+/// `notes.jsonl` branched as `idea` is `notes.idea.jsonl`, and can be
+/// resumed with --session like any other.
+pub struct Branches<'a> {
+    session: &'a SessionFile,
+    /// The branch being written to.
+    current: String,
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The registered name of the listener writing to a session file.
+pub fn listener_name(path: &str) -> String {
+    format!("session file {}", path)
+}
+
+impl<'a> Branches<'a> {
+    pub fn new(session: &'a SessionFile) -> Branches<'a> {
+        Branches {
+            session,
+            current: MAIN.to_string(),
+        }
+    }
+
+    /// The session file of a branch.
+    fn path(&self, name: &str) -> String {
+        if name == MAIN {
+            return self.session.path.clone();
+        }
+        let path = Path::new(&self.session.path);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let file = match path.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, name, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, name),
+        };
+        path.with_file_name(file).to_string_lossy().into_owned()
+    }
+
+    fn open(
+        &self,
+        path: &str,
+    ) -> Result<SessionAppendListener, Box<dyn Error>> {
+        let session = &self.session;
+        SessionAppendListener::new(path, session.sync, session.persist_system)
+            .map_err(|e| format!("could not open {}: {}", path, e).into())
+    }
+
+    /// Puts a listener for the branch at `path` in place of the one for
+    /// the current branch in the database, if there is one, first writing
+    /// `messages` to it.
+    fn switch_database(
+        &self,
+        path: &str,
+        messages: &mut ChatMessages,
+        write: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(database) = &self.session.database else {
+            return Ok(());
+        };
+        let mut listener = database.listener(Some(path))?;
+        if write {
+            for message in &messages.messages {
+                listener.on_message(message)?;
+            }
+        }
+        let name = db::listener_name(&database.path);
+        messages.listeners.replace(&name, name.clone(), listener);
+        Ok(())
+    }
+
+    /// Writes the conversation so far to a new branch, which is written
+    /// to from then on.
+    pub fn branch(
+        &mut self,
+        name: &str,
+        messages: &mut ChatMessages,
+    ) -> Result<(), Box<dyn Error>> {
+        if !valid_name(name) || name == MAIN {
+            Err(format!(
+                "a branch name is letters, digits, - and _, and not {}",
+                MAIN
+            ))?
+        }
+        let path = self.path(name);
+        if Path::new(&path).try_exists()? {
+            Err(format!("there is already a branch {} ({})", name, path))?
+        }
+        let mut listener = self.open(&path)?;
+        for message in &messages.messages {
+            listener.on_message(message)?;
+        }
+        let meta = format!("{}.meta.json", self.session.path);
+        if Path::new(&meta).exists() {
+            fs::copy(&meta, format!("{}.meta.json", path))?;
+        }
+        self.switch_database(&path, messages, true)?;
+        let current = self.path(&self.current);
+        messages.listeners.replace(
+            &listener_name(&current),
+            listener_name(&path),
+            listener,
+        );
+        self.current = name.to_string();
+        Ok(())
+    }
+
+    /// Goes back to a branch, or with `main` to the session file given
+    /// with --session, carrying on from where it left off.
+    pub fn switch(
+        &mut self,
+        name: &str,
+        messages: &mut ChatMessages,
+    ) -> Result<(), Box<dyn Error>> {
+        if name == self.current {
+            Err(format!("already on {}", name))?
+        }
+        let path = self.path(name);
+        if !valid_name(name) || !Path::new(&path).try_exists()? {
+            Err(format!("there is no branch {} (see /branches)", name))?
+        }
+        let listener = self.open(&path)?;
+        let mut loaded = ChatMessages::from_file(&path)?.messages;
+        // A session that leaves out its system prompt gets the one it was
+        // given this time, as it did when it was resumed.
+        if !self.session.persist_system {
+            let system = messages
+                .messages
+                .iter()
+                .take_while(|m| m.role == Role::System)
+                .cloned();
+            loaded.splice(0..0, system);
+        }
+        messages.messages = loaded;
+        self.switch_database(&path, messages, false)?;
+        let current = self.path(&self.current);
+        messages.listeners.replace(
+            &listener_name(&current),
+            listener_name(&path),
+            listener,
+        );
+        self.current = name.to_string();
+        Ok(())
+    }
+
+    /// The names of the branches, starting with `main`.
+    fn names(&self) -> Vec<String> {
+        let path = Path::new(&self.session.path);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let prefix = format!("{}.", stem);
+        let suffix = match path.extension() {
+            Some(ext) => format!(".{}", ext.to_string_lossy()),
+            None => String::new(),
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let file = entry.ok()?.file_name().into_string().ok()?;
+                let name = file.strip_prefix(&prefix)?.strip_suffix(&suffix)?;
+                valid_name(name).then(|| name.to_string())
+            })
+            .filter(|name| name != MAIN)
+            .collect();
+        names.sort();
+        names.insert(0, MAIN.to_string());
+        names
+    }
+
+    /// Lists the branches, marking the one being written to.
+    pub fn print(&self) {
+        for name in self.names() {
+            let mark = if name == self.current { "*" } else { " " };
+            eprintln!("{} {} ({})", mark, name, self.path(&name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termgpt::message::ChatGptMessage;
+
+    #[test]
+    fn branches_are_written_beside_the_session_and_switched_between() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.jsonl");
+        let path = path.to_str().unwrap();
+        let session = SessionFile {
+            path: path.to_string(),
+            sync: false,
+            persist_system: true,
+            database: None,
+        };
+        let mut messages = ChatMessages::new();
+        messages.register(
+            listener_name(path),
+            SessionAppendListener::new(path, false, true).unwrap(),
+        );
+        let user = |text: &str| ChatGptMessage::new(Role::User, text.into());
+        messages.push(user("shared")).unwrap();
+
+        let mut branches = Branches::new(&session);
+        branches.branch("idea", &mut messages).unwrap();
+        messages.push(user("on the branch")).unwrap();
+        assert_eq!(branches.names(), ["main", "idea"]);
+
+        branches.switch("main", &mut messages).unwrap();
+        assert_eq!(messages.messages.len(), 1);
+        messages.push(user("on main")).unwrap();
+
+        let branch =
+            fs::read_to_string(dir.path().join("notes.idea.jsonl")).unwrap();
+        assert!(branch.contains("shared") && branch.contains("on the branch"));
+        let main = fs::read_to_string(path).unwrap();
+        assert!(main.contains("on main") && !main.contains("on the branch"));
+        assert!(branches.branch("idea", &mut messages).is_err());
+        assert!(branches.switch("nothing", &mut messages).is_err());
+    }
+
+    #[test]
+    fn each_branch_is_a_conversation_of_its_own_in_the_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.jsonl");
+        let path = path.to_str().unwrap();
+        let db_path = dir.path().join("termgpt.db");
+        let database = Database {
+            path: db_path.to_str().unwrap().to_string(),
+            model: "gpt-4o".into(),
+        };
+        let mut messages = ChatMessages::new();
+        messages.register(
+            listener_name(path),
+            SessionAppendListener::new(path, false, true).unwrap(),
+        );
+        messages.register(
+            db::listener_name(&database.path),
+            database.listener(Some(path)).unwrap(),
+        );
+        let session = SessionFile {
+            path: path.to_string(),
+            sync: false,
+            persist_system: true,
+            database: Some(database),
+        };
+        let user = |text: &str| ChatGptMessage::new(Role::User, text.into());
+        messages.push(user("shared")).unwrap();
+
+        let mut branches = Branches::new(&session);
+        branches.branch("idea", &mut messages).unwrap();
+        messages.push(user("on the branch")).unwrap();
+        branches.switch("main", &mut messages).unwrap();
+        messages.push(user("on main")).unwrap();
+
+        let conn = db::open(db_path.to_str().unwrap()).unwrap();
+        let contents = |name: &str| -> Vec<String> {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT m.content FROM messages m
+                     JOIN sessions s ON s.id = m.session_id
+                     WHERE s.name = ?1 ORDER BY m.idx",
+                )
+                .unwrap();
+            stmt.query_map([name], |row| row.get(0))
+                .unwrap()
+                .map(Result::unwrap)
+                .collect()
+        };
+        assert_eq!(contents(path), ["shared", "on main"]);
+        let branch = dir.path().join("notes.idea.jsonl");
+        assert_eq!(
+            contents(branch.to_str().unwrap()),
+            ["shared", "on the branch"]
+        );
+    }
+}
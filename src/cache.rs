@@ -0,0 +1,209 @@
+//! Responses kept on disk, keyed by the request they answered, so that a
+//! request sent again can be answered without the API.
+
+use crate::api::{ChatGptChoice, ChatGptResponse};
+use crate::images::sha256;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, SystemTime};
+
+/// The termgpt directory under `$XDG_CACHE_HOME`, or `~/.cache`.
+pub fn cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".cache")))
+        .map(|dir| dir.join("termgpt"))
+}
+
+/// What is kept of a response: its usage and rate limits are left out, as
+/// an answer from the cache costs nothing.
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    model: Option<String>,
+    choices: Vec<ChatGptChoice>,
+}
+
+/// How many responses are cached, and the space they take.
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+/// A directory of responses, one file each, named by a hash of the request.
+pub struct ResponseCache {
+    dir: PathBuf,
+    /// How long a response is used for once it has been cached; forever if
+    /// `None`.
+    ttl: Option<Duration>,
+}
+
+impl ResponseCache {
+    pub fn new(dir: PathBuf, ttl: Option<Duration>) -> ResponseCache {
+        ResponseCache { dir, ttl }
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    /// The key a request is cached under: a hash of where it is sent and
+    /// the whole of its body.
+    pub fn key(url: &str, body: &[u8]) -> String {
+        let mut bytes = url.as_bytes().to_vec();
+        bytes.push(b'\n');
+        bytes.extend_from_slice(body);
+        sha256(&bytes)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    /// The response cached under a key, unless it has expired. An entry
+    /// that can't be read is treated as missing, to be cached again.
+    pub fn get(&self, key: &str) -> Option<ChatGptResponse> {
+        let path = self.path(key);
+        if let Some(ttl) = self.ttl {
+            let age = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())?;
+            if age > ttl {
+                return None;
+            }
+        }
+        let entry: Entry =
+            serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+        Some(ChatGptResponse {
+            model: entry.model,
+            choices: entry.choices,
+            usage: None,
+            rate_limits: None,
+        })
+    }
+
+    /// Caches a response. The cache only saves requests, so a failure to
+    /// write it is reported rather than failing the request.
+    pub fn put(&self, key: &str, response: &ChatGptResponse) {
+        let entry = Entry {
+            model: response.model.clone(),
+            choices: response.choices.clone(),
+        };
+        if let Err(e) = self.write(key, &entry) {
+            eprintln!("termgpt: could not write to the cache: {}", e);
+        }
+    }
+
+    /// Writes an entry to a file of its own first, so that a request made
+    /// at the same time never reads half of it.
+    fn write(&self, key: &str, entry: &Entry) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        let partial = path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(&partial, serde_json::to_vec(entry)?)?;
+        fs::rename(&partial, &path)
+    }
+
+    fn entries(&self) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Vec::new())
+            }
+            Err(e) => return Err(e),
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension() == Some("json".as_ref()) {
+                files.push((path, entry.metadata()?));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Deletes every cached response, returning how many there were.
+    pub fn clear(&self) -> io::Result<usize> {
+        let entries = self.entries()?;
+        for (path, _) in &entries {
+            fs::remove_file(path)?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Counts what is cached, leaving out responses that have expired.
+    pub fn stats(&self) -> io::Result<CacheStats> {
+        let now = SystemTime::now();
+        let mut stats = CacheStats {
+            entries: 0,
+            bytes: 0,
+        };
+        for (_, metadata) in self.entries()? {
+            let expired = self.ttl.is_some_and(|ttl| {
+                metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .is_some_and(|age| age > ttl)
+            });
+            if !expired {
+                stats.entries += 1;
+                stats.bytes += metadata.len();
+            }
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ChatGptMessage, Role};
+
+    fn response(content: &str) -> ChatGptResponse {
+        ChatGptResponse {
+            model: Some("gpt-4o".into()),
+            choices: vec![ChatGptChoice {
+                message: ChatGptMessage::new(Role::Assistant, content.into()),
+                finish_reason: Some("stop".into()),
+            }],
+            usage: None,
+            rate_limits: None,
+        }
+    }
+
+    #[test]
+    fn responses_are_kept_by_key_until_cleared() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ResponseCache::new(dir.path().join("termgpt"), None);
+        let key = ResponseCache::key("https://api", b"{\"model\":\"gpt-4o\"}");
+        assert_ne!(key, ResponseCache::key("https://api", b"{}"));
+        assert!(cache.get(&key).is_none());
+
+        cache.put(&key, &response("Paris."));
+        let cached = cache.get(&key).unwrap();
+        assert_eq!(cached.choices[0].message.content, "Paris.");
+        assert_eq!(cache.stats().unwrap().entries, 1);
+
+        assert_eq!(cache.clear().unwrap(), 1);
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn expired_responses_are_not_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache =
+            ResponseCache::new(dir.path().to_path_buf(), Some(Duration::ZERO));
+        let key = ResponseCache::key("https://api", b"{}");
+        cache.put(&key, &response("stale"));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.stats().unwrap().entries, 0);
+    }
+}
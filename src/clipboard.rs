@@ -0,0 +1,85 @@
+use crate::fenced_code_blocks;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::error::Error;
+use std::io;
+use std::io::Write;
+use termgpt::message::{ChatGptMessage, Role};
+
+/// The longest first line shown in the status after copying a block.
+const PREVIEW_CHARS: usize = 60;
+
+/// Copies text to the system clipboard, or failing that asks the terminal
+/// to with OSC 52, which also works over ssh. On X11 the clipboard is only
+/// served while it is open, so it is kept for the life of the REPL.
+struct Clipboard {
+    system: Option<arboard::Clipboard>,
+}
+
+impl Clipboard {
+    fn copy(&mut self, text: &str) -> io::Result<()> {
+        if let Some(system) = &mut self.system {
+            if system.set_text(text).is_ok() {
+                return Ok(());
+            }
+        }
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+        stdout.flush()
+    }
+}
+
+/// Copies the code blocks of the latest response, starting with the last
+/// and stepping back through the others, wrapping around, on each press.
+pub struct CodeCopier {
+    clipboard: Clipboard,
+    /// The position of the message and the index of the block last copied.
+    last: Option<(usize, usize)>,
+}
+
+impl CodeCopier {
+    pub fn new() -> CodeCopier {
+        CodeCopier {
+            clipboard: Clipboard {
+                system: arboard::Clipboard::new().ok(),
+            },
+            last: None,
+        }
+    }
+
+    /// Copies the next block and returns a status line describing it.
+    pub fn copy_next(
+        &mut self,
+        messages: &[ChatGptMessage],
+    ) -> Result<String, Box<dyn Error>> {
+        let (position, message) = messages
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, m)| m.role == Role::Assistant)
+            .ok_or("there is no response to copy from yet")?;
+        let blocks = fenced_code_blocks(&message.content);
+        if blocks.is_empty() {
+            Err("the last response has no code blocks")?
+        }
+        let index = match self.last {
+            Some((last, index)) if last == position && index > 0 => index - 1,
+            _ => blocks.len() - 1,
+        };
+        let (lang, code) = &blocks[index];
+        self.clipboard.copy(code)?;
+        self.last = Some((position, index));
+
+        let mut status = format!("copied block {}/{}", index + 1, blocks.len());
+        if !lang.is_empty() {
+            status.push_str(&format!(" ({})", lang));
+        }
+        let first_line = code.lines().next().unwrap_or("").trim();
+        status.push_str(": ");
+        status.extend(first_line.chars().take(PREVIEW_CHARS));
+        if first_line.chars().count() > PREVIEW_CHARS {
+            status.push('…');
+        }
+        Ok(status)
+    }
+}
@@ -0,0 +1,169 @@
+use clap::{Args, ValueHint};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::process;
+use termgpt::api::{ChatApi, ChatGptClient, ChatGptParams};
+use termgpt::message::{ChatGptMessage, Role};
+use termimad::crossterm::tty::IsTty;
+
+const DEFAULT_COMMIT_PROMPT: &str = "\
+You write git commit messages in the Conventional Commits style. Given a \
+staged diff, reply with only the commit message: a subject line of the \
+form `type(scope): summary` under 72 characters, where type is one of \
+feat, fix, docs, style, refactor, perf, test, build, ci or chore, then a \
+blank line and a short body explaining what changed and why, wrapped at \
+72 columns. Omit the body for trivial changes. Do not wrap the message \
+in a code block.";
+
+#[derive(Args)]
+pub struct CommitArgs {
+    /// Write the message into this file instead of printing it, as a
+    /// prepare-commit-msg hook does
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    amend_file: Option<String>,
+
+    /// The commit source git passes to a prepare-commit-msg hook; no
+    /// message is drafted when one was already given
+    #[arg(long, value_name = "SOURCE", requires = "amend_file")]
+    hook_source: Option<String>,
+
+    /// Run `git commit` with the message after confirmation
+    #[arg(long, conflicts_with = "amend_file")]
+    commit: bool,
+
+    /// Approximate number of tokens of the diff to send
+    #[arg(long, value_name = "TOKENS", default_value_t = 6000)]
+    max_diff_tokens: usize,
+}
+
+/// Converts the arguments git passes to a prepare-commit-msg hook into
+/// those of `termgpt commit`, for when termgpt is installed as the hook.
+pub fn hook_args(args: Vec<OsString>) -> Vec<OsString> {
+    let mut rest = args.into_iter();
+    let program = rest.next().unwrap_or_default();
+    let mut converted = vec![program, "commit".into()];
+    if let Some(file) = rest.next() {
+        converted.extend(["--amend-file".into(), file]);
+    }
+    if let Some(source) = rest.next() {
+        converted.extend(["--hook-source".into(), source]);
+    }
+    converted
+}
+
+fn git(args: &[&str]) -> Result<String, Box<dyn Error>> {
+    let output = process::Command::new("git").args(args).output()?;
+    if !output.status.success() {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())?
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Fits the diff into roughly `max_tokens` tokens, at four bytes a token.
+/// The file summary is always kept; each file's diff is kept whole if it
+/// fits, otherwise only its header is, so later small files still make it.
+fn truncate_diff(stat: &str, diff: &str, max_tokens: usize) -> String {
+    let mut budget = (max_tokens * 4).saturating_sub(stat.len());
+    let mut out = format!("{}\n", stat.trim_end());
+    let mut files: Vec<String> = Vec::new();
+    for line in diff.lines() {
+        if line.starts_with("diff --git ") || files.is_empty() {
+            files.push(String::new());
+        }
+        let file = files.last_mut().unwrap();
+        file.push_str(line);
+        file.push('\n');
+    }
+    for file in files {
+        if file.len() <= budget {
+            budget -= file.len();
+            out.push_str(&file);
+            continue;
+        }
+        let header: String = file
+            .lines()
+            .take_while(|line| !line.starts_with("@@"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let omitted = file.lines().count() - header.lines().count();
+        let note = format!("[{} lines of changes omitted]\n", omitted);
+        if header.len() + note.len() <= budget {
+            budget -= header.len() + note.len();
+            out.push_str(&header);
+            out.push_str(&note);
+        }
+    }
+    out
+}
+
+#[tokio::main]
+async fn draft_message(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    system: &str,
+    diff: String,
+) -> Result<String, Box<dyn Error>> {
+    let messages = [
+        ChatGptMessage::new(Role::System, system.to_string()),
+        ChatGptMessage::new(Role::User, diff),
+    ];
+    let mut response = client.get_chatgpt_response(params, &messages).await?;
+    let choice = response.choices.pop().ok_or("no choices returned")?;
+    Ok(choice.message.content.trim().to_string())
+}
+
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{} [y/N]: ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+pub fn run_commit(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    system: Option<&str>,
+    args: &CommitArgs,
+) -> Result<(), Box<dyn Error>> {
+    // Merges, amends and `git commit -m` already have a message.
+    if args.hook_source.as_deref().is_some_and(|s| s != "template") {
+        return Ok(());
+    }
+
+    let diff = git(&["diff", "--cached", "--no-color"])?;
+    if diff.trim().is_empty() {
+        Err("nothing is staged to commit; use `git add` first")?
+    }
+    let stat = git(&["diff", "--cached", "--no-color", "--stat"])?;
+    let diff = truncate_diff(&stat, &diff, args.max_diff_tokens);
+    let system = system.unwrap_or(DEFAULT_COMMIT_PROMPT);
+    let message = draft_message(client, params, system, diff)?;
+
+    if let Some(path) = &args.amend_file {
+        // Keep what git already put in the file, such as its comments.
+        let existing = fs::read_to_string(path).unwrap_or_default();
+        fs::write(path, format!("{}\n{}", message, existing))?;
+    } else if args.commit {
+        if !io::stdin().is_tty() {
+            Err("--commit needs a terminal to confirm the message")?
+        }
+        println!("{}\n", message);
+        if confirm("Commit with this message?")? {
+            let status = process::Command::new("git")
+                .args(["commit", "-m", &message])
+                .status()?;
+            if !status.success() {
+                Err("git commit failed")?
+            }
+        } else {
+            eprintln!("Aborted.");
+        }
+    } else {
+        println!("{}", message);
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde::Serialize as DeriveSerialize;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use termgpt::api::{
+    estimate_cost, ChatApi, ChatGptClient, ChatGptParams, ChatGptResponse,
+    ChatGptUsage,
+};
+use termgpt::message::ChatGptMessage;
+use tokio::task::JoinSet;
+
+/// One model's answer to a prompt sent with --compare.
+pub struct Answer {
+    pub model: String,
+    pub response: Result<ChatGptResponse, String>,
+    pub elapsed: Duration,
+}
+
+impl Answer {
+    fn content(&self) -> Option<&str> {
+        let response = self.response.as_ref().ok()?;
+        Some(&response.choices.first()?.message.content)
+    }
+}
+
+/// The model to look up the price of, without any provider prefix such as
+/// `openai/` that a proxy routes by.
+fn priced_model(model: &str) -> &str {
+    model.rsplit('/').next().unwrap_or(model)
+}
+
+/// Sends the same conversation to each model at once, returning their
+/// answers in the order the models were given. The models are asked
+/// without tools, as their calls couldn't be answered for each of them.
+pub async fn ask_each(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    models: &[String],
+    messages: &[ChatGptMessage],
+) -> Vec<Answer> {
+    let mut tasks = JoinSet::new();
+    for (index, model) in models.iter().enumerate() {
+        let client = client.clone();
+        let mut params = ChatGptParams {
+            model: model.clone(),
+            n: None,
+            tools: Vec::new(),
+            ..params.clone()
+        };
+        params.adapt_to_reasoning_model();
+        let messages = messages.to_vec();
+        tasks.spawn(async move {
+            let start = Instant::now();
+            let response = client
+                .get_chatgpt_response(&params, &messages)
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|response| match response.choices.is_empty() {
+                    true => Err("the API returned no answer".to_string()),
+                    false => Ok(response),
+                });
+            let answer = Answer {
+                model: params.model,
+                response,
+                elapsed: start.elapsed(),
+            };
+            (index, answer)
+        });
+    }
+    let mut answers = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok(answer) = joined {
+            answers.push(answer);
+        }
+    }
+    answers.sort_by_key(|(index, _)| *index);
+    answers.into_iter().map(|(_, answer)| answer).collect()
+}
+
+/// The answers that can be kept, numbered from 0 as /pick takes them from
+/// 1, and which of them is kept: that of the `keep`th model, or the first
+/// if that model's request failed.
+pub fn choices(
+    answers: &[Answer],
+    keep: usize,
+) -> Result<(Vec<ChatGptMessage>, usize), Box<dyn Error>> {
+    let mut choices = Vec::new();
+    let mut kept = 0;
+    for (index, answer) in answers.iter().enumerate() {
+        if let Ok(response) = &answer.response {
+            if index == keep {
+                kept = choices.len();
+            }
+            choices.push(response.choices[0].message.clone());
+        }
+    }
+    if choices.is_empty() {
+        Err("every model's request failed")?
+    }
+    Ok((choices, kept))
+}
+
+/// Shows each answer under a heading with its model, numbered as /pick
+/// takes it, or the error its request failed with.
+pub fn join_answers(
+    answers: &[Answer],
+    kept: usize,
+    render: impl Fn(&str) -> String,
+) -> String {
+    let mut number = 0;
+    let sections: Vec<String> = answers
+        .iter()
+        .map(|answer| match &answer.response {
+            Ok(response) => {
+                number += 1;
+                let note = if number - 1 == kept { " (kept)" } else { "" };
+                format!(
+                    "--- {}: {}{} ---\n\n{}",
+                    number,
+                    answer.model,
+                    note,
+                    render(&response.choices[0].message.content)
+                )
+            }
+            Err(e) => format!("--- {}: failed ---\n\n{}", answer.model, e),
+        })
+        .collect();
+    sections.join("\n\n")
+}
+
+/// Prints each model's latency, tokens and cost to stderr, for --stats.
+pub fn print_stats(answers: &[Answer]) {
+    for answer in answers {
+        let mut line =
+            format!("{}: {:.3}s", answer.model, answer.elapsed.as_secs_f64());
+        match &answer.response {
+            Ok(response) => {
+                if let Some(usage) = &response.usage {
+                    line.push_str(&format!(
+                        ", {} prompt + {} completion tokens",
+                        usage.prompt_tokens, usage.completion_tokens
+                    ));
+                    if let Some(cost) =
+                        estimate_cost(priced_model(&answer.model), usage)
+                    {
+                        line.push_str(&format!(", ${:.4}", cost));
+                    }
+                }
+            }
+            Err(_) => line.push_str(", failed"),
+        }
+        eprintln!("{}", line);
+    }
+}
+
+#[derive(DeriveSerialize)]
+struct JsonAnswer<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<&'a ChatGptUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cost: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    elapsed_ms: u128,
+}
+
+/// The answers for --format json, as an object keyed by model in the
+/// order the models were given.
+pub struct JsonAnswers<'a>(pub &'a [Answer]);
+
+impl Serialize for JsonAnswers<'_> {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for answer in self.0 {
+            let usage =
+                answer.response.as_ref().ok().and_then(|r| r.usage.as_ref());
+            let json =
+                JsonAnswer {
+                    content: answer.content(),
+                    finish_reason: answer.response.as_ref().ok().and_then(
+                        |r| r.choices.first()?.finish_reason.as_deref(),
+                    ),
+                    usage,
+                    cost: usage.and_then(|u| {
+                        estimate_cost(priced_model(&answer.model), u)
+                    }),
+                    error: answer.response.as_ref().err().map(String::as_str),
+                    elapsed_ms: answer.elapsed.as_millis(),
+                };
+            map.serialize_entry(&answer.model, &json)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termgpt::api::ChatGptChoice;
+    use termgpt::message::Role;
+
+    fn answer(model: &str, content: Result<&str, &str>) -> Answer {
+        Answer {
+            model: model.into(),
+            response: content
+                .map(|content| ChatGptResponse {
+                    model: None,
+                    choices: vec![ChatGptChoice {
+                        message: ChatGptMessage::new(
+                            Role::Assistant,
+                            content.into(),
+                        ),
+                        finish_reason: Some("stop".into()),
+                    }],
+                    usage: None,
+                    rate_limits: None,
+                })
+                .map_err(String::from),
+            elapsed: Duration::from_millis(1200),
+        }
+    }
+
+    #[test]
+    fn failed_models_are_shown_but_not_numbered() {
+        let answers = [
+            answer("gpt-4o", Ok("Paris.")),
+            answer("llama3:70b", Err("connection refused")),
+            answer("gpt-4o-mini", Ok("Paris!")),
+        ];
+        let (choices, kept) = choices(&answers, 1).unwrap();
+        assert_eq!(choices.len(), 2);
+        assert_eq!(kept, 0);
+        assert_eq!(
+            join_answers(&answers, kept, str::to_string),
+            "--- 1: gpt-4o (kept) ---\n\nParis.\n\n\
+             --- llama3:70b: failed ---\n\nconnection refused\n\n\
+             --- 2: gpt-4o-mini ---\n\nParis!"
+        );
+    }
+
+    #[test]
+    fn json_is_keyed_by_model_in_order() {
+        let answers = [
+            answer("b-model", Ok("one")),
+            answer("a-model", Err("no such model")),
+        ];
+        let json = serde_json::to_string(&JsonAnswers(&answers)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"b-model":{"content":"one","finish_reason":"stop","elapsed_ms":1200},"a-model":{"error":"no such model","elapsed_ms":1200}}"#
+        );
+    }
+}
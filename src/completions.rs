@@ -0,0 +1,55 @@
+use crate::preset::Preset;
+use crate::{Args, Config};
+use clap::builder::PossibleValuesParser;
+use clap::{Args as ClapArgs, CommandFactory};
+use clap_complete::{generate, Shell};
+use std::collections::BTreeMap;
+use std::io;
+use termgpt::api::MODEL_PRICES;
+
+const INSTALL_HELP: &str = "\
+Install:
+  bash        termgpt completions bash > ~/.local/share/bash-completion/completions/termgpt
+  zsh         termgpt completions zsh > ~/.zfunc/_termgpt  (with ~/.zfunc in $fpath)
+  fish        termgpt completions fish > ~/.config/fish/completions/termgpt.fish
+  powershell  termgpt completions powershell >> $PROFILE
+
+Preset and model names, and model aliases, are taken from the config when
+the script is generated, so regenerate it after adding presets.";
+
+#[derive(ClapArgs)]
+#[command(after_help = INSTALL_HELP)]
+pub struct CompletionsArgs {
+    /// The shell to generate a completion script for
+    shell: Shell,
+}
+
+/// Prints a completion script, with the presets and models known at this
+/// point offered as values for --preset and --model.
+pub fn run_completions(
+    args: &CompletionsArgs,
+    config: &Config,
+    presets: &BTreeMap<String, Preset>,
+) {
+    let presets: Vec<String> = presets.keys().cloned().collect();
+    let mut models: Vec<String> = config.model.iter().cloned().collect();
+    let mut aliases: Vec<&str> =
+        config.models.keys().map(String::as_str).collect();
+    aliases.sort();
+    let prices = MODEL_PRICES.iter().map(|(model, _, _)| *model);
+    for model in aliases.into_iter().chain(prices) {
+        if !models.iter().any(|m| m == model) {
+            models.push(model.to_string());
+        }
+    }
+
+    let mut command = Args::command().mut_arg("model", |arg| {
+        arg.value_parser(PossibleValuesParser::new(models))
+    });
+    if !presets.is_empty() {
+        command = command.mut_arg("preset", |arg| {
+            arg.value_parser(PossibleValuesParser::new(presets))
+        });
+    }
+    generate(args.shell, &mut command, "termgpt", &mut io::stdout());
+}
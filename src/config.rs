@@ -0,0 +1,390 @@
+use crate::mcp::McpConfig;
+use crate::preset::Preset;
+use crate::theme::ThemeConfig;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use termgpt::listener::OutputFileFormat;
+use termgpt::redact::RedactMode;
+
+/// Defaults loaded from the config file; command-line flags take precedence.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub model: Option<String>,
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+    pub user_agent: Option<String>,
+    pub cache_hints: Option<bool>,
+    pub cache: Option<bool>,
+    pub cache_ttl: Option<u64>,
+    pub system: Option<String>,
+    pub context_datetime: Option<bool>,
+    pub context_env: Option<bool>,
+    pub temperature: Option<f32>,
+    pub stream: Option<bool>,
+    pub sync: Option<bool>,
+    pub show_usage: Option<bool>,
+    pub plain: Option<bool>,
+    pub output_format: Option<OutputFileFormat>,
+    pub output_timestamps: Option<bool>,
+    pub pager: Option<bool>,
+    pub max_width: Option<usize>,
+    pub copy_code_key: Option<String>,
+    pub prompt_indicator: Option<String>,
+    pub prompt_color: Option<String>,
+    pub assistant_label: Option<String>,
+    pub send_empty_input: Option<bool>,
+    pub idle_timeout: Option<u64>,
+    pub speak: Option<bool>,
+    pub voice: Option<String>,
+    pub audio_player: Option<String>,
+    pub commit_prompt: Option<String>,
+    pub db: Option<String>,
+    pub log_jsonl: Option<String>,
+    pub moderate: Option<bool>,
+    pub moderation_model: Option<String>,
+    pub presets: HashMap<String, Preset>,
+    pub models: HashMap<String, String>,
+    pub interpreters: HashMap<String, String>,
+    pub mcp: McpConfig,
+    pub redact: Option<RedactMode>,
+    pub redact_patterns: Vec<String>,
+    pub theme: ThemeConfig,
+}
+
+/// Each config file key and what it sets, for the man page.
+pub const KEYS: &[(&str, &str)] = &[
+    ("model", "Default model, as with --model"),
+    (
+        "api_key",
+        "OpenAI API key, used if neither --api-key nor OPENAI_API_KEY is set",
+    ),
+    (
+        "base_url",
+        "Base URL of an OpenAI-compatible API, as with --base-url",
+    ),
+    (
+        "user_agent",
+        "User-Agent header for requests, as with --user-agent",
+    ),
+    (
+        "cache_hints",
+        "Mark stable context as cacheable, as with --cache-hints",
+    ),
+    (
+        "cache",
+        "Reuse responses to repeated requests, as with --cache",
+    ),
+    (
+        "cache_ttl",
+        "Seconds to use cached responses for, as with --cache-ttl",
+    ),
+    ("system", "Default system prompt, as with --system"),
+    (
+        "context_datetime",
+        "Tell the model the date and time, as with --context-datetime",
+    ),
+    (
+        "context_env",
+        "Tell the model the OS and shell, as with --context-env",
+    ),
+    (
+        "temperature",
+        "Default sampling temperature, as with --temperature",
+    ),
+    ("stream", "Stream responses by default, as with --stream"),
+    (
+        "sync",
+        "Sync session files to disk as they are written, as with --sync",
+    ),
+    (
+        "show_usage",
+        "Print token usage by default, as with --show-usage",
+    ),
+    (
+        "plain",
+        "Print responses without rendering, as with --plain",
+    ),
+    (
+        "output_format",
+        "Layout of output files, as with --output-format: plain, labelled \
+         or markdown",
+    ),
+    (
+        "output_timestamps",
+        "Put times in output file headings, as with --output-timestamps",
+    ),
+    (
+        "pager",
+        "Page long responses in the REPL, true by default; false is as with \
+         --no-pager",
+    ),
+    (
+        "max_width",
+        "Widest that responses are rendered, as with --width",
+    ),
+    (
+        "copy_code_key",
+        "Key that copies a code block of the last response in the REPL, \
+         ctrl-y by default",
+    ),
+    (
+        "prompt_indicator",
+        "REPL prompt, as with --prompt-indicator",
+    ),
+    (
+        "prompt_color",
+        "Color of the REPL prompt: a name, a 256-color index or #rrggbb",
+    ),
+    (
+        "assistant_label",
+        "Label shown before each response in the REPL, as with \
+         --assistant-label",
+    ),
+    (
+        "send_empty_input",
+        "Send blank lines typed in the REPL instead of ignoring them",
+    ),
+    (
+        "idle_timeout",
+        "Seconds at the REPL prompt before exiting, as with --idle-timeout",
+    ),
+    ("speak", "Read each response aloud, as with --speak"),
+    ("voice", "Voice to read responses in, as with --voice"),
+    (
+        "audio_player",
+        "Command that plays responses read aloud, as with --audio-player",
+    ),
+    (
+        "commit_prompt",
+        "System prompt used by the commit subcommand",
+    ),
+    (
+        "db",
+        "SQLite database to store conversations in, as with --db",
+    ),
+    (
+        "log_jsonl",
+        "JSON Lines file every conversation is appended to, as with \
+         --log-jsonl",
+    ),
+    (
+        "moderate",
+        "Refuse to send messages the moderation endpoint flags, as with \
+         --moderate",
+    ),
+    (
+        "moderation_model",
+        "Moderation model, as with --moderation-model",
+    ),
+    (
+        "redact",
+        "Replace secrets in messages, as with --redact: both, before they \
+         are sent, or persisted, only when saved",
+    ),
+    (
+        "redact_patterns",
+        "Regular expressions for secrets to redact as well as the common \
+         shapes of keys and passwords; only a group named secret is \
+         replaced if there is one",
+    ),
+    ("presets", "A table of named presets, as used by --preset"),
+    (
+        "models",
+        "A table of short names for models, such as fast = \"gpt-4o-mini\", \
+         that --model takes in place of the names they stand for",
+    ),
+    (
+        "interpreters",
+        "A table of commands that run code blocks for /run and --run, by \
+         fence language, such as python = \"python3\"",
+    ),
+    (
+        "mcp",
+        "Model Context Protocol servers whose tools the model may call, \
+         as tables under mcp.servers with a command, and args and env if \
+         need be",
+    ),
+    (
+        "theme",
+        "Markdown colors: a base theme as with --theme, and fg and bg for \
+         paragraph, headers, bold, italic, inline_code, code_block and \
+         quote, and a syntect code_theme for highlighting code blocks",
+    ),
+];
+
+/// The termgpt directory under `$XDG_CONFIG_HOME`, or `~/.config`.
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))
+        .map(|dir| dir.join("termgpt"))
+}
+
+/// Reads and parses a TOML file, returning `None` if it does not exist.
+pub fn read_toml<T: DeserializeOwned>(
+    path: &Path,
+) -> Result<Option<T>, Box<dyn Error>> {
+    match read_text(path)? {
+        Some(text) => parse_toml(path, &text).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn read_text(path: &Path) -> Result<Option<String>, Box<dyn Error>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(Some(text)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("could not read {}: {}", path.display(), e))?,
+    }
+}
+
+fn parse_toml<T: DeserializeOwned>(
+    path: &Path,
+    text: &str,
+) -> Result<T, Box<dyn Error>> {
+    toml::from_str(text).map_err(|e| {
+        let line = e
+            .span()
+            .map(|span| text[..span.start].matches('\n').count() + 1)
+            .unwrap_or(1);
+        format!(
+            "invalid config file {} at line {}: {}",
+            path.display(),
+            line,
+            e.message()
+        )
+        .into()
+    })
+}
+
+impl Config {
+    pub fn load() -> Result<Config, Box<dyn Error>> {
+        match config_dir() {
+            Some(dir) => Config::from_file(&dir.join("config.toml")),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Reads the config file, expanding environment variables in every
+    /// string in it, however deep, so that no key can be missed.
+    pub fn from_file(path: &Path) -> Result<Config, Box<dyn Error>> {
+        let Some(text) = read_text(path)? else {
+            return Ok(Config::default());
+        };
+        // Parsed as written first, so that a mistake is reported with its
+        // line, which the expanded values no longer have.
+        parse_toml::<Config>(path, &text)?;
+        let mut value = toml::Value::Table(parse_toml(path, &text)?);
+        let invalid = |e: String| {
+            format!("invalid config file {}: {}", path.display(), e)
+        };
+        expand_values(&mut value).map_err(invalid)?;
+        value.try_into().map_err(|e| invalid(e.to_string()).into())
+    }
+}
+
+/// The model an alias from the models table stands for, or the name as
+/// given if it isn't one.
+pub fn resolve_model<'a>(
+    models: &'a HashMap<String, String>,
+    name: &'a str,
+) -> &'a str {
+    models.get(name).map_or(name, String::as_str)
+}
+
+/// Expands environment variables in a string, or in each string within an
+/// array or table.
+fn expand_values(value: &mut toml::Value) -> Result<(), String> {
+    match value {
+        toml::Value::String(text) => *text = expand_env(text)?,
+        toml::Value::Array(values) => {
+            for value in values {
+                expand_values(value)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                expand_values(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces each `${NAME}` with the value of the environment variable, or
+/// fails if it is unset. `$${` is a literal `${`.
+fn expand_env(text: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or("unterminated ${ in value")?;
+        let name = &rest[start + 2..start + end];
+        let value = env::var(name)
+            .map_err(|_| format!("environment variable {} is not set", name))?;
+        out.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(text: &str) -> Result<Config, Box<dyn Error>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, text).unwrap();
+        Config::from_file(&path)
+    }
+
+    #[test]
+    fn variables_are_expanded_in_every_string() {
+        env::set_var("TERMGPT_TEST_TOKEN", "secret");
+        let config = config(
+            "user_agent = \"bot/${TERMGPT_TEST_TOKEN}\"\n\
+             system = \"Costs $${PRICE}.\"\n\
+             [mcp.servers.github]\n\
+             command = \"github-mcp\"\n\
+             args = [\"--token=${TERMGPT_TEST_TOKEN}\"]\n\
+             env = { TOKEN = \"${TERMGPT_TEST_TOKEN}\" }\n",
+        )
+        .unwrap();
+        assert_eq!(config.user_agent.as_deref(), Some("bot/secret"));
+        assert_eq!(config.system.as_deref(), Some("Costs ${PRICE}."));
+        let server = &config.mcp.servers["github"];
+        assert_eq!(server.args, ["--token=secret"]);
+        assert_eq!(server.env["TOKEN"], "secret");
+    }
+
+    #[test]
+    fn unset_variables_are_an_error() {
+        let error = config("[models]\nfast = \"${TERMGPT_TEST_UNSET}\"\n")
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().ends_with(
+                "environment variable TERMGPT_TEST_UNSET is not set"
+            ),
+            "{}",
+            error
+        );
+    }
+}
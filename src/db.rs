@@ -0,0 +1,265 @@
+use crate::estimate_tokens;
+use chrono::{SecondsFormat, Utc};
+use clap::Subcommand;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::error::Error;
+use std::slice;
+use std::time::Duration;
+use termgpt::listener::ChatMessageListener;
+use termgpt::message::ChatGptMessage;
+
+/// Each step of the schema, in order. A database records how many it has
+/// had applied in `user_version`, so new steps go on the end and existing
+/// ones are never edited.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE sessions (
+        id INTEGER PRIMARY KEY,
+        name TEXT,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX sessions_name ON sessions (name);
+    CREATE TABLE messages (
+        session_id INTEGER NOT NULL REFERENCES sessions (id),
+        idx INTEGER NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        model TEXT,
+        -- An estimate, as the API only reports usage for whole requests
+        tokens INTEGER,
+        created_at TEXT NOT NULL,
+        PRIMARY KEY (session_id, idx)
+    );
+"];
+
+/// How long to wait for another termgpt writing to the same database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Subcommand)]
+pub enum SessionsCommand {
+    /// List the conversations in the database
+    List,
+    /// Find messages containing some text
+    Search {
+        /// Text to look for, ignoring ASCII case
+        query: String,
+    },
+}
+
+/// Opens the database, creating it or bringing its schema up to date.
+pub fn open(path: &str) -> Result<Connection, Box<dyn Error>> {
+    let mut conn = Connection::open(path)
+        .map_err(|e| format!("could not open database {}: {}", path, e))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    let version: i64 =
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let version = version as usize;
+    if version > MIGRATIONS.len() {
+        Err(format!(
+            "database {} was written by a newer version of termgpt",
+            path
+        ))?
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i as i64 + 1)?;
+        tx.commit()?;
+    }
+    Ok(conn)
+}
+
+/// The database given with --db, and the model its messages are written
+/// as from, so that a listener for it can be opened again for a branch.
+#[derive(Clone)]
+pub struct Database {
+    pub path: String,
+    pub model: String,
+}
+
+impl Database {
+    /// A listener adding to the conversation named `session`.
+    pub fn listener(
+        &self,
+        session: Option<&str>,
+    ) -> Result<DbListener, Box<dyn Error>> {
+        DbListener::new(&self.path, &self.model, session)
+    }
+}
+
+/// The registered name of the listener writing to a database.
+pub fn listener_name(path: &str) -> String {
+    format!("database {}", path)
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Writes each message to the database. A run with a session file adds to
+/// the row of the same name, so that resuming it continues the same
+/// conversation; otherwise each run is a conversation of its own. The row
+/// is only created once there is a message to put in it.
+pub struct DbListener {
+    conn: Connection,
+    model: String,
+    session: Option<String>,
+    session_id: Option<i64>,
+}
+
+impl DbListener {
+    pub fn new(
+        path: &str,
+        model: &str,
+        session: Option<&str>,
+    ) -> Result<DbListener, Box<dyn Error>> {
+        Ok(DbListener {
+            conn: open(path)?,
+            model: model.to_string(),
+            session: session.map(String::from),
+            session_id: None,
+        })
+    }
+
+    fn session_id(&mut self) -> rusqlite::Result<i64> {
+        if let Some(id) = self.session_id {
+            return Ok(id);
+        }
+        let existing = match &self.session {
+            Some(name) => self
+                .conn
+                .query_row(
+                    "SELECT id FROM sessions WHERE name = ?1
+                     ORDER BY id DESC LIMIT 1",
+                    [name],
+                    |row| row.get(0),
+                )
+                .optional()?,
+            None => None,
+        };
+        let id = match existing {
+            Some(id) => id,
+            None => {
+                self.conn.execute(
+                    "INSERT INTO sessions (name, created_at) VALUES (?1, ?2)",
+                    params![self.session, now()],
+                )?;
+                self.conn.last_insert_rowid()
+            }
+        };
+        self.session_id = Some(id);
+        Ok(id)
+    }
+}
+
+impl ChatMessageListener for DbListener {
+    fn on_message(
+        &mut self,
+        message: &ChatGptMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        let session_id = self.session_id()?;
+        let tokens = estimate_tokens(slice::from_ref(message)) as i64;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO messages
+             (session_id, idx, role, content, model, tokens, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                session_id,
+                message.id.map(|id| id as i64),
+                message.role.name(),
+                message.content,
+                self.model,
+                tokens,
+                now()
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// The first line of some text, cut short to fit on a line of a listing.
+fn excerpt(text: &str) -> String {
+    let line = text.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+    let line = line.trim();
+    match line.char_indices().nth(60) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+pub fn run_sessions_command(
+    command: &SessionsCommand,
+    path: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let path = path.ok_or(
+        "no database to read sessions from (use --db or set db in the \
+         config file)",
+    )?;
+    let conn = open(path)?;
+    match command {
+        SessionsCommand::List => {
+            let mut stmt = conn.prepare(
+                "SELECT s.id, s.name, s.created_at, COUNT(m.idx),
+                   (SELECT content FROM messages
+                    WHERE session_id = s.id AND role = 'user'
+                    ORDER BY idx LIMIT 1)
+                 FROM sessions s LEFT JOIN messages m ON m.session_id = s.id
+                 GROUP BY s.id ORDER BY s.id",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })?;
+            for row in rows {
+                let (id, name, created_at, count, first) = row?;
+                let label = name.unwrap_or_else(|| {
+                    excerpt(first.as_deref().unwrap_or_default())
+                });
+                println!(
+                    "{:>4}  {}  {:>4} messages  {}",
+                    id,
+                    &created_at[..created_at.len().min(19)],
+                    count,
+                    label
+                );
+            }
+        }
+        SessionsCommand::Search { query } => {
+            let mut stmt = conn.prepare(
+                "SELECT m.session_id, s.name, m.idx, m.role, m.content
+                 FROM messages m JOIN sessions s ON s.id = m.session_id
+                 WHERE instr(lower(m.content), lower(?1)) > 0
+                 ORDER BY m.session_id, m.idx",
+            )?;
+            let rows = stmt.query_map([query], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            })?;
+            let query = query.to_lowercase();
+            for row in rows {
+                let (id, name, idx, role, content) = row?;
+                let line = content
+                    .lines()
+                    .find(|l| l.to_lowercase().contains(&query))
+                    .unwrap_or(&content);
+                println!(
+                    "{}#{}  {}: {}",
+                    name.unwrap_or_else(|| id.to_string()),
+                    idx,
+                    role,
+                    excerpt(line)
+                );
+            }
+        }
+    }
+    Ok(())
+}
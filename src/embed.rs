@@ -0,0 +1,203 @@
+use crate::tokens;
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use std::error::Error;
+use std::io::{self, Read};
+use std::ops::Range;
+use termgpt::api::{ChatGptClient, DEFAULT_EMBEDDING_MODEL};
+use termimad::crossterm::tty::IsTty;
+
+/// The most inputs the embeddings endpoint takes in one request.
+const MAX_BATCH_INPUTS: usize = 2048;
+
+/// The most tokens, across its inputs, one request may be.
+const MAX_BATCH_TOKENS: usize = 300_000;
+
+#[derive(Args)]
+pub struct EmbedArgs {
+    /// Texts to embed, each as one input; stdin is read if there are none
+    inputs: Vec<String>,
+
+    /// Embed each non-blank line as an input of its own
+    #[arg(long)]
+    lines: bool,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = EmbedFormat::Json)]
+    format: EmbedFormat,
+
+    /// Print the number of inputs and tokens to stderr
+    #[arg(long)]
+    stats: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum EmbedFormat {
+    /// An array of objects with each input and its embedding
+    Json,
+    /// One line of comma-separated numbers per input
+    Csv,
+}
+
+#[derive(Serialize)]
+struct Embedded<'a> {
+    input: &'a str,
+    embedding: &'a [f32],
+}
+
+/// The texts to embed, each with the number it's reported by: its place
+/// among the inputs, or with --lines its line.
+fn read_inputs(
+    args: &EmbedArgs,
+) -> Result<Vec<(usize, String)>, Box<dyn Error>> {
+    let mut texts = args.inputs.clone();
+    if texts.is_empty() {
+        if io::stdin().is_tty() {
+            Err("nothing to embed (give text as arguments or pipe it in)")?
+        }
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        texts.push(input);
+    }
+    if !args.lines {
+        return Ok(texts
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| (i + 1, t))
+            .collect());
+    }
+    let lines = texts.iter().flat_map(|text| text.lines());
+    Ok(lines
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| (i + 1, line.to_string()))
+        .collect())
+}
+
+/// Splits the inputs into requests within the endpoint's limits.
+fn batches(sizes: &[usize]) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut tokens = 0;
+    for (i, &size) in sizes.iter().enumerate() {
+        let full = i - start == MAX_BATCH_INPUTS
+            || (i > start && tokens + size > MAX_BATCH_TOKENS);
+        if full {
+            batches.push(start..i);
+            start = i;
+            tokens = 0;
+        }
+        tokens += size;
+    }
+    if start < sizes.len() {
+        batches.push(start..sizes.len());
+    }
+    batches
+}
+
+/// Names the inputs of a batch, such as "inputs 3 to 5".
+fn describe(numbers: &[usize], lines: bool) -> String {
+    let noun = if lines { "line" } else { "input" };
+    match numbers {
+        [number] => format!("{} {}", noun, number),
+        [first, .., last] => format!("{}s {} to {}", noun, first, last),
+        [] => format!("no {}s", noun),
+    }
+}
+
+/// Embeds each input, a batch at a time, and prints the embeddings. A
+/// batch that fails is reported and the rest are still embedded.
+#[tokio::main]
+pub async fn run_embed(
+    client: &ChatGptClient,
+    model: Option<&str>,
+    args: &EmbedArgs,
+) -> Result<(), Box<dyn Error>> {
+    let model = model.unwrap_or(DEFAULT_EMBEDDING_MODEL);
+    let inputs = read_inputs(args)?;
+    if inputs.is_empty() {
+        Err("nothing to embed")?
+    }
+    let sizes: Vec<usize> = inputs
+        .iter()
+        .map(|(_, text)| tokens::count(model, text))
+        .collect();
+
+    let mut embedded = Vec::new();
+    let mut failed = 0;
+    let mut tokens = 0;
+    for batch in batches(&sizes) {
+        let batch = &inputs[batch];
+        let texts: Vec<&str> = batch.iter().map(|(_, t)| t.as_str()).collect();
+        match client.embed(model, &texts).await {
+            Ok(embeddings) => {
+                tokens += embeddings.usage.map_or(0, |u| u.total_tokens);
+                embedded.extend(texts.into_iter().zip(embeddings.vectors));
+            }
+            Err(e) => {
+                let numbers: Vec<usize> =
+                    batch.iter().map(|(n, _)| *n).collect();
+                eprintln!(
+                    "termgpt: {} failed: {}",
+                    describe(&numbers, args.lines),
+                    e
+                );
+                failed += batch.len();
+            }
+        }
+    }
+
+    match args.format {
+        EmbedFormat::Json => {
+            let output: Vec<Embedded> = embedded
+                .iter()
+                .map(|(input, embedding)| Embedded { input, embedding })
+                .collect();
+            println!("{}", serde_json::to_string(&output)?);
+        }
+        EmbedFormat::Csv => {
+            for (_, embedding) in &embedded {
+                let numbers: Vec<String> =
+                    embedding.iter().map(|x| x.to_string()).collect();
+                println!("{}", numbers.join(","));
+            }
+        }
+    }
+    if args.stats {
+        eprintln!(
+            "termgpt: {} inputs embedded with {}, {} tokens",
+            embedded.len(),
+            model,
+            tokens
+        );
+    }
+    if failed > 0 {
+        Err(format!("{} of {} inputs failed", failed, inputs.len()))?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_stay_within_the_limits() {
+        let sizes = vec![1; MAX_BATCH_INPUTS + 1];
+        assert_eq!(
+            batches(&sizes),
+            [0..MAX_BATCH_INPUTS, MAX_BATCH_INPUTS..MAX_BATCH_INPUTS + 1]
+        );
+        let sizes = [200_000, 90_000, 20_000, 1];
+        assert_eq!(batches(&sizes), [0..2, 2..4]);
+        // An input too large on its own is sent alone, for the API to
+        // refuse.
+        assert_eq!(batches(&[400_000, 1]), [0..1, 1..2]);
+    }
+
+    #[test]
+    fn failed_inputs_are_named() {
+        assert_eq!(describe(&[4], false), "input 4");
+        assert_eq!(describe(&[2, 3, 7], true), "lines 2 to 7");
+    }
+}
@@ -0,0 +1,173 @@
+use crate::code_blocks;
+use clap::{Args, ValueHint};
+use reedline::{
+    DefaultPrompt, DefaultPromptSegment, EditCommand, Reedline, Signal,
+};
+use std::env;
+use std::error::Error;
+use std::io;
+use std::io::{BufRead, Write};
+use std::process;
+use termgpt::api::{ChatApi, ChatGptClient, ChatGptParams};
+use termgpt::message::{ChatGptMessage, ChatMessages, Role};
+use termgpt::session::SessionAppendListener;
+use termimad::crossterm::style::Stylize;
+use termimad::crossterm::tty::IsTty;
+
+#[derive(Args)]
+pub struct ExecArgs {
+    /// What the command should do
+    task: String,
+
+    /// Append the task, command and exit status to a session file
+    #[arg(short, long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    session: Option<String>,
+}
+
+/// The shell commands are run with, and the name the model is told.
+pub fn user_shell() -> (String, String) {
+    let shell = if cfg!(windows) {
+        env::var("COMSPEC").unwrap_or_else(|_| "cmd.exe".to_string())
+    } else {
+        env::var("SHELL").unwrap_or_else(|_| "sh".to_string())
+    };
+    let name = shell
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or(&shell)
+        .to_string();
+    (shell, name)
+}
+
+fn system_prompt(shell: &str) -> String {
+    format!(
+        "You turn requests into shell commands. The user is running {} \
+         on {}. Reply with exactly one command, which may span several \
+         lines, in a single fenced code block and nothing else. Do not \
+         explain it.",
+        shell,
+        env::consts::OS
+    )
+}
+
+/// Extracts the command from a response: the first code block if there is
+/// one, otherwise the whole response without inline code marks.
+fn extract_command(response: &str) -> Option<String> {
+    let block = code_blocks(response).into_iter().next();
+    let command = match &block {
+        Some(block) => block.as_str(),
+        None => response.trim().trim_matches('`'),
+    };
+    let command = command
+        .trim()
+        .lines()
+        .map(|line| line.strip_prefix("$ ").unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    (!command.is_empty()).then_some(command)
+}
+
+#[tokio::main]
+async fn ask(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    messages: &[ChatGptMessage],
+) -> Result<String, Box<dyn Error>> {
+    let mut response = client.get_chatgpt_response(params, messages).await?;
+    let choice = response.choices.pop().ok_or("no choices returned")?;
+    Ok(choice.message.content)
+}
+
+fn edit_command(command: &str) -> io::Result<Option<String>> {
+    let mut editor = Reedline::create();
+    editor.run_edit_commands(&[EditCommand::InsertString(command.into())]);
+    let prompt = DefaultPrompt::new(
+        DefaultPromptSegment::Basic("edit".to_string()),
+        DefaultPromptSegment::Empty,
+    );
+    match editor.read_line(&prompt)? {
+        Signal::Success(line) if !line.trim().is_empty() => Ok(Some(line)),
+        _ => Ok(None),
+    }
+}
+
+/// Asks whether to run the command, letting it be edited first. Returns
+/// the command to run, or `None` unless the answer is an explicit yes.
+fn confirm(mut command: String, styled: bool) -> io::Result<Option<String>> {
+    let stdin = io::stdin();
+    loop {
+        if styled {
+            println!("\n    {}\n", command.as_str().bold());
+        } else {
+            println!("\n    {}\n", command);
+        }
+        print!("Run this command? [y]es, [e]dit, [N]o: ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        stdin.lock().read_line(&mut answer)?;
+        match answer.trim() {
+            "y" | "Y" | "yes" => return Ok(Some(command)),
+            "e" | "E" | "edit" => match edit_command(&command)? {
+                Some(edited) => command = edited,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        }
+    }
+}
+
+pub fn run_exec(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    args: &ExecArgs,
+    styled: bool,
+    sync: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !io::stdin().is_tty() {
+        Err("exec needs a terminal to confirm the command")?
+    }
+    let (shell, shell_name) = user_shell();
+    let messages = [
+        ChatGptMessage::new(Role::System, system_prompt(&shell_name)),
+        ChatGptMessage::new(Role::User, args.task.clone()),
+    ];
+    let response = ask(client, params, &messages)?;
+    let command = extract_command(&response)
+        .ok_or("the response did not contain a command")?;
+
+    let Some(command) = confirm(command, styled)? else {
+        eprintln!("Aborted.");
+        return Ok(());
+    };
+
+    let flag = if cfg!(windows) { "/C" } else { "-c" };
+    let status = process::Command::new(&shell)
+        .arg(flag)
+        .arg(&command)
+        .status()?;
+    let outcome = match status.code() {
+        Some(code) => format!("exited with status {}", code),
+        None => "was killed by a signal".to_string(),
+    };
+    eprintln!("termgpt: command {}", outcome);
+
+    if let Some(filename) = &args.session {
+        let mut session = ChatMessages::from_file(filename)?;
+        let listener = SessionAppendListener::new(filename, sync, true)?;
+        session.register(format!("session file {}", filename), listener);
+        session.push(ChatGptMessage::new(Role::User, args.task.clone()))?;
+        session.push(ChatGptMessage::new(
+            Role::Assistant,
+            format!("```\n{}\n```", command),
+        ))?;
+        session.push(ChatGptMessage::new(
+            Role::User,
+            format!("I ran it and it {}.", outcome),
+        ))?;
+    }
+
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+    Ok(())
+}
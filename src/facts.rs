@@ -0,0 +1,88 @@
+use chrono::Local;
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::path::Path;
+use termgpt::message::{ChatGptMessage, Role};
+
+/// Which facts about the present to tell the model.
+#[derive(Clone, Copy, Default)]
+pub struct Facts {
+    pub datetime: bool,
+    pub env: bool,
+}
+
+impl Facts {
+    fn lines(self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.datetime {
+            let now = Local::now();
+            lines.push(format!(
+                "Current date: {}, time {}, timezone {}",
+                now.format("%Y-%m-%d (%A)"),
+                now.format("%H:%M"),
+                timezone(&now.format("%:z").to_string()),
+            ));
+        }
+        if self.env {
+            let mut line = format!(
+                "Operating system: {} ({})",
+                env::consts::OS,
+                env::consts::ARCH
+            );
+            if let Some(shell) = shell() {
+                line.push_str(&format!(", shell {}", shell));
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Adds the facts to the leading system message of a request, or
+    /// starts it with one. This is done as each request is made, so that
+    /// the date is right however long the REPL has been open, and the
+    /// facts never reach the session.
+    pub fn apply<'a>(
+        self,
+        context: Cow<'a, [ChatGptMessage]>,
+    ) -> Cow<'a, [ChatGptMessage]> {
+        let lines = self.lines();
+        if lines.is_empty() {
+            return context;
+        }
+        let facts = lines.join("\n");
+        let mut context = context.into_owned();
+        match context.first_mut() {
+            Some(first) if first.role == Role::System => {
+                first.content = format!("{}\n\n{}", first.content, facts);
+            }
+            _ => context.insert(0, ChatGptMessage::new(Role::System, facts)),
+        }
+        Cow::Owned(context)
+    }
+}
+
+/// The name of the local timezone, such as Europe/London, with its offset,
+/// or only the offset if the name can't be found.
+fn timezone(offset: &str) -> String {
+    let name = env::var("TZ")
+        .ok()
+        .map(|tz| tz.trim_start_matches(':').to_string())
+        .filter(|tz| !tz.is_empty())
+        .or_else(|| {
+            let target = fs::read_link("/etc/localtime").ok()?;
+            let target = target.to_str()?;
+            let (_, name) = target.split_once("zoneinfo/")?;
+            Some(name.to_string())
+        });
+    match name {
+        Some(name) => format!("{} (UTC{})", name, offset),
+        None => format!("UTC{}", offset),
+    }
+}
+
+fn shell() -> Option<String> {
+    let shell = env::var("SHELL").ok()?;
+    let name = Path::new(&shell).file_name()?.to_str()?;
+    Some(name.to_string())
+}
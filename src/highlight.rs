@@ -0,0 +1,71 @@
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn themes() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Checks that a highlighting theme is one of those bundled with syntect.
+pub fn check_theme(name: &str) -> Result<(), String> {
+    if themes().themes.contains_key(name) {
+        return Ok(());
+    }
+    let available: Vec<&str> =
+        themes().themes.keys().map(String::as_str).collect();
+    Err(format!(
+        "unknown code theme `{}` for theme.code_theme (available: {})",
+        name,
+        available.join(", ")
+    ))
+}
+
+/// Highlights a code block for the terminal, as a block padded on the
+/// theme's background. Unknown languages are shown unhighlighted.
+pub fn highlight(code: &str, lang: &str, theme: &str, width: usize) -> String {
+    let syntax = syntaxes()
+        .find_syntax_by_token(lang)
+        .unwrap_or_else(|| syntaxes().find_syntax_plain_text());
+    let theme = &themes().themes[theme];
+    let Color { r, g, b, .. } =
+        theme.settings.background.unwrap_or(Color::BLACK);
+    let background = format!("\x1b[48;2;{};{};{}m", r, g, b);
+
+    let code = code.replace('\t', "    ");
+    let block_width = code
+        .lines()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0)
+        .min(width.saturating_sub(2));
+
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let mut out = String::new();
+    for line in LinesWithEndings::from(&code) {
+        let ranges = match highlighter.highlight_line(line, syntaxes()) {
+            Ok(ranges) => ranges,
+            Err(_) => return code,
+        };
+        let ranges: Vec<_> = ranges
+            .into_iter()
+            .map(|(style, text)| (style, text.trim_end_matches('\n')))
+            .collect();
+        let padding = block_width
+            .saturating_sub(line.trim_end_matches('\n').chars().count());
+        out.push_str(&background);
+        out.push(' ');
+        out.push_str(&as_24_bit_terminal_escaped(&ranges, true));
+        out.push_str(&background);
+        out.push_str(&" ".repeat(padding + 1));
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
@@ -0,0 +1,112 @@
+use crate::repl_prompt::ReplPrompt;
+use crate::shutdown::Signals;
+use reedline::{
+    EditMode, PromptEditMode, Reedline, ReedlineEvent, ReedlineRawEvent, Signal,
+};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use termimad::crossterm::terminal;
+use tokio::sync::oneshot;
+use tokio::time;
+
+/// An edit mode that notes when the terminal last sent anything, so that
+/// typing keeps the REPL from timing out.
+struct Watched<M> {
+    mode: M,
+    active: Arc<Mutex<Instant>>,
+}
+
+impl<M: EditMode> EditMode for Watched<M> {
+    fn parse_event(&mut self, event: ReedlineRawEvent) -> ReedlineEvent {
+        *self.active.lock().unwrap() = Instant::now();
+        self.mode.parse_event(event)
+    }
+
+    fn edit_mode(&self) -> PromptEditMode {
+        self.mode.edit_mode()
+    }
+}
+
+/// The REPL's line editor, which with --idle-timeout gives up waiting for
+/// a line once nothing has been typed for that long.
+pub struct IdleLineEditor {
+    /// The editor, which is left reading on its own thread when the wait
+    /// times out.
+    editor: Option<Reedline>,
+    timeout: Option<Duration>,
+    /// When a key was last pressed, or the prompt last shown.
+    active: Arc<Mutex<Instant>>,
+}
+
+impl IdleLineEditor {
+    pub fn new(
+        editor: Reedline,
+        mode: impl EditMode + 'static,
+        timeout: Option<Duration>,
+    ) -> Self {
+        let active = Arc::new(Mutex::new(Instant::now()));
+        let mode = Watched {
+            mode,
+            active: active.clone(),
+        };
+        IdleLineEditor {
+            editor: Some(editor.with_edit_mode(Box::new(mode))),
+            timeout,
+            active,
+        }
+    }
+
+    /// Reads a line, or returns None if nothing is typed for as long as
+    /// the timeout. Reedline only returns once a line is entered, so it
+    /// reads on a thread of its own that is raced against the timeout,
+    /// which starts again with each key, and against `signals`. Once it has
+    /// lost, the REPL is to exit, and the terminal is taken out of raw mode
+    /// for it; a signal is returned as the error to exit with.
+    pub async fn read_line(
+        &mut self,
+        prompt: &ReplPrompt,
+        signals: &mut Signals,
+    ) -> Result<Option<Signal>, Box<dyn Error>> {
+        let Some(mut editor) = self.editor.take() else {
+            return Ok(None);
+        };
+        let (sender, mut receiver) = oneshot::channel();
+        let prompt = prompt.clone();
+        thread::spawn(move || {
+            let signal = editor.read_line(&prompt);
+            let _ = sender.send((editor, signal));
+        });
+        *self.active.lock().unwrap() = Instant::now();
+        loop {
+            let deadline = self.timeout.map(|timeout| {
+                time::Instant::from_std(*self.active.lock().unwrap() + timeout)
+            });
+            let idle =
+                time::sleep_until(deadline.unwrap_or_else(time::Instant::now));
+            tokio::select! {
+                read = &mut receiver => {
+                    let (editor, signal) = read
+                        .map_err(|_| "the line editor stopped")?;
+                    self.editor = Some(editor);
+                    return Ok(Some(signal?));
+                }
+                _ = idle, if deadline.is_some() => {
+                    let active = *self.active.lock().unwrap();
+                    if active.elapsed() < self.timeout.unwrap_or_default() {
+                        continue;
+                    }
+                    let _ = terminal::disable_raw_mode();
+                    println!();
+                    return Ok(None);
+                }
+                interrupted = signals.next() => {
+                    let _ = terminal::disable_raw_mode();
+                    println!();
+                    return Err(Box::new(interrupted));
+                }
+            }
+        }
+    }
+}
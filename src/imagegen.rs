@@ -0,0 +1,132 @@
+use crate::spinner::Spinner;
+use chrono::Local;
+use clap::{Args, ValueHint};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use termgpt::api::{ChatGptClient, ImageRequest, DEFAULT_IMAGE_MODEL};
+use termimad::crossterm::tty::IsTty;
+
+#[derive(Args)]
+pub struct ImageArgs {
+    /// What the image should show
+    prompt: String,
+
+    /// Write the image here instead of a file named for the time; with
+    /// several images, each gets a number before the extension
+    #[arg(short, long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    output: Option<String>,
+
+    /// Size of the image, such as 1024x1024 or 1536x1024
+    #[arg(long, value_name = "SIZE")]
+    size: Option<String>,
+
+    /// Number of images to generate
+    #[arg(short, long, value_name = "N", default_value_t = 1)]
+    n: u32,
+
+    /// Print the prompt as the model rewrote it, when it does
+    #[arg(long)]
+    show_revised_prompt: bool,
+}
+
+/// The extension for an image, from its contents.
+fn extension(data: &[u8]) -> &'static str {
+    image::guess_format(data)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("png")
+}
+
+/// Where the `index`th of `count` images goes. A path without an extension
+/// gets the image's own.
+fn output_path(
+    output: Option<&str>,
+    index: usize,
+    count: usize,
+    data: &[u8],
+) -> PathBuf {
+    let path = match output {
+        Some(output) => PathBuf::from(output),
+        None => PathBuf::from(format!(
+            "image-{}",
+            Local::now().format("%Y%m%d-%H%M%S")
+        )),
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().into_owned())
+        .unwrap_or_else(|| extension(data).to_string());
+    let name = if count > 1 {
+        format!("{}-{}.{}", stem, index + 1, extension)
+    } else {
+        format!("{}.{}", stem, extension)
+    };
+    path.with_file_name(name)
+}
+
+/// Generates images of the prompt and writes each to a file, printing
+/// where. `model` is the one given with --model, as a chat model from the
+/// config file wouldn't make images.
+#[tokio::main]
+pub async fn run_image(
+    client: &ChatGptClient,
+    model: Option<&str>,
+    args: &ImageArgs,
+) -> Result<(), Box<dyn Error>> {
+    let model = model.unwrap_or(DEFAULT_IMAGE_MODEL);
+    let request = ImageRequest {
+        model,
+        prompt: &args.prompt,
+        size: args.size.as_deref(),
+        n: (args.n > 1).then_some(args.n),
+        response_format: model.starts_with("dall-e").then_some("b64_json"),
+    };
+    let spinner = io::stdout().is_tty().then(Spinner::start);
+    let result = client.generate_images(&request).await;
+    drop(spinner);
+    let images = result?;
+    for (index, image) in images.iter().enumerate() {
+        let path = output_path(
+            args.output.as_deref(),
+            index,
+            images.len(),
+            &image.data,
+        );
+        fs::write(&path, &image.data).map_err(|e| {
+            format!("could not write {}: {}", path.display(), e)
+        })?;
+        println!("{}", path.display());
+        if args.show_revised_prompt {
+            if let Some(prompt) = &image.revised_prompt {
+                eprintln!("revised prompt: {}", prompt);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    #[test]
+    fn several_images_are_numbered() {
+        let first = output_path(Some("out/heron.png"), 0, 2, PNG);
+        let second = output_path(Some("out/heron.png"), 1, 2, PNG);
+        assert_eq!(first, PathBuf::from("out/heron-1.png"));
+        assert_eq!(second, PathBuf::from("out/heron-2.png"));
+    }
+
+    #[test]
+    fn paths_without_an_extension_get_the_image_format() {
+        let path = output_path(Some("heron"), 0, 1, PNG);
+        assert_eq!(path, PathBuf::from("heron.png"));
+        let jpeg = output_path(Some("heron"), 0, 1, b"\xff\xd8\xff\xe0");
+        assert_eq!(jpeg, PathBuf::from("heron.jpg"));
+    }
+}
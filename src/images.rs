@@ -0,0 +1,189 @@
+//! Images attached to user messages, for models that take them.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use image::{ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+/// The largest image file that can be attached, as the API's limit.
+pub const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Images with a longer side than this are scaled down before they are
+/// sent, as the API would scale them down anyway.
+pub const MAX_DIMENSION: u32 = 2048;
+
+/// An image attached to a message. Session files keep only where it was
+/// and a hash of it, so the image is read again when a session is
+/// resumed, and left out if it has since changed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ImageAttachment {
+    pub path: String,
+    pub sha256: String,
+    /// The image as sent, once it has been read.
+    #[serde(skip)]
+    pub data_url: Option<String>,
+}
+
+impl ImageAttachment {
+    /// Reads an image to attach, failing if it isn't a PNG, JPEG, GIF or
+    /// WebP image or is too large.
+    pub fn load(path: &str) -> Result<ImageAttachment, Box<dyn Error>> {
+        let absolute = Path::new(path)
+            .canonicalize()
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let bytes = read_image_file(&absolute)?;
+        Ok(ImageAttachment {
+            path: absolute.to_string_lossy().into_owned(),
+            sha256: sha256(&bytes),
+            data_url: Some(
+                data_url(&bytes).map_err(|e| format!("{}: {}", path, e))?,
+            ),
+        })
+    }
+
+    /// Reads the image again for a resumed session, failing if the file
+    /// is gone or no longer the image that was attached.
+    pub fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        let bytes = read_image_file(Path::new(&self.path))?;
+        if sha256(&bytes) != self.sha256 {
+            Err(format!("{} has changed since it was attached", self.path))?
+        }
+        self.data_url = Some(data_url(&bytes)?);
+        Ok(())
+    }
+
+    /// What a transcript shows in place of the image.
+    pub fn note(&self) -> String {
+        format!("[image: {}]", self.path)
+    }
+}
+
+fn read_image_file(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let size = fs::metadata(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .len();
+    if size > MAX_IMAGE_BYTES {
+        Err(format!(
+            "{} is {} MB, more than the {} MB that can be attached",
+            path.display(),
+            size / (1024 * 1024),
+            MAX_IMAGE_BYTES / (1024 * 1024)
+        ))?
+    }
+    Ok(fs::read(path)?)
+}
+
+pub(crate) fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+/// Encodes an image as a data URL, scaling it down first if it is larger
+/// than `MAX_DIMENSION`.
+fn data_url(bytes: &[u8]) -> Result<String, Box<dyn Error>> {
+    let format = image::guess_format(bytes)
+        .ok()
+        .filter(|format| {
+            matches!(
+                format,
+                ImageFormat::Png
+                    | ImageFormat::Jpeg
+                    | ImageFormat::Gif
+                    | ImageFormat::WebP
+            )
+        })
+        .ok_or("not a PNG, JPEG, GIF or WebP image")?;
+    let reader = ImageReader::with_format(Cursor::new(bytes), format);
+    let (width, height) = reader.into_dimensions()?;
+    let (format, bytes) = if width.max(height) > MAX_DIMENSION {
+        let image = image::load_from_memory_with_format(bytes, format)?
+            .thumbnail(MAX_DIMENSION, MAX_DIMENSION);
+        // Only PNG and JPEG are re-encoded, so anything else becomes PNG.
+        let format = match format {
+            ImageFormat::Jpeg => ImageFormat::Jpeg,
+            _ => ImageFormat::Png,
+        };
+        let mut scaled = Vec::new();
+        image.write_to(&mut Cursor::new(&mut scaled), format)?;
+        (format, scaled)
+    } else {
+        (format, bytes.to_vec())
+    };
+    Ok(format!(
+        "data:{};base64,{}",
+        format.to_mime_type(),
+        STANDARD.encode(bytes)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbImage::from_pixel(width, height, Rgb([200, 10, 10]));
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn decoded_dimensions(url: &str) -> (u32, u32) {
+        let encoded = url.split_once(',').unwrap().1;
+        let bytes = STANDARD.decode(encoded).unwrap();
+        let image = image::load_from_memory(&bytes).unwrap();
+        (image.width(), image.height())
+    }
+
+    #[test]
+    fn small_images_are_sent_as_they_are() {
+        let bytes = png(10, 20);
+        let url = data_url(&bytes).unwrap();
+        assert!(url.starts_with("data:image/png;base64,"));
+        assert_eq!(
+            url,
+            format!("data:image/png;base64,{}", STANDARD.encode(&bytes))
+        );
+    }
+
+    #[test]
+    fn large_images_are_scaled_down() {
+        let url = data_url(&png(4096, 1024)).unwrap();
+        assert_eq!(decoded_dimensions(&url), (2048, 512));
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        let err = data_url(b"%PDF-1.7").unwrap_err();
+        assert_eq!(err.to_string(), "not a PNG, JPEG, GIF or WebP image");
+    }
+
+    #[test]
+    fn changed_images_are_not_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shot.png");
+        fs::write(&path, png(4, 4)).unwrap();
+        let mut image = ImageAttachment::load(path.to_str().unwrap()).unwrap();
+        image.data_url = None;
+        image.reload().unwrap();
+        assert!(image.data_url.is_some());
+
+        fs::write(&path, png(5, 5)).unwrap();
+        let err = image.reload().unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("has changed since it was attached"));
+    }
+}
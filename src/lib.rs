@@ -0,0 +1,11 @@
+//! The chat, session and API code behind the termgpt command, for use by
+//! other programs.
+
+pub mod api;
+pub mod cache;
+pub mod images;
+pub mod listener;
+pub mod message;
+pub mod redact;
+pub mod session;
+pub mod trace;
@@ -0,0 +1,497 @@
+//! Listeners, which write out a conversation as it goes.
+
+use crate::message::{ChatGptMessage, Role};
+use crate::redact::Redactor;
+use chrono::Local;
+use clap::ValueEnum;
+use pulldown_cmark::{Event, Parser as MarkdownParser, Tag, TagEnd};
+use serde::Deserialize;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+/// Something told of each message added to a conversation, such as a file
+/// the conversation is written to.
+pub trait ChatMessageListener {
+    fn on_message(
+        &mut self,
+        message: &ChatGptMessage,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Receives each part of a streamed response as it arrives, before the
+    /// whole message is given to `on_message`.
+    fn on_chunk(&mut self, _text: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Told that `on_chunk` failed, after which the listener is given no
+    /// more of the message being streamed, so that what it has written
+    /// has no gaps. The whole message is still given to `on_message`.
+    fn on_stream_failed(&mut self) {}
+
+    /// Told that the conversation is waiting on the user, so that anything
+    /// held back, such as a sync, can be finished first.
+    fn on_idle(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// How many times in a row a listener may fail before it is dropped.
+const MAX_LISTENER_FAILURES: u32 = 3;
+
+struct RegisteredListener<'a> {
+    /// What the listener writes to, for error messages.
+    name: String,
+    listener: Box<dyn ChatMessageListener + 'a>,
+    failures: u32,
+    /// Whether it failed part of the message being streamed.
+    stream_failed: bool,
+}
+
+/// The listeners of a conversation. Unless `strict` is set, a listener that
+/// fails is reported and the rest carry on, so that a full disk under one
+/// output doesn't lose the message everywhere else; one that keeps failing
+/// is dropped.
+pub struct Listeners<'a> {
+    registered: Vec<RegisteredListener<'a>>,
+    /// Fail at the first listener error instead.
+    pub strict: bool,
+}
+
+impl Default for Listeners<'_> {
+    fn default() -> Self {
+        Listeners::new()
+    }
+}
+
+impl<'a> Listeners<'a> {
+    pub fn new() -> Listeners<'a> {
+        Listeners {
+            registered: Vec::new(),
+            strict: false,
+        }
+    }
+
+    /// Adds a listener, named for what it writes to.
+    pub fn register<L: ChatMessageListener + 'a>(
+        &mut self,
+        name: impl Into<String>,
+        listener: L,
+    ) {
+        self.registered.push(RegisteredListener {
+            name: name.into(),
+            listener: Box::new(listener),
+            failures: 0,
+            stream_failed: false,
+        });
+    }
+
+    /// Puts a listener in place of the one named `name`, or adds it if
+    /// there's none by that name, such as when a session file is switched.
+    pub fn replace<L: ChatMessageListener + 'a>(
+        &mut self,
+        name: &str,
+        new_name: impl Into<String>,
+        listener: L,
+    ) {
+        let entry = RegisteredListener {
+            name: new_name.into(),
+            listener: Box::new(listener),
+            failures: 0,
+            stream_failed: false,
+        };
+        match self.registered.iter_mut().find(|entry| entry.name == name) {
+            Some(registered) => *registered = entry,
+            None => self.registered.push(entry),
+        }
+    }
+
+    /// How many listeners there are, not counting any that were dropped.
+    pub fn len(&self) -> usize {
+        self.registered.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registered.is_empty()
+    }
+
+    /// Calls each listener in turn, as for a message added to the
+    /// conversation, which ends any message being streamed.
+    pub fn notify(
+        &mut self,
+        f: impl FnMut(
+            &mut (dyn ChatMessageListener + 'a),
+        ) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.notify_each(false, f)
+    }
+
+    /// Passes part of a streamed response to each listener still taking
+    /// the stream. The response isn't read any further until they all
+    /// have, so a slow listener holds it up rather than letting chunks
+    /// pile up in memory.
+    pub fn notify_chunk(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        self.notify_each(true, |listener| listener.on_chunk(text))
+    }
+
+    fn notify_each(
+        &mut self,
+        chunk: bool,
+        mut f: impl FnMut(
+            &mut (dyn ChatMessageListener + 'a),
+        ) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut i = 0;
+        while i < self.registered.len() {
+            let entry = &mut self.registered[i];
+            if chunk && entry.stream_failed {
+                i += 1;
+                continue;
+            }
+            match f(entry.listener.as_mut()) {
+                Ok(()) => entry.failures = 0,
+                Err(e) if self.strict => Err(format!("{}: {}", entry.name, e))?,
+                Err(e) => {
+                    eprintln!("termgpt: {}: {}", entry.name, e);
+                    entry.failures += 1;
+                    if entry.failures == MAX_LISTENER_FAILURES {
+                        eprintln!(
+                            "termgpt: {} failed {} times in a row; \
+                             no longer writing to it",
+                            entry.name, entry.failures
+                        );
+                        self.registered.remove(i);
+                        continue;
+                    }
+                    if chunk {
+                        entry.stream_failed = true;
+                        entry.listener.on_stream_failed();
+                    }
+                }
+            }
+            if !chunk {
+                self.registered[i].stream_failed = false;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Passes part of a streamed response on to the listeners, unless it is to
+/// be redacted, which can only be done to the whole message.
+pub fn stream_to_listeners(
+    listeners: &mut Listeners<'_>,
+    redactor: &Option<Redactor>,
+    text: &str,
+) -> Result<(), Box<dyn Error>> {
+    if redactor.is_none() {
+        listeners.notify_chunk(text)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn open_file_for_appending(filename: &str) -> io::Result<File> {
+    File::options().append(true).create(true).open(filename)
+}
+
+/// How messages are laid out in `--output` files.
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFileFormat {
+    /// Each message as it is, followed by a blank line
+    #[default]
+    Plain,
+    /// Each message under a `### User` or `### Assistant` heading
+    Labelled,
+    /// Each message under a `## User` or `## Assistant` heading, as in
+    /// Markdown transcripts
+    Markdown,
+}
+
+/// Appends each message to a file, writing streamed responses as they
+/// arrive.
+pub struct OutputAppendListener {
+    writer: BufWriter<File>,
+    format: OutputFileFormat,
+    /// Whether headings include the time of the message.
+    timestamps: bool,
+    plain: bool,
+    /// Whether the message being streamed has been partly written.
+    streaming: bool,
+}
+
+impl OutputAppendListener {
+    /// With `plain` set, markdown is stripped from each message.
+    pub fn new(
+        filename: &str,
+        format: OutputFileFormat,
+        timestamps: bool,
+        plain: bool,
+    ) -> io::Result<OutputAppendListener> {
+        let writer = BufWriter::new(open_file_for_appending(filename)?);
+        Ok(OutputAppendListener {
+            writer,
+            format,
+            timestamps,
+            plain,
+            streaming: false,
+        })
+    }
+}
+
+impl OutputAppendListener {
+    fn write_heading(&mut self, role: &Role) -> io::Result<()> {
+        let level = match self.format {
+            OutputFileFormat::Plain => return Ok(()),
+            OutputFileFormat::Labelled => "###",
+            OutputFileFormat::Markdown => "##",
+        };
+        let title = role.title();
+        if self.timestamps {
+            let now = Local::now().format("%Y-%m-%d %H:%M:%S");
+            writeln!(self.writer, "{} {} ({})\n", level, title, now)
+        } else {
+            writeln!(self.writer, "{} {}\n", level, title)
+        }
+    }
+}
+
+impl ChatMessageListener for OutputAppendListener {
+    fn on_message(
+        &mut self,
+        message: &ChatGptMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.streaming {
+            writeln!(self.writer, "\n")?;
+            self.streaming = false;
+            self.writer.flush()?;
+            self.writer.get_ref().sync_data()?;
+            return Ok(());
+        }
+        self.write_heading(&message.role)?;
+        if self.plain {
+            writeln!(self.writer, "{}\n", strip_markdown(&message.content))?;
+        } else {
+            writeln!(self.writer, "{}\n", message.content)?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Writes streamed text straight to the file, so that long responses
+    /// appear as they're generated. Markdown can only be stripped from a
+    /// whole message, so plain output waits for it.
+    fn on_chunk(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        if !self.plain {
+            if !self.streaming {
+                self.write_heading(&Role::Assistant)?;
+            }
+            self.writer.write_all(text.as_bytes())?;
+            self.writer.flush()?;
+            self.streaming = true;
+        }
+        Ok(())
+    }
+
+    /// Ends the part written, so that the whole message can follow it.
+    fn on_stream_failed(&mut self) {
+        if self.streaming {
+            let _ = writeln!(self.writer, "\n");
+            self.streaming = false;
+        }
+    }
+}
+
+/// Finishes off a message cut short by an interruption, so that the next
+/// run's output starts on a line of its own.
+impl Drop for OutputAppendListener {
+    fn drop(&mut self) {
+        if self.streaming {
+            let _ = writeln!(self.writer, "\n");
+        }
+        let _ = self.writer.flush();
+        let _ = self.writer.get_ref().sync_data();
+    }
+}
+
+fn end_block(text: &mut String) {
+    let trimmed = text.trim_end_matches('\n').len();
+    text.truncate(trimmed);
+    if !text.is_empty() {
+        text.push_str("\n\n");
+    }
+}
+
+/// Removes markdown syntax, keeping the text content and block structure.
+pub fn strip_markdown(markdown: &str) -> String {
+    let mut text = String::new();
+    for event in MarkdownParser::new(markdown) {
+        match event {
+            Event::Text(t) | Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Start(Tag::Item) => text.push_str("- "),
+            Event::End(TagEnd::Item) if !text.ends_with('\n') => {
+                text.push('\n')
+            }
+            Event::End(
+                TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::CodeBlock
+                | TagEnd::BlockQuote(_)
+                | TagEnd::List(_),
+            )
+            | Event::Rule => end_block(&mut text),
+            _ => {}
+        }
+    }
+    text.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::ChatMessages;
+    use crate::redact::RedactMode;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Records the content of each message it is given.
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl ChatMessageListener for Recorder {
+        fn on_message(
+            &mut self,
+            message: &ChatGptMessage,
+        ) -> Result<(), Box<dyn Error>> {
+            self.0.borrow_mut().push(message.content.clone());
+            Ok(())
+        }
+    }
+
+    /// Fails every time, counting how often it was called.
+    struct Failing(Rc<RefCell<u32>>);
+
+    impl ChatMessageListener for Failing {
+        fn on_message(
+            &mut self,
+            _message: &ChatGptMessage,
+        ) -> Result<(), Box<dyn Error>> {
+            *self.0.borrow_mut() += 1;
+            Err("disk full")?
+        }
+    }
+
+    /// Records chunks, failing the one given as `fail_at`.
+    struct ChunkRecorder {
+        chunks: Rc<RefCell<Vec<String>>>,
+        fail_at: Option<usize>,
+        calls: usize,
+    }
+
+    impl ChatMessageListener for ChunkRecorder {
+        fn on_message(
+            &mut self,
+            message: &ChatGptMessage,
+        ) -> Result<(), Box<dyn Error>> {
+            self.chunks.borrow_mut().push(message.content.clone());
+            Ok(())
+        }
+
+        fn on_chunk(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+            self.calls += 1;
+            if self.fail_at == Some(self.calls) {
+                Err("disk full")?
+            }
+            self.chunks.borrow_mut().push(text.to_string());
+            Ok(())
+        }
+    }
+
+    fn user(content: &str) -> ChatGptMessage {
+        ChatGptMessage::new(Role::User, content.to_string())
+    }
+
+    #[test]
+    fn every_listener_is_given_each_message() {
+        let first = Rc::new(RefCell::new(Vec::new()));
+        let second = Rc::new(RefCell::new(Vec::new()));
+        let mut chat = ChatMessages::new();
+        chat.register("first", Recorder(first.clone()));
+        chat.register("second", Recorder(second.clone()));
+        chat.push(user("one")).unwrap();
+        chat.push(user("two")).unwrap();
+        assert_eq!(*first.borrow(), ["one", "two"]);
+        assert_eq!(*second.borrow(), ["one", "two"]);
+    }
+
+    #[test]
+    fn failing_listener_is_dropped_after_repeated_failures() {
+        let calls = Rc::new(RefCell::new(0));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut chat = ChatMessages::new();
+        chat.register("failing", Failing(calls.clone()));
+        chat.register("recorder", Recorder(seen.clone()));
+        for i in 0..5 {
+            chat.push(user(&i.to_string())).unwrap();
+        }
+        assert_eq!(*calls.borrow(), MAX_LISTENER_FAILURES);
+        assert_eq!(chat.listeners.len(), 1);
+        assert_eq!(seen.borrow().len(), 5);
+        assert_eq!(chat.messages.len(), 5);
+    }
+
+    #[test]
+    fn listener_failing_a_chunk_misses_the_rest_of_the_stream() {
+        let failing = Rc::new(RefCell::new(Vec::new()));
+        let healthy = Rc::new(RefCell::new(Vec::new()));
+        let mut chat = ChatMessages::new();
+        chat.register(
+            "failing",
+            ChunkRecorder {
+                chunks: failing.clone(),
+                fail_at: Some(2),
+                calls: 0,
+            },
+        );
+        chat.register(
+            "healthy",
+            ChunkRecorder {
+                chunks: healthy.clone(),
+                fail_at: None,
+                calls: 0,
+            },
+        );
+        for chunk in ["a", "b", "c"] {
+            chat.listeners.notify_chunk(chunk).unwrap();
+        }
+        let reply = ChatGptMessage::new(Role::Assistant, "abc".into());
+        chat.push(reply.clone()).unwrap();
+        chat.listeners.notify_chunk("d").unwrap();
+        assert_eq!(*failing.borrow(), ["a", "abc", "d"]);
+        assert_eq!(*healthy.borrow(), ["a", "b", "c", "abc", "d"]);
+    }
+
+    #[test]
+    fn strict_listeners_fail_the_message() {
+        let mut chat = ChatMessages::new();
+        chat.listeners.strict = true;
+        chat.register("failing", Failing(Rc::new(RefCell::new(0))));
+        let error = chat.push(user("one")).unwrap_err();
+        assert_eq!(error.to_string(), "failing: disk full");
+        assert!(chat.messages.is_empty());
+    }
+
+    #[test]
+    fn listeners_are_given_the_redacted_copy() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut chat = ChatMessages::new();
+        chat.redact_with(Redactor::new(RedactMode::Persisted, &[]).unwrap());
+        chat.register("recorder", Recorder(seen.clone()));
+        chat.push(user("password = hunter2")).unwrap();
+        assert_eq!(*seen.borrow(), ["password = [REDACTED:password]"]);
+        assert_eq!(chat.messages[0].content, "password = hunter2");
+    }
+}
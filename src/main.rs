@@ -1,270 +1,3047 @@
-use clap::Parser;
-use reedline::{DefaultPrompt, DefaultPromptSegment::Empty, Reedline, Signal};
-use serde::{Deserialize, Serialize};
-use serde_jsonlines::{json_lines, JsonLinesWriter};
-use spinners::{Spinner, Spinners};
+use clap::{Parser, Subcommand, ValueEnum, ValueHint};
+use pulldown_cmark::{
+    CodeBlockKind, Event, Parser as MarkdownParser, Tag, TagEnd,
+};
+use reedline::{
+    default_emacs_keybindings, Emacs, KeyCode, KeyModifiers, Reedline,
+    ReedlineEvent, Signal,
+};
+use serde::Serialize;
+use serde_jsonlines::JsonLinesWriter;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::slice;
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
 use termimad::crossterm::style::Color;
 use termimad::crossterm::tty::IsTty;
-use termimad::MadSkin;
+use termimad::{terminal_size, FmtText};
 
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "lowercase")]
-enum Role {
-    Assistant,
-    System,
-    User,
+mod batch;
+mod bench;
+mod branch;
+mod clipboard;
+mod commit;
+mod compare;
+mod completions;
+mod config;
+mod db;
+mod embed;
+mod exec;
+mod facts;
+mod highlight;
+mod idle;
+mod imagegen;
+mod man;
+mod mcp;
+mod moderation;
+mod patch;
+mod prefill;
+mod prepend;
+mod preset;
+mod repl_prompt;
+mod run;
+mod shutdown;
+mod speak;
+mod spinner;
+mod stop_key;
+mod stream_stats;
+mod summary;
+mod template;
+mod theme;
+mod tokens;
+mod tools;
+mod totals;
+mod transcribe;
+mod transcript;
+mod watch;
+mod webhook;
+
+use bench::BenchOptions;
+use branch::{Branches, SessionFile};
+use clipboard::CodeCopier;
+use compare::Answer;
+use config::{resolve_model, Config};
+use db::{Database, SessionsCommand};
+use facts::Facts;
+use idle::IdleLineEditor;
+use moderation::{Flagged, FLAGGED_EXIT_CODE};
+use prefill::Prefilled;
+use preset::PresetsCommand;
+use repl_prompt::ReplPrompt;
+use shutdown::{Interrupted, Shutdown};
+use speak::Speaker;
+use spinner::Spinner;
+use stop_key::StopKey;
+use stream_stats::StreamStats;
+use summary::RollingSummary;
+use termgpt::api::{
+    estimate_cost, ChatApi, ChatGptClient, ChatGptParams, ChatGptResponse,
+    ChatGptUsage, RateLimit, RateLimits, ReasoningEffort, DEFAULT_BASE_URL,
+    DEFAULT_MODERATION_MODEL, DEFAULT_USER_AGENT,
+};
+use termgpt::cache::{self, ResponseCache};
+use termgpt::images::ImageAttachment;
+use termgpt::listener::{
+    stream_to_listeners, OutputAppendListener, OutputFileFormat,
+};
+use termgpt::message::{
+    parse_jsonl_messages, ChatGptMessage, ChatMessages, Role,
+};
+use termgpt::redact::{RedactMode, Redactor};
+use termgpt::session::{SessionAppendListener, SessionMetadata};
+use termgpt::trace::TraceFile;
+use theme::{Style, ThemeName};
+use tools::{Tool, Toolbox, MAX_TOOL_ROUNDS};
+use totals::SessionTotals;
+use transcript::{TranscriptFormat, TranscriptListener};
+use webhook::WebhookListener;
+
+/// Fields of the request that termgpt sets itself.
+const RESERVED_PARAMS: &[&str] =
+    &["model", "messages", "prompt", "stream", "stream_options"];
+
+/// Parses a KEY=VALUE request field, taking the value as JSON if it is
+/// valid JSON and as a string otherwise.
+fn parse_extra_param(arg: &str) -> Result<(String, serde_json::Value), String> {
+    let (key, value) = template::parse_var(arg)?;
+    if RESERVED_PARAMS.contains(&key.as_str()) {
+        return Err(format!(
+            "`{}` is set by termgpt and can't be overridden",
+            key
+        ));
+    }
+    let value = serde_json::from_str(&value)
+        .unwrap_or(serde_json::Value::String(value));
+    Ok((key, value))
 }
 
-#[derive(Serialize)]
-struct ChatGptRequest<'a> {
-    model: &'a str,
-    messages: &'a [ChatGptMessage],
+/// Explains a response cut off by a token limit, distinguishing
+/// --max-tokens from the model's context window.
+fn print_truncation_hint(
+    finish_reason: Option<&str>,
+    max_tokens: Option<u32>,
+    usage: &Option<ChatGptUsage>,
+) {
+    if finish_reason != Some("length") {
+        return;
+    }
+    let hit_max_tokens = match (max_tokens, usage) {
+        (Some(max), Some(usage)) => usage.completion_tokens >= max,
+        (max, _) => max.is_some(),
+    };
+    if hit_max_tokens {
+        eprintln!(
+            "termgpt: response truncated at --max-tokens {}; \
+             raise it for a longer answer",
+            max_tokens.unwrap_or_default()
+        );
+    } else {
+        eprintln!(
+            "termgpt: response truncated at the model's context limit; \
+             start a new session or shorten the conversation"
+        );
+    }
 }
 
-#[derive(Deserialize)]
-struct ChatGptResponse {
-    choices: Vec<ChatGptChoice>,
+fn print_rate_limits(limits: &Option<RateLimits>) {
+    let describe = |limit: &RateLimit, unit: &str| {
+        let mut text = limit.remaining.clone();
+        if let Some(total) = &limit.limit {
+            text.push_str(&format!("/{}", total));
+        }
+        text.push_str(&format!(" {} left", unit));
+        if let Some(reset) = &limit.reset {
+            text.push_str(&format!(" (resets in {})", reset));
+        }
+        text
+    };
+    match limits {
+        Some(limits) => {
+            let parts: Vec<String> = [
+                limits.requests.as_ref().map(|l| describe(l, "requests")),
+                limits.tokens.as_ref().map(|l| describe(l, "tokens")),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            eprintln!("rate limits: {}", parts.join(", "));
+        }
+        None => eprintln!("rate limits: not reported"),
+    }
 }
 
-#[derive(Deserialize)]
-struct ChatGptChoice {
-    message: ChatGptMessage,
+fn print_usage(model: &str, usage: &Option<ChatGptUsage>) {
+    match usage {
+        Some(usage) => {
+            eprint!(
+                "tokens: {} prompt + {} completion = {} total",
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.total_tokens
+            );
+            match estimate_cost(model, usage) {
+                Some(cost) => eprintln!(" (~${:.6})", cost),
+                None => eprintln!(),
+            }
+        }
+        None => eprintln!("tokens: usage not reported"),
+    }
 }
 
-#[derive(Deserialize, Serialize)]
-struct ChatGptMessage {
-    role: Role,
-    content: String,
+/// Reads few-shot examples, which must alternate between user and
+/// assistant messages, starting with the user.
+fn read_examples(
+    filename: &str,
+) -> Result<Vec<ChatGptMessage>, Box<dyn Error>> {
+    let messages = parse_jsonl_messages(&fs::read_to_string(filename)?)
+        .map_err(|e| format!("invalid examples file {}: {}", filename, e))?;
+    for (index, message) in messages.iter().enumerate() {
+        let (expected, name) = if index % 2 == 0 {
+            (Role::User, "user")
+        } else {
+            (Role::Assistant, "assistant")
+        };
+        if message.role != expected {
+            return Err(format!(
+                "invalid examples file {}: message {} should be a {} message",
+                filename,
+                index + 1,
+                name
+            )
+            .into());
+        }
+    }
+    if messages.len() % 2 != 0 {
+        return Err(format!(
+            "invalid examples file {}: last example has no assistant reply",
+            filename
+        )
+        .into());
+    }
+    Ok(messages)
 }
 
-async fn get_chatgpt_response(
-    api_key: &str,
-    model: &str,
-    messages: &[ChatGptMessage],
-) -> Result<ChatGptResponse, Box<dyn Error>> {
-    let client = reqwest::Client::new();
+/// The language and contents of each fenced code block in some markdown,
+/// without the fences. A fence left unterminated runs to the end of the
+/// text.
+fn fenced_code_blocks(markdown: &str) -> Vec<(String, String)> {
+    let mut blocks = Vec::new();
+    let mut block = None;
+    for event in MarkdownParser::new(markdown) {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let lang = info.split_whitespace().next().unwrap_or("");
+                block = Some((lang.to_string(), String::new()))
+            }
+            Event::Text(t) => {
+                if let Some((_, code)) = &mut block {
+                    code.push_str(&t);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => blocks.extend(block.take()),
+            _ => {}
+        }
+    }
+    blocks
+}
+
+/// The contents of each fenced code block in some markdown.
+fn code_blocks(markdown: &str) -> Vec<String> {
+    fenced_code_blocks(markdown)
+        .into_iter()
+        .map(|(_, code)| code)
+        .collect()
+}
+
+/// Picks the code printed by `--code`: the Nth block, counting from 1, or
+/// all of them for 0.
+fn select_code(markdown: &str, n: usize) -> Result<String, String> {
+    let mut blocks = code_blocks(markdown);
+    match n {
+        _ if blocks.is_empty() => Err("the response has no code blocks".into()),
+        0 => Ok(blocks.join("\n")),
+        n if n <= blocks.len() => Ok(blocks.swap_remove(n - 1)),
+        n => Err(format!(
+            "the response has {} code blocks, not {}",
+            blocks.len(),
+            n
+        )),
+    }
+}
 
-    let response: ChatGptResponse = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&ChatGptRequest { model, messages })
-        .send()
-        .await?
-        .json()
-        .await?;
+/// Below this many columns termimad output is garbled, so text is printed
+/// unrendered instead.
+const MIN_RENDER_WIDTH: u16 = 20;
 
-    Ok(response)
+/// Rendered lines are kept to a readable length on wide terminals.
+const DEFAULT_MAX_WIDTH: usize = 100;
+
+/// Renders markdown to the width of the terminal at the time of the call,
+/// so that resizes between responses are respected. Top-level fenced code
+/// blocks are cut out and syntax highlighted, with termimad laying out the
+/// text between them.
+fn render_markdown(style: &Style, max_width: usize, text: &str) -> String {
+    let (width, _) = terminal_size();
+    if width < MIN_RENDER_WIDTH {
+        static WARNING: Once = Once::new();
+        WARNING.call_once(|| {
+            eprintln!(
+                "termgpt: terminal narrower than {} columns; \
+                 showing responses as plain text",
+                MIN_RENDER_WIDTH
+            )
+        });
+        return text.to_string();
+    }
+    let width = match max_width {
+        0 => width as usize,
+        max => max.min(width as usize),
+    };
+    let layout = |markdown: &str| {
+        if markdown.trim().is_empty() {
+            String::new()
+        } else {
+            FmtText::from(&style.skin, markdown, Some(width)).to_string()
+        }
+    };
+
+    let mut out = String::new();
+    let mut depth = 0;
+    let mut laid_out = 0;
+    let mut code = None;
+    for (event, range) in MarkdownParser::new(text).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(lang)))
+                if depth == 0 =>
+            {
+                out.push_str(&layout(&text[laid_out..range.start]));
+                code = Some((lang.to_string(), String::new()));
+                depth += 1;
+            }
+            Event::Text(t) => {
+                if let Some((_, body)) = &mut code {
+                    body.push_str(&t);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) if code.is_some() => {
+                let (lang, body) = code.take().unwrap();
+                let lang = lang.split_whitespace().next().unwrap_or("");
+                out.push_str(&highlight::highlight(
+                    &body,
+                    lang,
+                    &style.code_theme,
+                    width,
+                ));
+                out.push('\n');
+                laid_out = range.end;
+                depth -= 1;
+            }
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+    }
+    out.push_str(&layout(&text[laid_out..]));
+    out.truncate(out.trim_end_matches('\n').len());
+    out.push('\n');
+    out
 }
 
-trait ChatMessageListener {
-    fn on_message(&mut self, message: &ChatGptMessage) -> Result<(), Box<dyn Error>>;
+struct PromptWrapper {
+    prefix: String,
+    suffix: String,
 }
 
-struct ChatMessages<'a> {
-    messages: Vec<ChatGptMessage>,
-    listeners: Vec<Box<dyn ChatMessageListener + 'a>>,
+impl PromptWrapper {
+    fn wrap(&self, content: &str) -> String {
+        format!("{}{}{}", self.prefix, content, self.suffix)
+    }
 }
 
-fn read_session_messages(filename: &str) -> io::Result<Vec<ChatGptMessage>> {
-    let path = Path::new(filename);
-    if path.try_exists()? {
-        json_lines::<ChatGptMessage, _>(path)?.collect::<io::Result<Vec<_>>>()
-    } else {
-        Ok(Vec::new())
+/// Shows text through `$PAGER`, or `less -RFX`, which prints short text
+/// inline, and waits for it to exit.
+fn page_output(text: &str) -> io::Result<()> {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|p| !p.trim().is_empty())
+        .unwrap_or_else(|| "less -RFX".to_string());
+    let mut words = pager.split_whitespace();
+    let mut child = process::Command::new(words.next().unwrap())
+        .args(words)
+        .stdin(process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Quitting the pager early closes the pipe, which isn't an error.
+    match writeln!(stdin, "{}", text) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+        _ => {}
     }
+    drop(stdin);
+    child.wait()?;
+    Ok(())
 }
 
-impl<'a> ChatMessages<'a> {
-    fn new() -> ChatMessages<'a> {
-        ChatMessages {
-            messages: Vec::new(),
-            listeners: Vec::new(),
-        }
+/// The messages to send for a conversation, which with a rolling summary
+/// are the summary and the recent turns rather than the whole history.
+fn request_messages<'a>(
+    messages: &'a [ChatGptMessage],
+    summary: &Option<RollingSummary>,
+) -> Cow<'a, [ChatGptMessage]> {
+    match summary {
+        Some(summary) => Cow::Owned(summary.context(messages)),
+        None => Cow::Borrowed(messages),
     }
+}
 
-    fn from_file(filename: &str) -> io::Result<ChatMessages<'a>> {
-        Ok(ChatMessages {
-            messages: read_session_messages(filename)?,
-            listeners: Vec::new(),
-        })
+/// Roughly how many tokens some messages take, at four bytes a token plus a
+/// few for each message's framing.
+fn estimate_tokens<'a>(
+    messages: impl IntoIterator<Item = &'a ChatGptMessage>,
+) -> usize {
+    messages.into_iter().map(|m| m.content.len() / 4 + 4).sum()
+}
+
+/// With `--confirm-over`, asks before sending a request that looks larger
+/// than the limit, counting the --examples that the client sends with it.
+/// The question is read from the terminal rather than stdin when stdin is
+/// the input.
+fn confirm_send(
+    client: &ChatGptClient,
+    options: &RequestOptions,
+    messages: &[ChatGptMessage],
+    summary: &Option<RollingSummary>,
+    new_messages: &[ChatGptMessage],
+) -> Result<bool, Box<dyn Error>> {
+    let Some(limit) = options.confirm_over.filter(|_| !options.yes) else {
+        return Ok(true);
+    };
+    let context = request_messages(messages, summary);
+    let tokens = estimate_tokens(
+        client
+            .examples
+            .iter()
+            .chain(context.iter())
+            .chain(new_messages),
+    );
+    if tokens <= limit {
+        return Ok(true);
     }
+    let question = format!(
+        "This request is about {} tokens, over the limit of {}. Send it?",
+        tokens, limit
+    );
+    ask_terminal(&question)?.ok_or_else(|| {
+        format!(
+            "request of about {} tokens is over --confirm-over {}, \
+             and there is no terminal to ask on; use -y to send it",
+            tokens, limit
+        )
+        .into()
+    })
+}
 
-    fn register<L: ChatMessageListener + 'a>(&mut self, listener: L) {
-        self.listeners.push(Box::new(listener));
+/// Asks a yes or no question on the terminal, even when stdin is the
+/// input, or returns `None` if there is no terminal to ask on.
+fn ask_terminal(question: &str) -> io::Result<Option<bool>> {
+    let mut answer = String::new();
+    if io::stdin().is_tty() {
+        eprint!("{} [y/N]: ", question);
+        io::stdin().read_line(&mut answer)?;
+    } else {
+        let Ok(tty) = File::open("/dev/tty") else {
+            return Ok(None);
+        };
+        eprint!("{} [y/N]: ", question);
+        BufReader::new(tty).read_line(&mut answer)?;
     }
+    Ok(Some(matches!(answer.trim(), "y" | "Y" | "yes")))
+}
 
-    fn push(&mut self, message: ChatGptMessage) -> Result<(), Box<dyn Error>> {
-        for listener in self.listeners.iter_mut() {
-            listener.on_message(&message)?;
+/// Writes a response to the stdin of a shell command, as with `--pipe-to`,
+/// and waits for it to finish.
+fn pipe_response(command: &str, content: &str) -> Result<(), Box<dyn Error>> {
+    let (shell, _) = exec::user_shell();
+    let flag = if cfg!(windows) { "/C" } else { "-c" };
+    let mut child = process::Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run `{}`: {}", command, e))?;
+    // Commands may well exit without reading everything.
+    match child.stdin.take().unwrap().write_all(content.as_bytes()) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e)?,
+        _ => {}
+    }
+    let status = child.wait()?;
+    match status.code() {
+        _ if status.success() => Ok(()),
+        Some(code) => {
+            Err(format!("`{}` exited with status {}", command, code))?
         }
-        self.messages.push(message);
-        Ok(())
+        None => Err(format!("`{}` was killed by a signal", command))?,
     }
 }
 
-struct SessionAppendListener {
-    writer: JsonLinesWriter<File>,
+/// Handles `/pipe [COMMAND|off]`, which shows or changes the command that
+/// responses are piped to.
+fn set_pipe(pipe_to: &mut Option<String>, args: &str) {
+    match args {
+        "" => match pipe_to {
+            Some(command) => eprintln!("responses are piped to `{}`", command),
+            None => eprintln!("responses are printed"),
+        },
+        "off" => *pipe_to = None,
+        command => *pipe_to = Some(command.to_string()),
+    }
 }
 
-fn open_file_for_appending(filename: &str) -> io::Result<File>{
-    File::options().write(true).append(true).create(true).open(filename)
+/// Handles `/prefill [TEXT]`, which starts the next response with the
+/// text, exactly as given after the space, or without it cancels that.
+fn set_prefill(prefill: &mut Option<String>, line: &str) {
+    match line
+        .strip_prefix("/prefill ")
+        .filter(|text| !text.is_empty())
+    {
+        Some(text) => *prefill = Some(text.to_string()),
+        None => {
+            *prefill = None;
+            eprintln!("the next response won't be prefilled");
+        }
+    }
 }
 
-impl SessionAppendListener {
-    fn new(filename: &str) -> io::Result<SessionAppendListener> {
-        let writer = JsonLinesWriter::new(open_file_for_appending(filename)?);
-        Ok(SessionAppendListener { writer })
-    }
+fn is_exit_command(line: &str) -> bool {
+    matches!(line.trim(), "/exit" | "/quit")
+}
+
+fn confirm_overwrite(path: &Path) -> io::Result<bool> {
+    print!("{} exists; overwrite? [y/N]: ", path.display());
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
 }
 
-impl ChatMessageListener for SessionAppendListener {
-    fn on_message(&mut self, message: &ChatGptMessage) -> Result<(), Box<dyn Error>> {
-        self.writer.write(&message)?;
-        self.writer.flush()?;
-        Ok(())
+/// Handles `/history`, which lists what would be sent with the next
+/// request, one message to a line.
+fn print_history(context: &[ChatGptMessage]) {
+    let (columns, _) = terminal_size();
+    let columns = if columns > 0 { columns as usize } else { 80 };
+    // Room for the number and role columns.
+    let preview_chars = columns.saturating_sub(20).max(20);
+    for message in context {
+        let id = message.id.map_or("-".to_string(), |id| id.to_string());
+        let role = message.role.name();
+        let mut lines =
+            message.content.lines().filter(|l| !l.trim().is_empty());
+        let first = lines.next().unwrap_or("").trim();
+        let mut preview: String = first.chars().take(preview_chars).collect();
+        let more = lines.count();
+        if first.chars().count() > preview_chars || more > 0 {
+            preview.push('…');
+        }
+        for image in &message.images {
+            preview.push(' ');
+            preview.push_str(&image.note());
+        }
+        println!("{:>4}  {:<9}  {}", id, role, preview);
     }
+    println!(
+        "{} messages, ~{} tokens",
+        context.len(),
+        estimate_tokens(context)
+    );
 }
 
-struct OutputAppendListener {
-    writer: BufWriter<File>,
+/// Handles `/save-last [--code] [-y] FILE`, which writes the last
+/// response, or just its code blocks, to a file.
+fn save_last(
+    messages: &[ChatGptMessage],
+    args: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut code = false;
+    let mut overwrite = false;
+    let mut filename = None;
+    for arg in args.split_whitespace() {
+        match arg {
+            "-c" | "--code" => code = true,
+            "-y" | "--yes" => overwrite = true,
+            _ if filename.is_none() => filename = Some(arg),
+            _ => Err("usage: /save-last [--code] [-y] FILE")?,
+        }
+    }
+    let path =
+        Path::new(filename.ok_or("usage: /save-last [--code] [-y] FILE")?);
+    let last = messages
+        .iter()
+        .rev()
+        .find(|m| m.role == Role::Assistant)
+        .ok_or("there is no response to save yet")?;
+    let content = if code {
+        let blocks = code_blocks(&last.content);
+        if blocks.is_empty() {
+            Err("the last response has no code blocks")?
+        }
+        blocks.join("\n")
+    } else {
+        format!("{}\n", last.content)
+    };
+    if path.try_exists()? && !overwrite && !confirm_overwrite(path)? {
+        return Ok(());
+    }
+    fs::write(path, content)?;
+    eprintln!("Saved to {}", path.display());
+    Ok(())
 }
 
-impl OutputAppendListener {
-    fn new(filename: &str) -> io::Result<OutputAppendListener> {
-        let writer = BufWriter::new(open_file_for_appending(filename)?);
-        Ok(OutputAppendListener { writer })
+/// What the copy key sends in place of a line, which can't be typed.
+const COPY_CODE_COMMAND: &str = "\0copy-code";
+
+/// The default key that copies a code block from the last response.
+const DEFAULT_COPY_KEY: &str = "ctrl-y";
+
+/// Parses a key such as `ctrl-y` or `alt-c` into reedline's terms.
+fn parse_key(key: &str) -> Result<(KeyModifiers, KeyCode), String> {
+    let invalid = || format!("invalid key `{}`", key);
+    let mut parts: Vec<&str> = key.split(['-', '+']).collect();
+    let code = match parts.pop().ok_or_else(invalid)? {
+        k if k.eq_ignore_ascii_case("tab") => KeyCode::Tab,
+        k if k.len() > 1 && k.starts_with(['f', 'F']) => {
+            KeyCode::F(k[1..].parse().map_err(|_| invalid())?)
+        }
+        k => match k.chars().collect::<Vec<_>>()[..] {
+            [c] => KeyCode::Char(c.to_ascii_lowercase()),
+            _ => Err(invalid())?,
+        },
+    };
+    let mut modifiers = KeyModifiers::NONE;
+    for part in parts {
+        modifiers |= match part.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => KeyModifiers::CONTROL,
+            "alt" | "meta" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => Err(invalid())?,
+        };
     }
+    Ok((modifiers, code))
 }
 
-impl ChatMessageListener for OutputAppendListener {
-    fn on_message(&mut self, message: &ChatGptMessage) -> Result<(), Box<dyn Error>> {
-        writeln!(self.writer, "{}\n", message.content)?;
-        self.writer.flush()?;
-        Ok(())
+/// The REPL's line editor. `copy_key` copies a code block from the last
+/// response. With `$VISUAL` or `$EDITOR` set, Ctrl-X opens the line in the
+/// editor and sends it once the editor exits, which is as close to
+/// readline's Ctrl-X Ctrl-E as single-key bindings can get, and Ctrl-O
+/// opens it without sending. Its edit mode is returned beside it rather
+/// than set, so that the REPL can watch the keys it is sent.
+fn line_editor(copy_key: (KeyModifiers, KeyCode)) -> (Reedline, Emacs) {
+    let mut keybindings = default_emacs_keybindings();
+    let (modifiers, code) = copy_key;
+    keybindings.add_binding(
+        modifiers,
+        code,
+        ReedlineEvent::ExecuteHostCommand(COPY_CODE_COMMAND.into()),
+    );
+
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .ok()
+        .filter(|editor| !editor.trim().is_empty());
+    let mut line_editor = Reedline::create();
+    if let Some(editor) = editor {
+        keybindings.add_binding(
+            KeyModifiers::CONTROL,
+            KeyCode::Char('x'),
+            ReedlineEvent::Multiple(vec![
+                ReedlineEvent::OpenEditor,
+                ReedlineEvent::Enter,
+            ]),
+        );
+        line_editor = line_editor.with_buffer_editor(editor, "md".into());
     }
+    (line_editor, Emacs::new(keybindings))
 }
 
+/// Adds a response to the conversation, updating any rolling summary.
+async fn keep_response(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    messages: &mut ChatMessages<'_>,
+    summary: &mut Option<RollingSummary>,
+    message: ChatGptMessage,
+) -> Result<(), Box<dyn Error>> {
+    messages.push(message)?;
+    if let Some(summary) = summary {
+        let client = client.without_examples();
+        summary.update(&client, params, &messages.messages).await?;
+    }
+    Ok(())
+}
 
-fn termimad_skin() -> MadSkin {
-    let mut skin = MadSkin::default_dark();
-    skin.paragraph.set_fg(Color::AnsiValue(249));
-    skin
+/// Makes the tool calls a response asks for and sends back their results,
+/// for as long as the model keeps calling tools, returning the response
+/// that at last answers with the usage of every round added up. The calls
+/// and results join the conversation.
+async fn answer_tool_calls(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    options: &RequestOptions,
+    messages: &mut ChatMessages<'_>,
+    summary: &Option<RollingSummary>,
+    shutdown: &Shutdown,
+    mut resp: ChatGptResponse,
+) -> Result<ChatGptResponse, Box<dyn Error>> {
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let last = resp.choices.len().checked_sub(1);
+        let kept = options.keep_choice.min(last.ok_or("no choices returned")?);
+        if resp.choices[kept].message.tool_calls.is_empty() {
+            return Ok(resp);
+        }
+        let request = resp.choices.swap_remove(kept).message;
+        let results: Vec<ChatGptMessage> = request
+            .tool_calls
+            .iter()
+            .map(|call| options.tools.call(call))
+            .collect();
+        messages.push(request)?;
+        for result in results {
+            messages.push(result)?;
+        }
+        let context = options
+            .facts
+            .apply(request_messages(&messages.messages, summary));
+        let next = client.get_chatgpt_response(params, &context);
+        let usage = resp.usage.take();
+        resp = shutdown.interruptible(next).await?;
+        resp.usage = usage.zip(resp.usage).map(|(a, b)| ChatGptUsage {
+            prompt_tokens: a.prompt_tokens + b.prompt_tokens,
+            completion_tokens: a.completion_tokens + b.completion_tokens,
+            total_tokens: a.total_tokens + b.total_tokens,
+        });
+    }
+    Err(format!(
+        "the model was still calling tools after {} rounds",
+        MAX_TOOL_ROUNDS
+    ))?
 }
 
 #[tokio::main]
 async fn repl_loop(
-    api_key: &str,
-    model: &str,
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    options: &RequestOptions,
+    wrapper: &PromptWrapper,
     messages: &mut ChatMessages,
+    summary: &mut Option<RollingSummary>,
+    mut images: Vec<ImageAttachment>,
 ) -> Result<(), Box<dyn Error>> {
-    let mut line_editor = Reedline::create();
-    let prompt = DefaultPrompt::new(Empty, Empty);
+    let shutdown = Shutdown::listen();
+    // A signal at the prompt, or between requests, makes the REPL return
+    // rather than exit from under the listeners.
+    let mut signals = shutdown.subscribe();
+    let (editor, keys) = line_editor(options.copy_key);
+    let mut line_editor =
+        IdleLineEditor::new(editor, keys, options.idle_timeout);
+    let mut copier = None;
+    let mut pipe_to = options.pipe_to.clone();
+    let mut branches = options.session.as_ref().map(Branches::new);
+    // Alternative responses from --count, and which is to be kept.
+    let mut pending: Option<(Vec<ChatGptMessage>, usize)> = None;
+    // The start of the next response, from --prefill or /prefill.
+    let mut prefill = options.prefill.clone();
+    let mut totals = SessionTotals::default();
+    let mut prompt =
+        ReplPrompt::new(&options.prompt_indicator, options.prompt_color);
+    prompt.model = params.model.clone();
 
-    let term_skin = termimad_skin();
+    // stdin can be a terminal while stdout is redirected, in which case the
+    // spinner and markdown styling would end up in the output as escapes.
+    let stdout_tty = io::stdout().is_tty();
+    let styled = options.color.enabled(stdout_tty);
+    let render = styled && !options.plain;
+    let label = match options.assistant_label.as_str() {
+        "" => String::new(),
+        label => format!("{} ", label),
+    };
 
     loop {
-        let sig = line_editor.read_line(&prompt)?;
+        prompt.turn = 1 + messages
+            .messages
+            .iter()
+            .filter(|m| m.role == Role::User)
+            .count();
+        messages.listeners.notify(|listener| listener.on_idle())?;
+        let Some(sig) = line_editor.read_line(&prompt, &mut signals).await?
+        else {
+            eprintln!(
+                "termgpt: nothing entered for {}s; exiting",
+                options.idle_timeout.unwrap_or_default().as_secs()
+            );
+            break;
+        };
+        // Anything but /pick settles on the response already chosen.
+        let picking = matches!(
+            &sig,
+            Signal::Success(content) if content.trim_start().starts_with("/pick")
+        );
+        if let Some((mut choices, kept)) = pending.take_if(|_| !picking) {
+            let mesg = choices.swap_remove(kept);
+            keep_response(client, params, messages, summary, mesg).await?;
+        }
         match sig {
+            Signal::Success(content) if is_exit_command(&content) => {
+                break;
+            }
+            Signal::Success(content) if content == COPY_CODE_COMMAND => {
+                let copier = copier.get_or_insert_with(CodeCopier::new);
+                match copier.copy_next(&messages.messages) {
+                    Ok(status) => eprintln!("{}", status),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Signal::Success(content)
+                if content.trim() == "/pipe"
+                    || content.trim_start().starts_with("/pipe ") =>
+            {
+                set_pipe(&mut pipe_to, content.trim()["/pipe".len()..].trim());
+            }
+            Signal::Success(content)
+                if content.trim_start().starts_with("/prefill") =>
+            {
+                set_prefill(&mut prefill, content.trim_start());
+            }
+            Signal::Success(content)
+                if content.trim_start().starts_with("/pick") =>
+            {
+                let arg = content.trim_start()["/pick".len()..].trim();
+                match (pending.take(), arg.parse::<usize>()) {
+                    (Some((mut choices, _)), Ok(n))
+                        if (1..=choices.len()).contains(&n) =>
+                    {
+                        let mesg = choices.swap_remove(n - 1);
+                        keep_response(client, params, messages, summary, mesg)
+                            .await?;
+                        eprintln!("kept choice {}", n);
+                    }
+                    (Some((choices, kept)), _) => {
+                        eprintln!(
+                            "usage: /pick N, from 1 to {}",
+                            choices.len()
+                        );
+                        pending = Some((choices, kept));
+                    }
+                    (None, _) => {
+                        eprintln!("there are no alternative responses to pick")
+                    }
+                }
+            }
+            Signal::Success(content)
+                if content.trim() == "/image"
+                    || content.trim_start().starts_with("/image ") =>
+            {
+                match content.trim()["/image".len()..].trim() {
+                    "" => eprintln!("usage: /image PATH"),
+                    path => match ImageAttachment::load(path) {
+                        Ok(image) => {
+                            eprintln!(
+                                "attached {} to the next message",
+                                image.path
+                            );
+                            images.push(image);
+                        }
+                        Err(e) => eprintln!("{}", e),
+                    },
+                }
+            }
+            Signal::Success(content) if content.trim() == "/speak" => {
+                let last = messages
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|m| m.role == Role::Assistant);
+                match last {
+                    Some(last) => {
+                        speak_response(client, options, &last.content).await
+                    }
+                    None => eprintln!("there is no response to read yet"),
+                }
+            }
+            Signal::Success(content)
+                if matches!(
+                    content.split_whitespace().next(),
+                    Some("/branch" | "/branches" | "/switch")
+                ) =>
+            {
+                let (command, name) = content
+                    .trim()
+                    .split_once(' ')
+                    .map_or((content.trim(), ""), |(c, n)| (c, n.trim()));
+                let result = match (branches.as_mut(), command) {
+                    (None, _) => Err("branching needs a session file from \
+                                      --session, without --rolling-summary"
+                        .into()),
+                    (Some(_), "/branch" | "/switch") if name.is_empty() => {
+                        Err(format!("usage: {} NAME", command).into())
+                    }
+                    (Some(branches), "/branches") => {
+                        branches.print();
+                        Ok(())
+                    }
+                    (Some(branches), "/branch") => {
+                        branches.branch(name, messages).map(|()| {
+                            eprintln!("on a new branch, {}", name);
+                        })
+                    }
+                    (Some(branches), _) => {
+                        branches.switch(name, messages).map(|()| {
+                            copier = None;
+                            eprintln!("back on {}", name);
+                        })
+                    }
+                };
+                if let Err(e) = result {
+                    eprintln!("{}", e);
+                }
+            }
+            Signal::Success(content) if content.trim() == "/tools" => {
+                options.tools.print();
+            }
+            Signal::Success(content) if content.trim() == "/history" => {
+                print_history(&request_messages(&messages.messages, summary));
+            }
+            Signal::Success(content) if content.trim() == "/run" => {
+                let result = run::run_last_block(
+                    &messages.messages,
+                    &options.interpreters,
+                    true,
+                    styled,
+                );
+                match result {
+                    Ok(Some(output)) => messages.push(output)?,
+                    Ok(None) => {}
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Signal::Success(content) if content.trim() == "/apply" => {
+                let last = messages
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|m| m.role == Role::Assistant);
+                let result = match last {
+                    Some(last) => {
+                        patch::apply_response(&last.content, false, styled)
+                    }
+                    None => Err("there is no response to apply yet".into()),
+                };
+                if let Err(e) = result {
+                    eprintln!("{}", e);
+                }
+            }
+            Signal::Success(content)
+                if content.trim_start().starts_with("/save-last") =>
+            {
+                let args = content.trim_start()["/save-last".len()..].trim();
+                if let Err(e) = save_last(&messages.messages, args) {
+                    eprintln!("{}", e);
+                }
+            }
+            Signal::Success(content)
+                if content.trim().is_empty() && !options.send_empty =>
+            {
+                continue;
+            }
             Signal::Success(content) => {
-                messages.push(ChatGptMessage {
-                    role: Role::User,
-                    content,
-                })?;
+                let mut message =
+                    ChatGptMessage::new(Role::User, wrapper.wrap(&content));
+                messages.redact_outgoing(slice::from_mut(&mut message));
+                let new_messages = slice::from_ref(&message);
+                if let Some(model) = &options.moderation {
+                    let check = moderation::check(client, model, new_messages);
+                    if let Err(flagged) = check.await {
+                        eprintln!("{}", flagged);
+                        continue;
+                    }
+                }
+                if !confirm_send(
+                    client,
+                    options,
+                    &messages.messages,
+                    summary,
+                    new_messages,
+                )? {
+                    continue;
+                }
+                message.images = mem::take(&mut images);
+                messages.push(message)?;
+                totals.turn();
 
-                let mut spinner = Spinner::new(Spinners::Dots2, String::new());
+                let context = options
+                    .facts
+                    .apply(request_messages(&messages.messages, summary));
+                if !options.compare.is_empty() {
+                    let spinner = (stdout_tty && styled).then(Spinner::start);
+                    let answers = compare::ask_each(
+                        client,
+                        params,
+                        &options.compare,
+                        &context,
+                    );
+                    let answers = shutdown
+                        .interruptible(async { Ok(answers.await) })
+                        .await;
+                    drop(spinner);
+                    let answers = answers?;
+                    if options.compare_stats {
+                        compare::print_stats(&answers);
+                    }
+                    for answer in &answers {
+                        let response = answer.response.as_ref().ok();
+                        let usage = response.and_then(|r| r.usage.as_ref());
+                        totals.add(&answer.model, usage);
+                    }
+                    let (mut choices, kept) =
+                        match compare::choices(&answers, options.keep_choice) {
+                            Ok(choices) => choices,
+                            Err(e) => {
+                                print_compare_failures(&answers);
+                                eprintln!("{}", e);
+                                continue;
+                            }
+                        };
+                    match &pipe_to {
+                        Some(command) => {
+                            let piped =
+                                pipe_response(command, &choices[kept].content);
+                            if let Err(e) = piped {
+                                eprintln!("{}", e);
+                            }
+                        }
+                        None => {
+                            let text =
+                                show_answers(options, &answers, kept, render);
+                            println!("{}{}", label, text);
+                        }
+                    }
+                    if choices.len() > 1 {
+                        eprintln!(
+                            "termgpt: keeping answer {}; /pick N keeps another",
+                            kept + 1
+                        );
+                        pending = Some((choices, kept));
+                    } else {
+                        let mesg = choices.pop().unwrap();
+                        keep_response(client, params, messages, summary, mesg)
+                            .await?;
+                    }
+                    continue;
+                }
+                let mut stats = StreamStats::new(options.stream_stats);
+                let mut labelled = false;
+                let mut spinner = (stdout_tty && styled).then(Spinner::start);
+                let prefill = prefill.take();
+                let api = Prefilled {
+                    api: client,
+                    prefill: prefill.as_deref(),
+                };
 
-                let resp =
-                    get_chatgpt_response(api_key, model, &messages.messages);
+                // What has been streamed, which is kept if it is stopped.
+                let mut streamed = String::new();
 
-                let mesg = resp.await?.choices.pop().unwrap().message;
+                let resp = if options.stream {
+                    let resp = api.stream_chatgpt_response(
+                        params,
+                        &context,
+                        options.show_usage,
+                        |text| {
+                            streamed.push_str(text);
+                            stream_to_listeners(
+                                &mut messages.listeners,
+                                &messages.redactor,
+                                text,
+                            )?;
+                            // A piped response isn't shown, so the
+                            // spinner keeps going until it is done.
+                            if pipe_to.is_some() {
+                                return Ok(());
+                            }
+                            if let Some(mut spinner) = spinner.take() {
+                                spinner.stop();
+                            }
+                            if !labelled {
+                                print!("{}", label);
+                                labelled = true;
+                            }
+                            stats.before_chunk();
+                            print!("{}", text);
+                            io::stdout().flush()?;
+                            stats.after_chunk();
+                            Ok(())
+                        },
+                    );
+                    let mut stop_key = StopKey::watch();
+                    let resp = shutdown.stoppable(resp, stop_key.pressed());
+                    let resp = resp.await?;
+                    drop(stop_key);
+                    match resp {
+                        Some(resp) => resp,
+                        None => {
+                            signals.clear();
+                            if let Some(mut spinner) = spinner.take() {
+                                spinner.stop();
+                            }
+                            let mark = if streamed.is_empty() {
+                                STOPPED_MARK.to_string()
+                            } else {
+                                format!("\n\n{}", STOPPED_MARK)
+                            };
+                            stream_to_listeners(
+                                &mut messages.listeners,
+                                &messages.redactor,
+                                &mark,
+                            )?;
+                            if pipe_to.is_none() {
+                                let label = if labelled { "" } else { &label };
+                                println!("{}{}", label, mark);
+                            }
+                            let prefill = prefill
+                                .filter(|p| streamed.starts_with(p.as_str()));
+                            streamed.push_str(&mark);
+                            let mesg = ChatGptMessage {
+                                prefill,
+                                ..ChatGptMessage::new(Role::Assistant, streamed)
+                            };
+                            keep_response(
+                                client, params, messages, summary, mesg,
+                            )
+                            .await?;
+                            continue;
+                        }
+                    }
+                } else {
+                    let resp = api.get_chatgpt_response(params, &context);
+                    shutdown.interruptible(resp).await?
+                };
+                // The spinner would spin over any question a tool asks.
+                let calls_tools = resp
+                    .choices
+                    .iter()
+                    .any(|c| !c.message.tool_calls.is_empty());
+                if let Some(mut spinner) = spinner.take_if(|_| calls_tools) {
+                    spinner.stop();
+                }
+                let mut resp = answer_tool_calls(
+                    client, params, options, messages, summary, &shutdown, resp,
+                )
+                .await?;
 
-                spinner.stop_with_message(format!(
-                    "{}",
-                    term_skin.term_text(&mesg.content)
-                ));
-                messages.push(mesg)?;
+                stats.finish();
+                let last = resp.choices.len().checked_sub(1);
+                let kept =
+                    options.keep_choice.min(last.ok_or("no choices returned")?);
+                let finish_reason = resp.choices[kept].finish_reason.take();
+                let mut choices: Vec<ChatGptMessage> =
+                    resp.choices.into_iter().map(|c| c.message).collect();
+
+                let shown: Vec<String> = choices
+                    .iter()
+                    .map(|mesg| {
+                        if render {
+                            render_markdown(
+                                &options.style,
+                                options.width,
+                                &mesg.content,
+                            )
+                        } else {
+                            mesg.content.clone()
+                        }
+                    })
+                    .collect();
+                let text = match &shown[..] {
+                    [text] => format!("{}{}", label, text),
+                    _ => format!("{}{}", label, join_choices(&shown, kept)),
+                };
+                // Streamed responses have already been printed, and are
+                // never paged.
+                let (_, height) = terminal_size();
+                let page = options.pager
+                    && height > 0
+                    && text.lines().count() >= height as usize;
+                match (&pipe_to, spinner) {
+                    (Some(command), spinner) => {
+                        if let Some(mut spinner) = spinner {
+                            spinner.stop();
+                        }
+                        if let Err(e) =
+                            pipe_response(command, &choices[kept].content)
+                        {
+                            eprintln!("{}", e);
+                        }
+                    }
+                    (None, Some(mut spinner)) if page => {
+                        spinner.stop();
+                        if page_output(&text).is_err() {
+                            println!("{}", text);
+                        }
+                    }
+                    (None, Some(mut spinner)) => {
+                        spinner.stop_with_message(text)
+                    }
+                    (None, None) if options.stream => println!(),
+                    (None, None) => println!("{}", text),
+                }
+                print_truncation_hint(
+                    finish_reason.as_deref(),
+                    params.token_limit(),
+                    &resp.usage,
+                );
+                if options.show_usage {
+                    print_usage(&params.model, &resp.usage);
+                }
+                totals.add(&params.model, resp.usage.as_ref());
+                if client.show_limits {
+                    print_rate_limits(&resp.rate_limits);
+                }
+                if options.speak {
+                    speak_response(client, options, &choices[kept].content)
+                        .await;
+                }
+                if choices.len() > 1 {
+                    eprintln!(
+                        "termgpt: keeping choice {}; /pick N keeps another",
+                        kept + 1
+                    );
+                    pending = Some((choices, kept));
+                } else {
+                    let mesg = choices.pop().unwrap();
+                    keep_response(client, params, messages, summary, mesg)
+                        .await?;
+                }
             }
             Signal::CtrlD | Signal::CtrlC => {
                 break;
             }
         }
     }
+    if !options.quiet && totals.turns() > 0 {
+        let session = options.session_path.as_deref();
+        eprintln!("termgpt: {}", totals.describe(session));
+    }
     Ok(())
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
-    /// OpenAI model to use
-    #[arg(short, long, default_value = "gpt-3.5-turbo")]
-    model: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Send a single prompt and print the response
+    #[arg(conflicts_with = "prompt_option")]
+    prompt: Option<String>,
+
+    /// Same as the PROMPT argument
+    #[arg(short, long = "prompt", value_name = "PROMPT")]
+    prompt_option: Option<String>,
+
+    /// OpenAI model to use [default: gpt-3.5-turbo]
+    #[arg(short, long, global = true)]
+    model: Option<String>,
 
     /// OpenAI API Key [default: $OPENAI_API_KEY]
-    #[arg(long)]
+    #[arg(long, global = true)]
     api_key: Option<String>,
 
+    /// Base URL of an OpenAI-compatible API [default: https://api.openai.com/v1]
+    #[arg(long, global = true, value_name = "URL")]
+    base_url: Option<String>,
+
+    /// User-Agent header sent with requests [default: termgpt/VERSION]
+    #[arg(long, global = true, value_name = "STR")]
+    user_agent: Option<String>,
+
+    /// Use the legacy completions endpoint with a flattened prompt
+    #[arg(long, global = true)]
+    completion_mode: bool,
+
+    /// Append every API request and response to a JSONL file
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath
+    )]
+    trace_file: Option<String>,
+
+    /// System prompt to start the conversation with
+    #[arg(long, global = true, value_name = "TEXT")]
+    system: Option<String>,
+
+    /// Read the system prompt from a file
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        conflicts_with = "system"
+    )]
+    system_file: Option<String>,
+
+    /// Send the system prompt but leave it out of the session file
+    #[arg(long, global = true)]
+    no_persist_system: bool,
+
+    /// Tell the model the current date, time and timezone
+    #[arg(long)]
+    context_datetime: bool,
+
+    /// Let the model call these built-in tools, such as read_file,shell
+    #[arg(long, value_enum, value_name = "TOOLS", value_delimiter = ',')]
+    tools: Vec<Tool>,
+
+    /// Don't start the MCP servers in the config file
+    #[arg(long)]
+    no_mcp: bool,
+
+    /// Tell the model the operating system, architecture and shell
+    #[arg(long)]
+    context_env: bool,
+
+    /// Prepend example exchanges from a JSONL file to every request
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath
+    )]
+    examples: Option<String>,
+
+    /// Set a {{NAME}} template variable in prompts
+    #[arg(
+        long = "var",
+        global = true,
+        value_name = "NAME=VALUE",
+        value_parser = template::parse_var
+    )]
+    vars: Vec<(String, String)>,
+
+    /// Leave undefined template variables as they are
+    #[arg(long, global = true)]
+    allow_missing_vars: bool,
+
     /// Persist session to a JSONL file
-    #[arg(short, long, value_name = "FILE")]
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath
+    )]
     session: Option<String>,
 
-    /// Output conversation to a plaintext file
-    #[arg(short, long, value_name = "FILE")]
-    output: Option<String>,
+    /// REPL prompt, where {model} and {turn} show the model and turn number
+    #[arg(long, value_name = "STR")]
+    prompt_indicator: Option<String>,
+
+    /// Label shown before each response in the REPL, such as "🤖"
+    #[arg(long, value_name = "STR")]
+    assistant_label: Option<String>,
+
+    /// Pipe each response to a shell command instead of printing it
+    #[arg(long, value_name = "COMMAND", conflicts_with = "format")]
+    pipe_to: Option<String>,
+
+    /// Read each response aloud once it has been shown
+    #[arg(long)]
+    speak: bool,
+
+    /// Voice to read responses in, such as alloy, nova or onyx
+    #[arg(long, value_name = "VOICE")]
+    voice: Option<String>,
+
+    /// How fast to read responses, from 0.25 to 4 [default: 1]
+    #[arg(long, value_name = "SPEED")]
+    speech_speed: Option<f32>,
+
+    /// Command that plays the audio file given to it, such as "mpv"
+    #[arg(long, value_name = "COMMAND")]
+    audio_player: Option<String>,
+
+    /// Also store the conversation in an SQLite database
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath
+    )]
+    db: Option<String>,
+
+    /// Sync the session file to disk at most every 200ms and before each
+    /// prompt: a crash loses less, but each sync slows writes down
+    #[arg(long)]
+    sync: bool,
+
+    /// Stop when a session, output or other file can't be written to
+    #[arg(long)]
+    strict_listeners: bool,
+
+    /// Refuse to send messages the moderation endpoint flags
+    #[arg(long)]
+    moderate: bool,
+
+    /// Moderation model for --moderate [default: omni-moderation-latest]
+    #[arg(long, value_name = "MODEL")]
+    moderation_model: Option<String>,
+
+    /// POST each message as JSON to this URL
+    #[arg(long, value_name = "URL", value_hint = ValueHint::Url)]
+    webhook: Option<String>,
+
+    /// Bearer token for --webhook, or set TERMGPT_WEBHOOK_TOKEN
+    #[arg(long, value_name = "TOKEN", requires = "webhook")]
+    webhook_token: Option<String>,
+
+    /// Replace secrets in messages before they are sent, or only when saved
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHERE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "both"
+    )]
+    redact: Option<RedactMode>,
+
+    /// Output conversation to a plaintext file; may be given more than once
+    #[arg(short, long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    output: Vec<String>,
+
+    /// Also append each message to a JSON Lines log that is never read back
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    log_jsonl: Option<String>,
+
+    /// Sampling temperature, between 0 and 2
+    #[arg(short, long, global = true)]
+    temperature: Option<f32>,
+
+    /// Maximum number of tokens to generate in each response
+    #[arg(long, global = true, value_name = "TOKENS")]
+    max_tokens: Option<u32>,
+
+    /// Ask for N alternative responses, which are not streamed
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    count: Option<u32>,
+
+    /// Which of the --count responses, or --compare models, to keep in
+    /// the conversation [default: 1]
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    keep_choice: Option<u32>,
+
+    /// Send the prompt to each of these models at once, such as
+    /// "gpt-4o,openai/gpt-4o-mini", and show every answer
+    #[arg(
+        long,
+        value_name = "MODELS",
+        value_delimiter = ',',
+        conflicts_with_all = ["count", "bench", "code"]
+    )]
+    compare: Vec<String>,
+
+    /// Print each --compare model's latency, tokens and cost to stderr
+    #[arg(long, requires = "compare")]
+    stats: bool,
+
+    /// How hard a reasoning model thinks before answering
+    #[arg(long, global = true, value_enum, value_name = "EFFORT")]
+    reasoning_effort: Option<ReasoningEffort>,
+
+    /// Add a field to each request, such as min_p=0.05; the value is JSON
+    #[arg(
+        long,
+        global = true,
+        value_name = "KEY=VALUE",
+        value_parser = parse_extra_param
+    )]
+    extra_param: Vec<(String, serde_json::Value)>,
+
+    /// Send a running summary of older messages instead of the full history
+    #[arg(long)]
+    rolling_summary: bool,
+
+    /// Number of recent exchanges sent verbatim with --rolling-summary
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 4,
+        requires = "rolling_summary"
+    )]
+    recent_turns: usize,
+
+    /// Use a named preset of system prompt, template and model settings
+    #[arg(long, global = true, value_name = "NAME")]
+    preset: Option<String>,
+
+    /// Ignore the config file
+    #[arg(long, global = true)]
+    no_config: bool,
+
+    /// Strip markdown from messages written to the output files
+    #[arg(long)]
+    output_plain: bool,
+
+    /// Layout of messages in the output files [default: plain]
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    output_format: Option<OutputFileFormat>,
+
+    /// Put the time of each message in the output file headings
+    #[arg(long)]
+    output_timestamps: bool,
+
+    /// Write the whole conversation to a file, rewritten as it grows
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    transcript: Option<String>,
+
+    /// Format of the transcript file
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = TranscriptFormat::Markdown,
+        requires = "transcript"
+    )]
+    transcript_format: TranscriptFormat,
+
+    /// Stream the response as it is generated
+    #[arg(long)]
+    stream: bool,
+
+    /// Show the count and rate of streamed tokens on stderr
+    #[arg(long)]
+    stream_stats: bool,
+
+    /// Print token usage to stderr after each response
+    #[arg(long)]
+    show_usage: bool,
+
+    /// Don't print the turns, tokens and cost when the REPL exits
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print the API's remaining rate limits to stderr after each request
+    #[arg(long, global = true)]
+    show_limits: bool,
+
+    /// Print responses exactly as received, without markdown rendering
+    #[arg(long)]
+    plain: bool,
+
+    /// Write the response to stdout byte for byte, without rendering it or
+    /// adding a newline
+    #[arg(
+        long,
+        conflicts_with_all = ["format", "code", "count", "compare", "pipe_to"]
+    )]
+    raw_output: bool,
+
+    /// Start the response with this text, for the model to carry on from
+    #[arg(long, value_name = "TEXT", conflicts_with = "compare")]
+    prefill: Option<String>,
+
+    /// When to style output with colors
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_name = "WHEN",
+        default_value_t = ColorChoice::Auto
+    )]
+    color: ColorChoice,
+
+    /// Wrap rendered responses at most this wide; 0 uses the full terminal
+    #[arg(long, value_name = "COLS")]
+    width: Option<usize>,
+
+    /// Don't page long responses in the REPL through $PAGER
+    #[arg(long)]
+    no_pager: bool,
+
+    /// Exit the REPL after this long at the prompt with no key pressed;
+    /// 0 never does
+    #[arg(long, value_name = "SECS")]
+    idle_timeout: Option<u64>,
+
+    /// Print diagnostics to stderr
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Built-in color theme for rendered markdown
+    #[arg(long, value_enum, value_name = "NAME")]
+    theme: Option<ThemeName>,
+
+    /// Format of the printed response outside the REPL
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// With --format jsonl, print only the new assistant message
+    #[arg(long)]
+    only_new: bool,
+
+    /// Print only the code blocks of the response, or just the Nth
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "0",
+        conflicts_with = "format"
+    )]
+    code: Option<usize>,
+
+    /// Offer to apply the diffs in the response to the files they name
+    #[arg(long)]
+    patch: bool,
+
+    /// Offer to run the last code block of the response
+    #[arg(long)]
+    run: bool,
+
+    /// With --patch, only report what would change
+    #[arg(long, requires = "patch")]
+    dry_run: bool,
+
+    /// Ask before sending a request estimated to be over this many tokens
+    #[arg(long, value_name = "TOKENS")]
+    confirm_over: Option<usize>,
+
+    /// Answer yes to any confirmation, such as from --confirm-over
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Send the prompt N times and report latency and throughput
+    #[arg(long, value_name = "N")]
+    bench: Option<usize>,
+
+    /// Number of --bench requests to send at once
+    #[arg(long, value_name = "N", default_value_t = 1, requires = "bench")]
+    concurrency: usize,
+
+    /// Read the input message from a file instead of stdin
+    #[arg(short, long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    file: Option<String>,
+
+    /// Role of the piped or file input; non-user input precedes the prompt
+    #[arg(
+        long,
+        visible_aliases = ["stdin-role", "pipe-as"],
+        value_enum,
+        default_value_t = Role::User
+    )]
+    role: Role,
+
+    /// Format of the piped or file input
+    #[arg(long, value_enum, default_value_t = InputFormat::Text)]
+    input_format: InputFormat,
+
+    /// Append the input message to the session without sending it
+    #[arg(long)]
+    no_request: bool,
+
+    /// Text added before every user message; saved as sent
+    #[arg(long, value_name = "TEXT")]
+    prompt_prefix: Option<String>,
+
+    /// Text added after every user message; saved as sent
+    #[arg(long, value_name = "TEXT")]
+    prompt_suffix: Option<String>,
+
+    /// Template combining the prompt with piped stdin
+    #[arg(
+        long,
+        visible_alias = "stdin-as",
+        value_name = "TEMPLATE",
+        default_value = DEFAULT_STDIN_TEMPLATE
+    )]
+    stdin_template: String,
+
+    /// Maximum size in bytes of piped or file context
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024)]
+    max_context_bytes: usize,
+
+    /// Add a file as context ahead of the prompt, with its path
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    prepend_file: Vec<String>,
+
+    /// Print how many tokens the prompt and input are, without sending them
+    #[arg(long)]
+    count_tokens: bool,
+
+    /// Attach an image to the prompt, for models that take images
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    image: Vec<String>,
+
+    /// Add the files matching a glob, such as "src/**/*.rs", as context
+    #[arg(long, value_name = "PATTERN")]
+    prepend_glob: Vec<String>,
+
+    /// Maximum number of files from --prepend-file and --prepend-glob
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    max_context_files: usize,
+
+    /// Mark the system prompt and prepended files with cache_control, for
+    /// backends that cache prompts, such as Anthropic's
+    #[arg(long, global = true)]
+    cache_hints: bool,
+
+    /// Reuse the response to a request sent before, at temperature 0
+    #[arg(long)]
+    cache: bool,
+
+    /// Use cached responses for at most this long
+    #[arg(long, value_name = "SECS")]
+    cache_ttl: Option<u64>,
+
+    /// Neither use nor save cached responses
+    #[arg(long, conflicts_with_all = ["cache", "force_cache"])]
+    no_cache: bool,
+
+    /// Cache responses at any temperature
+    #[arg(long)]
+    force_cache: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InputFormat {
+    /// A single message
+    Text,
+    /// One JSON message object per line, as in session files
+    Jsonl,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Send each prompt in a file as a separate conversation
+    Batch(batch::BatchArgs),
+    /// Draft a commit message for the staged changes
+    Commit(commit::CommitArgs),
+    /// Print embeddings of text from the arguments or stdin
+    Embed(embed::EmbedArgs),
+    /// Ask for a shell command and run it after confirmation
+    Exec(exec::ExecArgs),
+    /// Generate images from a prompt and save them
+    Image(imagegen::ImageArgs),
+    /// Transcribe an audio file, or with --chat send it as the prompt
+    Transcribe(transcribe::TranscribeArgs),
+    /// Ask about a file again each time it changes
+    Watch(watch::WatchArgs),
+    /// Print a shell completion script
+    Completions(completions::CompletionsArgs),
+    /// Print a man page in roff format
+    Man,
+    /// Manage prompt presets
+    Presets {
+        #[command(subcommand)]
+        command: PresetsCommand,
+    },
+    /// Browse conversations stored with --db
+    Sessions {
+        #[command(subcommand)]
+        command: SessionsCommand,
+    },
+    /// Manage the responses saved with --cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Delete every cached response
+    Clear,
+    /// Print how many responses are cached and the space they take
+    Stats,
+}
+
+fn run_cache_command(
+    command: &CacheCommand,
+    ttl: Option<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    let dir = cache::cache_dir().ok_or("no cache directory")?;
+    let cache = ResponseCache::new(dir, ttl);
+    match command {
+        CacheCommand::Clear => {
+            let cleared = cache.clear()?;
+            eprintln!("termgpt: deleted {} cached responses", cleared);
+        }
+        CacheCommand::Stats => {
+            let stats = cache.stats()?;
+            println!(
+                "{} responses, {} KB, in {}",
+                stats.entries,
+                stats.bytes.div_ceil(1024),
+                cache.dir().display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Ends a response stopped with Esc or Ctrl-C while it streamed.
+const STOPPED_MARK: &str = "[stopped by user]";
+
+const DEFAULT_STDIN_TEMPLATE: &str = "{{prompt}}\n\n```\n{{stdin}}\n```";
+
+fn check_context_size(
+    source: &str,
+    size: usize,
+    max_bytes: usize,
+) -> Result<(), Box<dyn Error>> {
+    if size > max_bytes {
+        Err(format!(
+            "{} exceeds the context limit of {} bytes \
+             (use --max-context-bytes to raise it)",
+            source, max_bytes
+        ))?
+    }
+    Ok(())
+}
+
+/// A path as it will be once the file exists, so that two ways of naming
+/// the same file compare equal.
+fn resolved_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match (dir.canonicalize(), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Refuses to write two things to one file, such as a session and a
+/// plaintext log given the same name with --session and --output, which
+/// would be garbled by both writing to it.
+fn check_distinct_files(files: &[(&str, &str)]) -> Result<(), Box<dyn Error>> {
+    let resolved: Vec<PathBuf> =
+        files.iter().map(|(_, path)| resolved_path(path)).collect();
+    for (i, (flag, _)) in files.iter().enumerate() {
+        let Some(j) = (0..i).find(|&j| resolved[j] == resolved[i]) else {
+            continue;
+        };
+        let (other, _) = files[j];
+        let path = resolved[i].display();
+        if other == *flag {
+            Err(format!("{} is given {} twice", flag, path))?
+        }
+        Err(format!(
+            "{} and {} are both {}; each needs a file of its own",
+            other, flag, path
+        ))?
+    }
+    Ok(())
+}
+
+fn read_stdin(max_bytes: usize) -> Result<String, Box<dyn Error>> {
+    let mut content = String::new();
+    io::stdin()
+        .take(max_bytes as u64 + 1)
+        .read_to_string(&mut content)?;
+    check_context_size("stdin", content.len(), max_bytes)?;
+    Ok(content)
+}
+
+fn read_context_file(
+    filename: &str,
+    max_bytes: usize,
+) -> Result<String, Box<dyn Error>> {
+    let size = fs::metadata(filename)?.len();
+    check_context_size(filename, size as usize, max_bytes)?;
+    Ok(fs::read_to_string(filename)?)
+}
+
+/// Handles --count-tokens, counting the prompt, --file, files added with
+/// --prepend-file and --prepend-glob, and piped input together.
+fn count_tokens(args: &Args, model: &str) -> Result<(), Box<dyn Error>> {
+    let mut texts: Vec<String> = Vec::new();
+    texts.extend(args.prompt.clone().or(args.prompt_option.clone()));
+    if let Some(filename) = &args.file {
+        texts.push(read_context_file(filename, args.max_context_bytes)?);
+    }
+    texts.extend(prepend::read_prepended(
+        &args.prepend_file,
+        &args.prepend_glob,
+        args.max_context_files,
+        args.max_context_bytes,
+    )?);
+    if !io::stdin().is_tty() {
+        texts.push(read_stdin(args.max_context_bytes)?);
+    }
+    if texts.is_empty() {
+        Err("--count-tokens needs a prompt, --file or piped input")?
+    }
+    let total: usize =
+        texts.iter().map(|text| tokens::count(model, text)).sum();
+    println!("{}", total);
+    Ok(())
+}
+
+fn compose_prompt(
+    template: &str,
+    prompt: &str,
+    stdin: &str,
+) -> Result<String, Box<dyn Error>> {
+    if stdin.trim().is_empty() {
+        return Ok(prompt.to_string());
+    }
+    let vars = HashMap::from([
+        ("prompt".to_string(), prompt.to_string()),
+        ("stdin".to_string(), stdin.trim_end().to_string()),
+    ]);
+    template::render(template, &vars, true)
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum ColorChoice {
+    /// Style output on a terminal unless NO_COLOR is set
+    Auto,
+    /// Style output even when it is piped
+    Always,
+    /// Never style output
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self, tty: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                tty && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// The response content as plain text
+    Text,
+    /// A JSON object with the content and response metadata
+    Json,
+    /// The conversation as JSON Lines, as in session files
+    Jsonl,
+}
+
+struct RequestOptions {
+    stream: bool,
+    stream_stats: bool,
+    show_usage: bool,
+    format: OutputFormat,
+    only_new: bool,
+    code: Option<usize>,
+    /// Which of several alternative responses to keep, from 0.
+    keep_choice: usize,
+    plain: bool,
+    /// Print the response exactly as it came, with no newline after it.
+    raw_output: bool,
+    /// The start of the next response, which the model carries on from.
+    prefill: Option<String>,
+    color: ColorChoice,
+    style: Style,
+    /// Maximum width of rendered output, or 0 for the terminal's width.
+    width: usize,
+    pager: bool,
+    copy_key: (KeyModifiers, KeyCode),
+    confirm_over: Option<usize>,
+    yes: bool,
+    interpreters: HashMap<String, String>,
+    pipe_to: Option<String>,
+    /// Read each response aloud.
+    speak: bool,
+    speaker: Speaker,
+    prompt_indicator: String,
+    prompt_color: Option<Color>,
+    /// Shown before each response in the REPL, followed by a space.
+    assistant_label: String,
+    /// Facts added to the system prompt of each request.
+    facts: Facts,
+    /// Send blank lines typed in the REPL rather than ignoring them.
+    send_empty: bool,
+    /// How long the REPL waits at the prompt before exiting, if it does.
+    idle_timeout: Option<Duration>,
+    /// The tools the model may call.
+    tools: Toolbox,
+    /// The moderation model to check messages with, if they are checked.
+    moderation: Option<String>,
+    /// The session file, which `/branch` can branch.
+    session: Option<SessionFile>,
+    /// Models to send each prompt to at once, with --compare.
+    compare: Vec<String>,
+    /// Print each compared model's latency, tokens and cost.
+    compare_stats: bool,
+    /// Leave out the summary printed when the REPL exits.
+    quiet: bool,
+    /// The session file named in that summary, if there is one.
+    session_path: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    content: &'a str,
+    model: &'a str,
+    finish_reason: Option<&'a str>,
+    usage: Option<&'a ChatGptUsage>,
+    cost: Option<f64>,
+    elapsed_ms: u128,
+    /// Every response, when there are alternatives.
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    choices: &'a [String],
+}
+
+/// Shows alternative responses one after another under numbered headings.
+fn join_choices(shown: &[String], kept: usize) -> String {
+    shown
+        .iter()
+        .enumerate()
+        .map(|(i, text)| {
+            let note = if i == kept { " (kept)" } else { "" };
+            format!(
+                "--- choice {} of {}{} ---\n\n{}",
+                i + 1,
+                shown.len(),
+                note,
+                text.trim_end()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<(), Box<dyn Error>> {
+    if io::stdout().is_tty() {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        println!("{}", serde_json::to_string(value)?);
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn print_response(
-    api_key: &str,
-    model: &str,
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    options: &RequestOptions,
     messages: &mut ChatMessages<'_>,
+    summary: &mut Option<RollingSummary>,
 ) -> Result<(), Box<dyn Error>> {
-    let resp = get_chatgpt_response(api_key, model, &messages.messages);
-    let mesg = resp.await?.choices.pop().unwrap().message;
+    let print_text = options.format == OutputFormat::Text
+        && options.code.is_none()
+        && options.pipe_to.is_none();
+    let mut jsonl = JsonLinesWriter::new(io::stdout());
 
-    println!("{}", mesg.content);
-    messages.push(mesg)?;
+    if options.format == OutputFormat::Jsonl && !options.only_new {
+        for message in messages.messages.iter() {
+            jsonl.write(message)?;
+            jsonl.flush()?;
+        }
+    }
+
+    let shutdown = Shutdown::listen();
+    let start = Instant::now();
+    let context = options
+        .facts
+        .apply(request_messages(&messages.messages, summary));
+    if !options.compare.is_empty() {
+        let answers =
+            compare::ask_each(client, params, &options.compare, &context);
+        let answers = shutdown.interruptible(async { Ok(answers.await) });
+        let answers = answers.await?;
+        return keep_comparison(
+            client, params, options, messages, summary, answers, &mut jsonl,
+        )
+        .await;
+    }
+    let mut stats = StreamStats::new(options.stream_stats);
+    let api = Prefilled {
+        api: client,
+        prefill: options.prefill.as_deref(),
+    };
+
+    let resp = if options.stream {
+        let resp = api.stream_chatgpt_response(
+            params,
+            &context,
+            options.show_usage || options.format == OutputFormat::Json,
+            |text| {
+                stats.before_chunk();
+                if print_text {
+                    print!("{}", text);
+                    io::stdout().flush()?;
+                }
+                stats.after_chunk();
+                stream_to_listeners(
+                    &mut messages.listeners,
+                    &messages.redactor,
+                    text,
+                )?;
+                Ok(())
+            },
+        );
+        let resp = shutdown.interruptible(resp).await?;
+        stats.finish();
+        resp
+    } else {
+        let resp = api.get_chatgpt_response(params, &context);
+        shutdown.interruptible(resp).await?
+    };
+    let mut resp = answer_tool_calls(
+        client, params, options, messages, summary, &shutdown, resp,
+    )
+    .await?;
+
+    let elapsed = start.elapsed();
+    let last = resp.choices.len().checked_sub(1);
+    let kept = options.keep_choice.min(last.ok_or("no choices returned")?);
+    let choices: Vec<String> = match resp.choices.len() {
+        1 => vec![],
+        _ => resp
+            .choices
+            .iter()
+            .map(|c| c.message.content.clone())
+            .collect(),
+    };
+    let choice = resp.choices.swap_remove(kept);
+    let resp_model = resp.model.as_deref().unwrap_or(&params.model);
+    let code = options
+        .code
+        .map(|n| select_code(&choice.message.content, n));
+
+    match options.format {
+        // The code is printed once the response is saved, so that it isn't
+        // lost if there turns out to be none.
+        OutputFormat::Text if code.is_some() => {}
+        OutputFormat::Text if options.pipe_to.is_some() => {}
+        // A streamed response has already been printed as it came.
+        OutputFormat::Text if options.raw_output => {
+            if !options.stream {
+                print!("{}", choice.message.content);
+            }
+            io::stdout().flush()?;
+        }
+        OutputFormat::Text if options.stream => println!(),
+        OutputFormat::Text if !choices.is_empty() => {
+            let shown: Vec<String> = if options.color == ColorChoice::Always
+                && !options.plain
+            {
+                choices
+                    .iter()
+                    .map(|c| render_markdown(&options.style, options.width, c))
+                    .collect()
+            } else {
+                choices.clone()
+            };
+            println!("{}", join_choices(&shown, kept));
+        }
+        // Responses are only rendered outside the REPL when asked for, so
+        // that piping one-shot output keeps the markdown source.
+        OutputFormat::Text
+            if options.color == ColorChoice::Always && !options.plain =>
+        {
+            println!(
+                "{}",
+                render_markdown(
+                    &options.style,
+                    options.width,
+                    &choice.message.content,
+                )
+            )
+        }
+        OutputFormat::Text => println!("{}", choice.message.content),
+        OutputFormat::Json => print_json(&JsonOutput {
+            content: &choice.message.content,
+            model: resp_model,
+            finish_reason: choice.finish_reason.as_deref(),
+            usage: resp.usage.as_ref(),
+            cost: resp
+                .usage
+                .as_ref()
+                .and_then(|u| estimate_cost(resp_model, u)),
+            elapsed_ms: elapsed.as_millis(),
+            choices: &choices,
+        })?,
+        OutputFormat::Jsonl => {}
+    }
+
+    print_truncation_hint(
+        choice.finish_reason.as_deref(),
+        params.token_limit(),
+        &resp.usage,
+    );
+    if options.show_usage {
+        print_usage(resp_model, &resp.usage);
+    }
+    if client.show_limits {
+        print_rate_limits(&resp.rate_limits);
+    }
+    messages.push(choice.message)?;
+    // Without a session the summary would be thrown away, so don't pay
+    // for it.
+    if let Some(summary) = summary.as_mut().filter(|s| s.is_persistent()) {
+        let client = client.without_examples();
+        summary.update(&client, params, &messages.messages).await?;
+    }
+    if options.format == OutputFormat::Jsonl {
+        jsonl.write(messages.messages.last().unwrap())?;
+        jsonl.flush()?;
+    }
+    let content = &messages.messages.last().unwrap().content;
+    match (code, &options.pipe_to) {
+        (Some(Ok(code)), Some(command)) => pipe_response(command, &code)?,
+        (Some(Ok(code)), None) => print!("{}", code),
+        (Some(Err(e)), _) => {
+            eprintln!("{}", content);
+            Err(e)?
+        }
+        (None, Some(command)) => pipe_response(command, content)?,
+        (None, None) => {}
+    }
+    if options.speak {
+        speak_response(client, options, content).await;
+    }
+    Ok(())
+}
+
+/// Prints the answers of --compare, and keeps one of them in the
+/// conversation as if it were the only response.
+async fn keep_comparison(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    options: &RequestOptions,
+    messages: &mut ChatMessages<'_>,
+    summary: &mut Option<RollingSummary>,
+    answers: Vec<Answer>,
+    jsonl: &mut JsonLinesWriter<io::Stdout>,
+) -> Result<(), Box<dyn Error>> {
+    if options.compare_stats {
+        compare::print_stats(&answers);
+    }
+    let (mut choices, kept) =
+        compare::choices(&answers, options.keep_choice)
+            .inspect_err(|_| print_compare_failures(&answers))?;
+    match options.format {
+        OutputFormat::Text if options.pipe_to.is_some() => {}
+        OutputFormat::Text => {
+            let render = options.color == ColorChoice::Always && !options.plain;
+            let text = show_answers(options, &answers, kept, render);
+            println!("{}", text);
+        }
+        OutputFormat::Json => print_json(&compare::JsonAnswers(&answers))?,
+        OutputFormat::Jsonl => {}
+    }
+    messages.push(choices.swap_remove(kept))?;
+    if let Some(summary) = summary.as_mut().filter(|s| s.is_persistent()) {
+        let client = client.without_examples();
+        summary.update(&client, params, &messages.messages).await?;
+    }
+    let kept = messages.messages.last().unwrap();
+    if options.format == OutputFormat::Jsonl {
+        jsonl.write(kept)?;
+        jsonl.flush()?;
+    }
+    if let Some(command) = &options.pipe_to {
+        pipe_response(command, &kept.content)?;
+    }
     Ok(())
 }
 
+/// The answers of --compare under their headings, rendered if `render`.
+fn show_answers(
+    options: &RequestOptions,
+    answers: &[Answer],
+    kept: usize,
+    render: bool,
+) -> String {
+    compare::join_answers(answers, kept, |content| {
+        if render {
+            render_markdown(&options.style, options.width, content)
+        } else {
+            content.to_string()
+        }
+    })
+}
+
+/// Says why each compared model's request failed, when they all did.
+fn print_compare_failures(answers: &[Answer]) {
+    for answer in answers {
+        if let Err(e) = &answer.response {
+            eprintln!("termgpt: {}: {}", answer.model, e);
+        }
+    }
+}
+
+/// Reads a response aloud, only warning if it can't be, as it has been
+/// shown already.
+async fn speak_response(
+    client: &ChatGptClient,
+    options: &RequestOptions,
+    text: &str,
+) {
+    if let Err(e) = options.speaker.speak(client, text).await {
+        eprintln!("termgpt: could not read the response aloud: {}", e);
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    error: String,
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+    let mut argv: Vec<OsString> = env::args_os().collect();
+    let program = argv.first().map(|p| Path::new(p).file_name());
+    if program == Some(Some("prepare-commit-msg".as_ref())) {
+        argv = commit::hook_args(argv);
+    }
+    let args = Args::parse_from(argv);
+    let format = args.format;
+    let result = run(args);
+
+    // Everything has been flushed by now, as it was dropped on the way out.
+    if let Some(interrupted) = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<Interrupted>())
+    {
+        if io::stdout().is_tty() {
+            print!("\x1b[2K\r");
+            io::stdout().flush()?;
+        }
+        eprintln!("termgpt: {}", interrupted);
+        process::exit(interrupted.code);
+    }
+    let flagged = result
+        .as_ref()
+        .is_err_and(|e| e.downcast_ref::<Flagged>().is_some());
+    let code = if flagged { FLAGGED_EXIT_CODE } else { 1 };
+    match (&result, format) {
+        (Err(e), OutputFormat::Json) => {
+            print_json(&JsonError {
+                error: e.to_string(),
+            })?;
+            process::exit(code);
+        }
+        (Err(e), _) if flagged => {
+            eprintln!("termgpt: {}", e);
+            process::exit(code);
+        }
+        _ => {}
+    }
+    result
+}
+
+const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    if let Some(Command::Man) = &args.command {
+        return man::print_man_page();
+    }
+    let config = if args.no_config {
+        Config::default()
+    } else {
+        Config::load()?
+    };
+    let presets = preset::load_presets(&config.presets)?;
+
+    if let Some(Command::Presets { command }) = &args.command {
+        return preset::run_presets_command(command, &presets);
+    }
+    if let Some(Command::Sessions { command }) = &args.command {
+        let db = args.db.as_deref().or(config.db.as_deref());
+        return db::run_sessions_command(command, db);
+    }
+    let cache_ttl =
+        args.cache_ttl.or(config.cache_ttl).map(Duration::from_secs);
+    if let Some(Command::Cache { command }) = &args.command {
+        return run_cache_command(command, cache_ttl);
+    }
+    if let Some(Command::Completions(completions_args)) = &args.command {
+        completions::run_completions(completions_args, &config, &presets);
+        return Ok(());
+    }
+
+    let preset = match &args.preset {
+        Some(name) => preset::find_preset(&presets, name)?,
+        None => Default::default(),
+    };
+
+    if args.count_tokens {
+        let model = args
+            .model
+            .as_deref()
+            .or(preset.model.as_deref())
+            .or(config.model.as_deref())
+            .unwrap_or(DEFAULT_MODEL);
+        return count_tokens(&args, resolve_model(&config.models, model));
+    }
+
+    // Files given with the config's keys are written to as well.
+    let files = [
+        ("--session", args.session.as_deref()),
+        (
+            "--log-jsonl",
+            args.log_jsonl.as_deref().or(config.log_jsonl.as_deref()),
+        ),
+        ("--transcript", args.transcript.as_deref()),
+        ("--db", args.db.as_deref().or(config.db.as_deref())),
+        ("--trace-file", args.trace_file.as_deref()),
+    ];
+    let outputs = args.output.iter().map(|p| ("--output", Some(p.as_str())));
+    let files: Vec<(&str, &str)> = files
+        .into_iter()
+        .chain(outputs)
+        .filter_map(|(flag, path)| Some((flag, path?)))
+        .collect();
+    check_distinct_files(&files)?;
 
     let api_key = args
         .api_key
         .or(env::var("OPENAI_API_KEY").ok())
-        .expect("OpenAI API key not set");
+        .or(config.api_key)
+        .ok_or("OpenAI API key not set")?;
+    let base_url = args
+        .base_url
+        .or(config.base_url)
+        .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+    let user_agent = args
+        .user_agent
+        .or(config.user_agent)
+        .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string());
+    let mut client = ChatGptClient::new(api_key, base_url, &user_agent);
+    client.completion_mode = args.completion_mode;
+    client.show_limits = args.show_limits || args.verbose;
+    client.cache_hints =
+        args.cache_hints || config.cache_hints.unwrap_or(false);
+    if let Some(filename) = &args.trace_file {
+        let trace = TraceFile::open(filename).map_err(|e| {
+            format!("could not open trace file {}: {}", filename, e)
+        })?;
+        client.trace = Some(Arc::new(trace));
+    }
+    if let Some(filename) = &args.examples {
+        client.examples = read_examples(filename)?;
+    }
+    if let Some(Command::Image(image_args)) = &args.command {
+        let model = args
+            .model
+            .as_deref()
+            .map(|m| resolve_model(&config.models, m));
+        return imagegen::run_image(&client, model, image_args);
+    }
+    if let Some(Command::Embed(embed_args)) = &args.command {
+        let model = args
+            .model
+            .as_deref()
+            .map(|m| resolve_model(&config.models, m));
+        return embed::run_embed(&client, model, embed_args);
+    }
+    // The transcript is printed, or with --chat sent on as the prompt.
+    let transcript = match &args.command {
+        Some(Command::Transcribe(transcribe_args)) => {
+            let text = transcribe::run_transcribe(&client, transcribe_args)?;
+            if !transcribe_args.chat {
+                println!("{}", text.trim());
+                return Ok(());
+            }
+            Some(text.trim().to_string())
+        }
+        _ => None,
+    };
+
+    // A resumed session defaults to the settings it was created with.
+    let metadata = match &args.session {
+        Some(session) => SessionMetadata::load(session)?,
+        None => None,
+    };
+    let resumed = metadata.is_some();
+    let metadata = metadata.unwrap_or_default();
+    // Once a session leaves out its system prompt it always does, so that
+    // resuming it without the flag doesn't start writing one.
+    let persist_system =
+        !(args.no_persist_system || metadata.no_persist_system);
+    let model = args
+        .model
+        .or(preset.model)
+        .or(metadata.model)
+        .or(config.model)
+        .unwrap_or_else(|| DEFAULT_MODEL.to_string());
+    let mut params = ChatGptParams {
+        model: resolve_model(&config.models, &model).to_string(),
+        temperature: args
+            .temperature
+            .or(preset.temperature)
+            .or(metadata.temperature)
+            .or(config.temperature),
+        max_tokens: args.max_tokens.or(metadata.max_tokens),
+        max_completion_tokens: None,
+        reasoning_effort: args
+            .reasoning_effort
+            .or(preset.reasoning_effort)
+            .or(metadata.reasoning_effort),
+        n: args.count.filter(|&n| n > 1),
+        tools: Vec::new(),
+        extra: args.extra_param.into_iter().collect(),
+    };
+    if !args.tools.is_empty() && args.completion_mode {
+        Err("--tools can't be used with --completion-mode")?
+    }
+    let dropped = params.adapt_to_reasoning_model();
+    if !dropped.is_empty() {
+        eprintln!(
+            "termgpt: {} doesn't accept {}; leaving it out",
+            params.model,
+            dropped.join(", ")
+        );
+    }
+    // Only a request answered the same way each time is worth caching, and
+    // --bench is there to time the API.
+    let caching = !args.no_cache
+        && (args.cache || args.force_cache || config.cache.unwrap_or(false));
+    if caching && args.bench.is_none() {
+        match params.temperature {
+            Some(t) if t > 0.0 && !args.force_cache => eprintln!(
+                "termgpt: not caching at temperature {} \
+                 (use --temperature 0, or --force-cache)",
+                t
+            ),
+            None if !args.force_cache => eprintln!(
+                "termgpt: not caching at the default temperature \
+                 (use --temperature 0, or --force-cache)"
+            ),
+            _ => {
+                let dir = cache::cache_dir().ok_or("no cache directory")?;
+                let cache = ResponseCache::new(dir, cache_ttl);
+                client.cache = Some(Arc::new(cache));
+            }
+        }
+    }
+    let system = match args.system_file {
+        Some(filename) => Some(fs::read_to_string(filename)?),
+        None => args.system.or(preset.system).or(config.system),
+    };
+
+    let mut vars: HashMap<String, String> = args.vars.into_iter().collect();
+    let render = |text: &str, vars: &HashMap<String, String>| {
+        template::render(text, vars, args.allow_missing_vars)
+    };
+
+    if let Some(Command::Batch(batch_args)) = args.command {
+        let system = system.map(|s| render(&s, &vars)).transpose()?;
+        return batch::run_batch(
+            &client,
+            &params,
+            system.as_deref(),
+            &batch_args,
+        );
+    }
+
+    if let Some(Command::Commit(commit_args)) = &args.command {
+        let system = config.commit_prompt.as_deref();
+        return commit::run_commit(&client, &params, system, commit_args);
+    }
+    if let Some(Command::Watch(watch_args)) = &args.command {
+        let system = system.map(|s| render(&s, &vars)).transpose()?;
+        let styled = args.color.enabled(io::stdout().is_tty());
+        let style = theme::build_style(None, &config.theme)?;
+        let width = config.max_width.unwrap_or(DEFAULT_MAX_WIDTH);
+        return watch::run_watch(
+            &client,
+            &params,
+            system.as_deref(),
+            watch_args,
+            |text| {
+                if styled {
+                    render_markdown(&style, width, text)
+                } else {
+                    text.to_string()
+                }
+            },
+        );
+    }
+    let sync = args.sync || config.sync.unwrap_or(false);
+    if let Some(Command::Exec(exec_args)) = args.command {
+        let styled = args.color.enabled(io::stdout().is_tty());
+        return exec::run_exec(&client, &params, &exec_args, styled, sync);
+    }
+
+    let mut toolbox = Toolbox::new(args.tools);
+    if !args.no_mcp && !args.completion_mode {
+        toolbox.connect(&config.mcp.servers, args.verbose);
+    }
+    params.tools = toolbox.definitions();
+
+    let mut summary = if args.rolling_summary {
+        Some(RollingSummary::new(
+            args.session.as_deref(),
+            args.recent_turns,
+        )?)
+    } else {
+        None
+    };
 
+    let persistent = args.session.is_some();
+    let session_name = args.session.clone();
+    let database = args.db.or(config.db).map(|path| Database {
+        path,
+        model: params.model.clone(),
+    });
+    // A rolling summary is of one line of the conversation, so it can't be
+    // branched.
+    let session_file = args
+        .session
+        .clone()
+        .filter(|_| !args.rolling_summary)
+        .map(|path| SessionFile {
+            path,
+            sync,
+            persist_system,
+            database: database.clone(),
+        });
     let mut messages = match args.session {
         Some(filename) => {
+            // Opened first, so that nothing is written while another
+            // termgpt has the session.
+            let listener =
+                SessionAppendListener::new(&filename, sync, persist_system)
+                    .map_err(|e| {
+                        format!(
+                            "could not open session file {}: {}",
+                            filename, e
+                        )
+                    })?;
+            if !resumed {
+                SessionMetadata::save(&filename, &params, !persist_system)
+                    .map_err(|e| {
+                        format!("could not write {}.meta.json: {}", filename, e)
+                    })?;
+            }
             let mut messages = ChatMessages::from_file(&filename)
                 .expect("could not read session file");
-            let listener = SessionAppendListener::new(&filename)
-                .expect("could not open session file for writing");
-            messages.register(listener);
+            messages.register(branch::listener_name(&filename), listener);
             messages
         }
         None => ChatMessages::new(),
     };
 
-    if let Some(filename) = args.output {
-        let listener = OutputAppendListener::new(&filename)
-            .expect("could not open output file for writing");
-        messages.register(listener);
+    messages.listeners.strict = args.strict_listeners;
+
+    if let Some(mode) = args.redact.or(config.redact) {
+        messages.redact_with(Redactor::new(mode, &config.redact_patterns)?);
+    }
+
+    if let Some(filename) = args.log_jsonl.or(config.log_jsonl) {
+        let listener = SessionAppendListener::for_log(&filename, sync)
+            .map_err(|e| format!("could not open log {}: {}", filename, e))?;
+        messages.register(format!("log {}", filename), listener);
+    }
+
+    if let Some(database) = &database {
+        messages.register(
+            db::listener_name(&database.path),
+            database.listener(session_name.as_deref())?,
+        );
     }
 
-    let stdin = io::stdin();
+    if let Some(url) = args.webhook {
+        let token = args
+            .webhook_token
+            .or_else(|| env::var("TERMGPT_WEBHOOK_TOKEN").ok());
+        let name = format!("webhook {}", url);
+        messages.register(
+            name,
+            WebhookListener::new(
+                client.http.clone(),
+                url,
+                token,
+                &params.model,
+                session_name.as_deref(),
+            )?,
+        );
+    }
 
-    if stdin.is_tty() {
-        repl_loop(&api_key, &args.model, &mut messages)
+    let output_format = args
+        .output_format
+        .or(config.output_format)
+        .unwrap_or_default();
+    let output_timestamps =
+        args.output_timestamps || config.output_timestamps.unwrap_or(false);
+    for filename in args.output {
+        let listener = OutputAppendListener::new(
+            &filename,
+            output_format,
+            output_timestamps,
+            args.output_plain,
+        )
+        .expect("could not open output file for writing");
+        messages.register(format!("output file {}", filename), listener);
+    }
+
+    if let Some(filename) = args.transcript {
+        let listener = TranscriptListener::new(
+            &filename,
+            args.transcript_format,
+            &messages.messages,
+        );
+        messages.register(format!("transcript {}", filename), listener);
+    }
+
+    let mut prompt = args.prompt.or(args.prompt_option).or(transcript);
+    let file = match args.file {
+        Some(filename) => {
+            Some(read_context_file(&filename, args.max_context_bytes)?)
+        }
+        None => None,
+    };
+    if let Some(template) = preset.template {
+        if prompt.is_some() || file.is_some() || !io::stdin().is_tty() {
+            let arg = prompt.map(|p| render(&p, &vars)).transpose()?;
+            vars.insert("arg".to_string(), arg.unwrap_or_default());
+            prompt = Some(template);
+        }
+    }
+    let templates = match args.input_format {
+        InputFormat::Text => [&prompt, &file, &system],
+        InputFormat::Jsonl => [&prompt, &None, &system],
+    };
+    let uses_stdin = templates
+        .iter()
+        .flat_map(|t| t.as_deref())
+        .any(|t| template::references(t, "stdin"));
+    let mut stdin = if !io::stdin().is_tty() && (file.is_none() || uses_stdin) {
+        Some(read_stdin(args.max_context_bytes)?)
     } else {
-        let content = io::read_to_string(stdin)?;
-        messages.push(ChatGptMessage { role: Role::User, content })?;
-        print_response(&api_key, &args.model, &mut messages)
+        None
+    };
+    if uses_stdin {
+        vars.insert("stdin".to_string(), stdin.take().unwrap_or_default());
+    }
+
+    let prompt = prompt.map(|p| render(&p, &vars)).transpose()?;
+    let system = system.map(|s| render(&s, &vars)).transpose()?;
+    let input = match (args.input_format, file) {
+        (InputFormat::Text, Some(file)) => Some(render(&file, &vars)?),
+        (_, file) => file.or(stdin),
+    };
+
+    // A resumed session keeps the system prompt it started with, so that
+    // passing the same --system again doesn't duplicate it. One that leaves
+    // its system prompt out of the file takes it from this run instead,
+    // ahead of the messages read back.
+    let kept = messages
+        .messages
+        .first()
+        .filter(|first| first.role == Role::System)
+        .map(|first| first.content.clone());
+    let system = system.map(|content| ChatGptMessage {
+        cacheable: client.cache_hints,
+        ..ChatGptMessage::new(Role::System, content)
+    });
+    match (system, kept) {
+        (Some(system), Some(kept)) if system.content != kept => eprintln!(
+            "termgpt: session already has a different system prompt; \
+             keeping the existing one"
+        ),
+        (Some(_), Some(_)) => {}
+        (Some(system), None) if !persist_system => messages.prepend(system)?,
+        (Some(system), None) => messages.push(system)?,
+        (None, None) if resumed && !persist_system => eprintln!(
+            "termgpt: session doesn't keep its system prompt; pass it with \
+             --system, --system-file or --preset"
+        ),
+        (None, _) => {}
+    }
+    // A system prompt read back is as stable as a new one, even if the
+    // session was started without --cache-hints.
+    if client.cache_hints {
+        if let Some(first) = messages.messages.first_mut() {
+            first.cacheable |= first.role == Role::System;
+        }
     }
+
+    // Only ask the terminal about its background when the answer matters
+    // and nothing else chose a theme.
+    let renders = (prompt.is_none() && input.is_none())
+        || args.color == ColorChoice::Always;
+    let detect_theme = config.theme.base.is_none()
+        && renders
+        && !args.plain
+        && io::stdin().is_tty()
+        && args.color.enabled(io::stdout().is_tty());
+    let theme = args.theme.or_else(|| {
+        detect_theme.then(|| theme::detect_background(args.verbose))
+    });
+    let keep_choice = args.keep_choice.unwrap_or(1);
+    let (choices, of) = match args.compare.len() {
+        0 => (args.count.unwrap_or(1), "--count responses"),
+        models => (models as u32, "--compare models"),
+    };
+    if keep_choice > choices {
+        Err(format!(
+            "--keep-choice {} is past the last of the {}",
+            keep_choice, of
+        ))?
+    }
+    let options = RequestOptions {
+        // Alternative responses would arrive interleaved, so they are
+        // only shown once complete, as are responses that may call tools.
+        stream: (args.stream || config.stream.unwrap_or(false))
+            && params.n.is_none()
+            && params.tools.is_empty()
+            && args.compare.is_empty(),
+        stream_stats: args.stream_stats,
+        show_usage: args.show_usage || config.show_usage.unwrap_or(false),
+        format: args.format,
+        only_new: args.only_new,
+        code: args.code,
+        keep_choice: keep_choice as usize - 1,
+        plain: args.plain || config.plain.unwrap_or(false),
+        raw_output: args.raw_output,
+        prefill: args.prefill,
+        color: args.color,
+        style: theme::build_style(theme, &config.theme)?,
+        width: args.width.or(config.max_width).unwrap_or(DEFAULT_MAX_WIDTH),
+        pager: !args.no_pager && config.pager.unwrap_or(true),
+        copy_key: parse_key(
+            config.copy_code_key.as_deref().unwrap_or(DEFAULT_COPY_KEY),
+        )
+        .map_err(|e| format!("invalid copy_code_key: {}", e))?,
+        confirm_over: args.confirm_over,
+        yes: args.yes,
+        interpreters: config.interpreters,
+        pipe_to: args.pipe_to,
+        speak: args.speak || config.speak.unwrap_or(false),
+        speaker: Speaker {
+            voice: args
+                .voice
+                .or(config.voice)
+                .unwrap_or_else(|| speak::DEFAULT_VOICE.to_string()),
+            speed: args.speech_speed,
+            player: args
+                .audio_player
+                .or(config.audio_player)
+                .or_else(speak::detect_player),
+        },
+        send_empty: config.send_empty_input.unwrap_or(false),
+        idle_timeout: args
+            .idle_timeout
+            .or(config.idle_timeout)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        tools: toolbox,
+        facts: Facts {
+            datetime: args.context_datetime
+                || config.context_datetime.unwrap_or(false),
+            env: args.context_env || config.context_env.unwrap_or(false),
+        },
+        assistant_label: args
+            .assistant_label
+            .or(config.assistant_label)
+            .unwrap_or_default(),
+        prompt_indicator: args
+            .prompt_indicator
+            .or(config.prompt_indicator)
+            .unwrap_or_else(|| repl_prompt::DEFAULT_INDICATOR.to_string()),
+        prompt_color: match &config.prompt_color {
+            Some(color) => {
+                Some(theme::parse_color(color).ok_or_else(|| {
+                    format!("invalid prompt_color `{}`", color)
+                })?)
+            }
+            None => None,
+        },
+        moderation: (args.moderate || config.moderate.unwrap_or(false)).then(
+            || {
+                args.moderation_model
+                    .or(config.moderation_model)
+                    .unwrap_or_else(|| DEFAULT_MODERATION_MODEL.to_string())
+            },
+        ),
+        session: session_file,
+        quiet: args.quiet,
+        session_path: session_name,
+        compare: args
+            .compare
+            .iter()
+            .map(|model| resolve_model(&config.models, model).to_string())
+            .collect(),
+        compare_stats: args.stats,
+    };
+
+    let wrapper = PromptWrapper {
+        prefix: args.prompt_prefix.unwrap_or_default(),
+        suffix: args.prompt_suffix.unwrap_or_default(),
+    };
+
+    // Prepended files go ahead of the first message sent, which in the REPL
+    // means straight away.
+    let prepended = prepend::read_prepended(
+        &args.prepend_file,
+        &args.prepend_glob,
+        args.max_context_files,
+        args.max_context_bytes,
+    )?
+    .map(|content| ChatGptMessage {
+        cacheable: client.cache_hints,
+        ..ChatGptMessage::new(Role::User, content)
+    });
+    let images = args
+        .image
+        .iter()
+        .map(|path| ImageAttachment::load(path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let new_messages = match (args.input_format, prompt, input) {
+        (_, None, None) if args.bench.is_some() => {
+            return Err("--bench needs a prompt or input".into());
+        }
+        (_, None, None) => {
+            if let Some(message) = prepended {
+                messages.push(message)?;
+            }
+            return repl_loop(
+                &client,
+                &params,
+                &options,
+                &wrapper,
+                &mut messages,
+                &mut summary,
+                images,
+            );
+        }
+        (InputFormat::Text, Some(prompt), Some(input))
+            if matches!(args.role, Role::User) =>
+        {
+            let content =
+                compose_prompt(&args.stdin_template, &prompt, &input)?;
+            vec![ChatGptMessage::new(Role::User, wrapper.wrap(&content))]
+        }
+        (InputFormat::Text, prompt, input) => {
+            let input = input
+                .filter(|input| prompt.is_none() || !input.trim().is_empty())
+                .map(|content| {
+                    let content = match args.role {
+                        Role::User => wrapper.wrap(&content),
+                        _ => content,
+                    };
+                    ChatGptMessage::new(args.role, content)
+                });
+            let prompt = prompt.map(|prompt| {
+                ChatGptMessage::new(Role::User, wrapper.wrap(&prompt))
+            });
+            input.into_iter().chain(prompt).collect()
+        }
+        (InputFormat::Jsonl, prompt, input) => {
+            let mut new_messages =
+                parse_jsonl_messages(&input.unwrap_or_default())?;
+            if let Some(prompt) = prompt {
+                new_messages.push(ChatGptMessage::new(
+                    Role::User,
+                    wrapper.wrap(&prompt),
+                ));
+            }
+            new_messages
+        }
+    };
+    let mut new_messages: Vec<_> =
+        prepended.into_iter().chain(new_messages).collect();
+    if !images.is_empty() {
+        let prompt = new_messages
+            .iter_mut()
+            .rev()
+            .find(|m| m.role == Role::User)
+            .ok_or("--image needs a user message to attach the image to")?;
+        prompt.images = images;
+    }
+    messages.redact_outgoing(&mut new_messages);
+
+    if let Some(count) = args.bench {
+        let mut conversation = messages.messages;
+        conversation.extend(new_messages);
+        let bench_options = BenchOptions {
+            count,
+            concurrency: args.concurrency,
+            stream: options.stream,
+        };
+        return bench::run_bench(
+            &client,
+            &params,
+            conversation,
+            &bench_options,
+        );
+    }
+
+    if let Some(model) =
+        options.moderation.as_deref().filter(|_| !args.no_request)
+    {
+        moderation::check_blocking(&client, model, &new_messages)?;
+    }
+    if !args.no_request
+        && !confirm_send(
+            &client,
+            &options,
+            &messages.messages,
+            &summary,
+            &new_messages,
+        )?
+    {
+        Err("request not sent")?
+    }
+
+    for message in new_messages {
+        messages.push(message)?;
+    }
+
+    if args.no_request {
+        return Ok(());
+    }
+    print_response(&client, &params, &options, &mut messages, &mut summary)?;
+    let styled = options.color.enabled(io::stderr().is_tty());
+    if args.patch {
+        let last = messages.messages.last().unwrap();
+        patch::apply_response(&last.content, args.dry_run, styled)?;
+    }
+    if args.run {
+        // The output is only worth adding if the session keeps it.
+        let output = run::run_last_block(
+            &messages.messages,
+            &options.interpreters,
+            persistent,
+            styled,
+        )?;
+        if let Some(output) = output {
+            messages.push(output)?;
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,133 @@
+use crate::{config, Args};
+use clap::CommandFactory;
+use clap_mangen::roff::{bold, italic, roman, Roff};
+use clap_mangen::Man;
+use std::error::Error;
+use std::io;
+
+const ENVIRONMENT: &[(&str, &str)] = &[
+    ("OPENAI_API_KEY", "API key used when --api-key is not given"),
+    (
+        "TERMGPT_WEBHOOK_TOKEN",
+        "Bearer token for --webhook when --webhook-token is not given",
+    ),
+    (
+        "NO_COLOR",
+        "When set and not empty, output isn't styled unless --color=always",
+    ),
+    (
+        "PAGER",
+        "Pager long responses in the REPL are shown through; defaults to \
+         less -RFX",
+    ),
+    (
+        "VISUAL, EDITOR",
+        "Editor opened from the REPL with Ctrl-X, which sends the edited \
+         line, or Ctrl-O, which doesn't",
+    ),
+    (
+        "XDG_CACHE_HOME",
+        "Base directory of the response cache; defaults to ~/.cache",
+    ),
+    (
+        "XDG_CONFIG_HOME",
+        "Base directory of the config files; defaults to ~/.config",
+    ),
+];
+
+const FILES: &[(&str, &str)] = &[
+    (
+        "$XDG_CONFIG_HOME/termgpt/config.toml",
+        "Default settings for any flag without a value on the command line",
+    ),
+    (
+        "$XDG_CONFIG_HOME/termgpt/presets/NAME.toml",
+        "A preset named NAME, overriding one of the same name in the config",
+    ),
+    (
+        "$XDG_CACHE_HOME/termgpt/",
+        "Responses saved with --cache, a JSON file for each request",
+    ),
+    (
+        "SESSION.meta.json",
+        "The model and sampling settings a session file was created with",
+    ),
+    (
+        "SESSION.summary.json",
+        "The running summary of a session used with --rolling-summary",
+    ),
+];
+
+const EXIT_STATUS: &[(&str, &str)] = &[
+    ("0", "The request succeeded, or the REPL was exited"),
+    ("1", "An API, file or configuration error occurred"),
+    ("2", "The command line arguments were invalid"),
+    ("3", "With --moderate, the message was flagged and not sent"),
+];
+
+const EXAMPLES: &[(&str, &str)] = &[
+    ("termgpt", "Start an interactive chat"),
+    (
+        "termgpt \"Explain this error\" < build.log",
+        "Send a prompt along with piped context",
+    ),
+    (
+        "termgpt -s notes.jsonl \"Summarise the above\"",
+        "Continue a conversation saved in a session file",
+    ),
+    (
+        "termgpt --preset review -f main.rs",
+        "Send a file using the template and model of a preset",
+    ),
+    (
+        "termgpt batch prompts.txt -j 4 > results.jsonl",
+        "Send each line of a file as a separate prompt",
+    ),
+];
+
+fn section(roff: &mut Roff, title: &str, items: &[(&str, &str)]) {
+    roff.control("SH", [title]);
+    for (term, description) in items {
+        roff.control("TP", [])
+            .text([bold(*term)])
+            .text([roman(*description)]);
+    }
+}
+
+/// Prints a roff man page generated from the command-line definitions.
+pub fn print_man_page() -> Result<(), Box<dyn Error>> {
+    let man = Man::new(Args::command());
+    let mut out = io::stdout();
+    man.render_title(&mut out)?;
+    man.render_name_section(&mut out)?;
+    man.render_synopsis_section(&mut out)?;
+    man.render_description_section(&mut out)?;
+    man.render_options_section(&mut out)?;
+    man.render_subcommands_section(&mut out)?;
+
+    let mut roff = Roff::new();
+    section(&mut roff, "ENVIRONMENT", ENVIRONMENT);
+    section(&mut roff, "FILES", FILES);
+    roff.control("SH", ["CONFIGURATION"]).text([
+        roman("The config file is TOML and may set the following keys; "),
+        roman("flags on the command line take precedence. See "),
+        italic("FILES"),
+        roman(" for its location. Any value may reference environment "),
+        roman("variables as "),
+        bold("${NAME}"),
+        roman(", and "),
+        bold("$${"),
+        roman(" is a literal ${."),
+    ]);
+    for (key, description) in config::KEYS {
+        roff.control("TP", [])
+            .text([bold(*key)])
+            .text([roman(*description)]);
+    }
+    section(&mut roff, "EXIT STATUS", EXIT_STATUS);
+    section(&mut roff, "EXAMPLES", EXAMPLES);
+    roff.to_writer(&mut out)?;
+
+    man.render_version_section(&mut out)?;
+    Ok(())
+}
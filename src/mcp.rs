@@ -0,0 +1,327 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The MCP revision termgpt speaks. Servers answer with the one they
+/// speak, which is accepted whatever it is, as termgpt only lists and
+/// calls tools.
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// How long a server has to start and list its tools.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a tool call has to finish.
+const CALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The `[mcp]` table of the config file.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct McpConfig {
+    pub servers: BTreeMap<String, McpServerConfig>,
+}
+
+/// A Model Context Protocol server, run as a command that speaks it over
+/// stdin and stdout.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct McpServerConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+/// A tool a server offers.
+pub struct McpTool {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+}
+
+/// A running server, and the tools it listed when it started. It is
+/// stopped when dropped.
+pub struct McpServer {
+    pub name: String,
+    pub tools: Vec<McpTool>,
+    connection: Mutex<Connection>,
+}
+
+struct Connection {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+    next_id: u64,
+}
+
+impl McpServer {
+    /// Runs the server's command, makes the handshake and lists its
+    /// tools. Its stderr is only shown with `verbose`, as servers tend to
+    /// log there.
+    pub fn start(
+        name: &str,
+        config: &McpServerConfig,
+        verbose: bool,
+    ) -> Result<McpServer, Box<dyn Error>> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(if verbose {
+                Stdio::inherit()
+            } else {
+                Stdio::null()
+            })
+            .spawn()
+            .map_err(|e| format!("could not run {}: {}", config.command, e))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut connection = Connection {
+            child,
+            stdin,
+            lines,
+            next_id: 1,
+        };
+
+        connection.request(
+            "initialize",
+            json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": {},
+                "clientInfo": {
+                    "name": "termgpt",
+                    "version": env!("CARGO_PKG_VERSION")
+                }
+            }),
+            STARTUP_TIMEOUT,
+        )?;
+        connection.notify("notifications/initialized")?;
+
+        let mut tools = Vec::new();
+        let mut cursor = None;
+        loop {
+            let params = match cursor.take() {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let result =
+                connection.request("tools/list", params, STARTUP_TIMEOUT)?;
+            for tool in result["tools"].as_array().into_iter().flatten() {
+                let Some(name) = tool["name"].as_str() else {
+                    continue;
+                };
+                tools.push(McpTool {
+                    name: name.to_string(),
+                    description: tool["description"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    input_schema: match &tool["inputSchema"] {
+                        Value::Null => json!({"type": "object"}),
+                        schema => schema.clone(),
+                    },
+                });
+            }
+            match result["nextCursor"].as_str() {
+                Some(next) => cursor = Some(next.to_string()),
+                None => break,
+            }
+        }
+
+        Ok(McpServer {
+            name: name.to_string(),
+            tools,
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Calls one of the server's tools, returning the text it gave back.
+    /// A result the server marks as an error is returned as one.
+    pub fn call_tool(
+        &self,
+        name: &str,
+        arguments: Value,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut connection = self.connection.lock().unwrap();
+        let result = connection.request(
+            "tools/call",
+            json!({ "name": name, "arguments": arguments }),
+            CALL_TIMEOUT,
+        )?;
+        let parts: Vec<String> = result["content"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|part| match part["type"].as_str() {
+                Some("text") => part["text"].as_str().unwrap_or("").into(),
+                Some("resource") => match part["resource"]["text"].as_str() {
+                    Some(text) => text.into(),
+                    None => "[binary resource]".into(),
+                },
+                Some(kind) => format!("[{} content]", kind),
+                None => String::new(),
+            })
+            .collect();
+        let text = parts.join("\n");
+        if result["isError"].as_bool().unwrap_or(false) {
+            return Err(text.into());
+        }
+        Ok(text)
+    }
+}
+
+/// Stops the server, whether it started or failed part way through.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Connection {
+    fn send(&mut self, message: &Value) -> io::Result<()> {
+        writeln!(self.stdin, "{}", message)?;
+        self.stdin.flush()
+    }
+
+    fn notify(&mut self, method: &str) -> io::Result<()> {
+        self.send(&json!({"jsonrpc": "2.0", "method": method}))
+    }
+
+    /// Sends a request and waits for its response. Requests the server
+    /// makes in the meantime are answered, pings with an empty result and
+    /// anything else as unsupported; its notifications are ignored.
+    fn request(
+        &mut self,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+    ) -> Result<Value, Box<dyn Error>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params
+        }))
+        .map_err(|e| format!("could not send {}: {}", method, e))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let line = self.lines.recv_timeout(wait).map_err(|e| match e {
+                RecvTimeoutError::Timeout => {
+                    format!("no response to {} after {:?}", method, timeout)
+                }
+                RecvTimeoutError::Disconnected => {
+                    format!("the server exited during {}", method)
+                }
+            })?;
+            let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                continue;
+            };
+            if let Some(request) = message["method"].as_str() {
+                if let Some(request_id) = message.get("id") {
+                    let reply = if request == "ping" {
+                        json!({"jsonrpc": "2.0", "id": request_id, "result": {}})
+                    } else {
+                        json!({
+                            "jsonrpc": "2.0",
+                            "id": request_id,
+                            "error": {
+                                "code": -32601,
+                                "message": "termgpt doesn't support this"
+                            }
+                        })
+                    };
+                    self.send(&reply)?;
+                }
+                continue;
+            }
+            if message["id"] != json!(id) {
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                let text = error["message"].as_str().unwrap_or("unknown error");
+                Err(format!("{} failed: {}", method, text))?
+            }
+            return Ok(message["result"].clone());
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A server that answers the handshake, lists one tool and answers one
+    /// call, as a script of canned lines.
+    fn fake_server(call_result: &str) -> McpServerConfig {
+        let script = format!(
+            r#"read line
+echo '{{"jsonrpc":"2.0","id":1,"result":{{"protocolVersion":"2024-11-05","capabilities":{{}},"serverInfo":{{"name":"fake","version":"1"}}}}}}'
+read line
+read line
+echo '{{"jsonrpc":"2.0","method":"notifications/message","params":{{}}}}'
+echo '{{"jsonrpc":"2.0","id":2,"result":{{"tools":[{{"name":"echo","description":"Echo it","inputSchema":{{"type":"object"}}}}]}}}}'
+read line
+echo '{{"jsonrpc":"2.0","id":3,"result":{}}}'
+"#,
+            call_result
+        );
+        McpServerConfig {
+            command: "sh".into(),
+            args: vec!["-c".into(), script],
+            env: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn tools_are_listed_and_called() {
+        let config =
+            fake_server(r#"{"content":[{"type":"text","text":"hi"}]}"#);
+        let server = McpServer::start("fake", &config, false).unwrap();
+        assert_eq!(server.tools.len(), 1);
+        assert_eq!(server.tools[0].name, "echo");
+        assert_eq!(server.tools[0].description, "Echo it");
+        let result = server.call_tool("echo", json!({})).unwrap();
+        assert_eq!(result, "hi");
+    }
+
+    #[test]
+    fn error_results_are_errors() {
+        let config = fake_server(
+            r#"{"content":[{"type":"text","text":"no such file"}],"isError":true}"#,
+        );
+        let server = McpServer::start("fake", &config, false).unwrap();
+        let err = server.call_tool("echo", json!({})).unwrap_err();
+        assert_eq!(err.to_string(), "no such file");
+    }
+
+    #[test]
+    fn servers_that_exit_fail_to_start() {
+        let config = McpServerConfig {
+            command: "sh".into(),
+            args: vec!["-c".into(), "read line".into()],
+            env: HashMap::new(),
+        };
+        let err = McpServer::start("gone", &config, false).err().unwrap();
+        assert_eq!(err.to_string(), "the server exited during initialize");
+    }
+}
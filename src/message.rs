@@ -0,0 +1,335 @@
+//! Chat messages and the conversation they make up.
+
+use crate::images::ImageAttachment;
+use crate::listener::{ChatMessageListener, Listeners};
+use crate::redact::{RedactMode, Redactor};
+use crate::session::read_session_messages;
+use clap::builder::PossibleValue;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+
+/// Who a message is from. Roles termgpt doesn't know, which some APIs
+/// send, are kept as they are rather than failing the whole response or
+/// session file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Role {
+    Assistant,
+    System,
+    User,
+    /// The result of a tool the assistant called.
+    Tool,
+    Other(String),
+}
+
+impl Role {
+    /// The role as the API and session files write it.
+    pub fn name(&self) -> &str {
+        match self {
+            Role::Assistant => "assistant",
+            Role::System => "system",
+            Role::User => "user",
+            Role::Tool => "tool",
+            Role::Other(name) => name,
+        }
+    }
+
+    /// The role as a heading.
+    pub fn title(&self) -> &str {
+        match self {
+            Role::Assistant => "Assistant",
+            Role::System => "System",
+            Role::User => "User",
+            Role::Tool => "Tool",
+            Role::Other(name) => name,
+        }
+    }
+}
+
+impl Serialize for Role {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Role {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(match name.as_str() {
+            "assistant" => Role::Assistant,
+            "system" => Role::System,
+            "user" => Role::User,
+            "tool" => Role::Tool,
+            _ => {
+                eprintln!("termgpt: warning: unknown message role {:?}", name);
+                Role::Other(name)
+            }
+        })
+    }
+}
+
+/// The roles that can be given on the command line.
+static ROLE_VALUES: [Role; 3] = [Role::Assistant, Role::System, Role::User];
+
+impl ValueEnum for Role {
+    fn value_variants<'a>() -> &'a [Self] {
+        &ROLE_VALUES
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Role::Assistant | Role::System | Role::User => {
+                Some(PossibleValue::new(self.name().to_string()))
+            }
+            Role::Tool | Role::Other(_) => None,
+        }
+    }
+}
+
+/// A message of a conversation, as kept in session files.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ChatGptMessage {
+    /// A stable identifier within the conversation, assigned when the
+    /// message is added. Older session files without ids get them on read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub role: Role,
+    /// Empty in an assistant message that only calls tools, which the API
+    /// sends as null.
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: String,
+    /// The tools an assistant message asks to have called.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a tool message is the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Images attached to a user message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageAttachment>,
+    /// Large context that stays the same from request to request, such as
+    /// the system prompt or prepended files, which backends that cache
+    /// prompts are told they may cache.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cacheable: bool,
+    /// The start of an assistant message that was sent with the request,
+    /// for the model to carry on from, rather than written by the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefill: Option<String>,
+}
+
+/// A call the model asks to have made to one of the tools it was offered.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a JSON object, which the model may have got wrong.
+    pub arguments: String,
+}
+
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+impl ChatGptMessage {
+    /// A message without an id, which it is given when added to a
+    /// conversation.
+    pub fn new(role: Role, content: String) -> ChatGptMessage {
+        ChatGptMessage {
+            id: None,
+            role,
+            content,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            cacheable: false,
+            prefill: None,
+        }
+    }
+
+    /// The result of a tool call, to send back to the model.
+    pub fn tool_result(call: &ToolCall, content: String) -> ChatGptMessage {
+        ChatGptMessage {
+            tool_call_id: Some(call.id.clone()),
+            ..ChatGptMessage::new(Role::Tool, content)
+        }
+    }
+}
+
+/// A conversation, which passes each message added to it on to its
+/// listeners.
+pub struct ChatMessages<'a> {
+    pub messages: Vec<ChatGptMessage>,
+    pub listeners: Listeners<'a>,
+    pub redactor: Option<Redactor>,
+}
+
+/// Gives each message without an id the next id after the ones before it,
+/// so files written before ids existed get the same ids on every read.
+pub fn assign_missing_ids(messages: &mut [ChatGptMessage]) {
+    let mut last_id = 0;
+    for message in messages.iter_mut() {
+        let id = message.id.unwrap_or(last_id + 1);
+        message.id = Some(id);
+        last_id = last_id.max(id);
+    }
+}
+
+impl<'a> ChatMessages<'a> {
+    pub fn new() -> ChatMessages<'a> {
+        ChatMessages {
+            messages: Vec::new(),
+            listeners: Listeners::new(),
+            redactor: None,
+        }
+    }
+
+    /// Starts with the messages of a session file, if it exists. Attached
+    /// images are read again, and any that can't be are warned about and
+    /// left out of requests.
+    pub fn from_file(filename: &str) -> io::Result<ChatMessages<'a>> {
+        let mut messages = read_session_messages(filename)?;
+        for image in messages.iter_mut().flat_map(|m| &mut m.images) {
+            if let Err(e) = image.reload() {
+                eprintln!("termgpt: leaving out an attached image: {}", e);
+            }
+        }
+        Ok(ChatMessages {
+            messages,
+            listeners: Listeners::new(),
+            redactor: None,
+        })
+    }
+
+    /// Adds a listener, named for what it writes to.
+    pub fn register<L: ChatMessageListener + 'a>(
+        &mut self,
+        name: impl Into<String>,
+        listener: L,
+    ) {
+        self.listeners.register(name, listener);
+    }
+
+    fn next_id(&self) -> u64 {
+        self.messages.iter().filter_map(|m| m.id).max().unwrap_or(0) + 1
+    }
+
+    /// Replaces secrets in what listeners are given, and with
+    /// `RedactMode::Both` in what is kept to send as well.
+    pub fn redact_with(&mut self, redactor: Redactor) {
+        self.redactor = Some(redactor);
+    }
+
+    /// With `RedactMode::Both`, replaces secrets in messages about to be
+    /// sent, and says on stderr what was replaced, so that they are sent
+    /// and saved only in their redacted form.
+    pub fn redact_outgoing(&self, messages: &mut [ChatGptMessage]) {
+        let Some(redactor) = self
+            .redactor
+            .as_ref()
+            .filter(|r| r.mode == RedactMode::Both)
+        else {
+            return;
+        };
+        let mut total = BTreeMap::new();
+        for message in messages {
+            let (content, counts) = redactor.redact_counting(&message.content);
+            if let Cow::Owned(content) = content {
+                message.content = content;
+            }
+            for (kind, count) in counts {
+                *total.entry(kind).or_insert(0) += count;
+            }
+        }
+        if !total.is_empty() {
+            let summary: Vec<String> = total
+                .iter()
+                .map(|(kind, count)| format!("{} {}", count, kind))
+                .collect();
+            eprintln!(
+                "termgpt: redacted {} before sending",
+                summary.join(", ")
+            );
+        }
+    }
+
+    /// Adds a message, giving it the next id, and passes it on to the
+    /// listeners.
+    pub fn push(
+        &mut self,
+        message: ChatGptMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert(self.messages.len(), self.next_id(), message)
+    }
+
+    /// Adds a message before the rest, as with a system prompt that the
+    /// session file doesn't keep. It is given id 0, which no kept message
+    /// has, so that the ids in the file carry on in sequence.
+    pub fn prepend(
+        &mut self,
+        message: ChatGptMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert(0, 0, message)
+    }
+
+    fn insert(
+        &mut self,
+        index: usize,
+        id: u64,
+        mut message: ChatGptMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        message.id = Some(id);
+        let redacted = self.redactor.as_ref().map(|redactor| {
+            let redacted = redactor.redact_message(&message);
+            if redactor.mode == RedactMode::Both {
+                message.content = redacted.content.clone();
+            }
+            redacted
+        });
+        let given = redacted.as_ref().unwrap_or(&message);
+        self.listeners
+            .notify(|listener| listener.on_message(given))?;
+        self.messages.insert(index, message);
+        Ok(())
+    }
+}
+
+impl Default for ChatMessages<'_> {
+    fn default() -> Self {
+        ChatMessages::new()
+    }
+}
+
+/// Parses messages in the JSON Lines format of session files.
+pub fn parse_jsonl_messages(
+    input: &str,
+) -> Result<Vec<ChatGptMessage>, Box<dyn Error>> {
+    let mut messages = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = serde_json::from_str(line).map_err(|e| {
+            format!("invalid message on line {}: {}", index + 1, e)
+        })?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
@@ -0,0 +1,68 @@
+use std::error::Error;
+use std::fmt;
+use termgpt::api::ChatGptClient;
+use termgpt::message::{ChatGptMessage, Role};
+
+/// The exit status when `--moderate` refuses to send a message.
+pub const FLAGGED_EXIT_CODE: i32 = 3;
+
+/// A message the moderation endpoint flagged, which is neither sent nor
+/// saved.
+#[derive(Debug)]
+pub struct Flagged {
+    pub categories: Vec<String>,
+}
+
+impl fmt::Display for Flagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.categories.is_empty() {
+            write!(f, "message not sent: flagged by moderation")
+        } else {
+            write!(
+                f,
+                "message not sent: flagged for {}",
+                self.categories.join(", ")
+            )
+        }
+    }
+}
+
+impl Error for Flagged {}
+
+/// Runs the user messages about to be sent past the moderation endpoint.
+/// If the check itself fails, that is only warned about, so that an outage
+/// of the moderation endpoint doesn't stop termgpt being used.
+pub async fn check(
+    client: &ChatGptClient,
+    model: &str,
+    messages: &[ChatGptMessage],
+) -> Result<(), Flagged> {
+    let input: Vec<&str> = messages
+        .iter()
+        .filter(|m| m.role == Role::User)
+        .map(|m| m.content.as_str())
+        .collect();
+    if input.is_empty() {
+        return Ok(());
+    }
+    match client.moderate(model, &input).await {
+        Ok(moderation) if moderation.flagged => Err(Flagged {
+            categories: moderation.categories,
+        }),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("termgpt: could not moderate message, sending it: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// `check`, for outside the REPL.
+#[tokio::main]
+pub async fn check_blocking(
+    client: &ChatGptClient,
+    model: &str,
+    messages: &[ChatGptMessage],
+) -> Result<(), Flagged> {
+    check(client, model, messages).await
+}
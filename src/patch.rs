@@ -0,0 +1,620 @@
+use crate::{ask_terminal, fenced_code_blocks};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// How many context lines at each end of a hunk may be ignored when it
+/// doesn't otherwise match, as with patch's `--fuzz`.
+const MAX_FUZZ: usize = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum LineKind {
+    Context,
+    Removed,
+    Added,
+}
+
+struct Hunk {
+    header: String,
+    /// The line the hunk starts at in the original file, counting from 1,
+    /// if the header gave one.
+    old_start: Option<usize>,
+    lines: Vec<(LineKind, String)>,
+}
+
+impl Hunk {
+    fn old_lines(&self, fuzz: usize) -> Vec<&str> {
+        self.trimmed(fuzz)
+            .iter()
+            .filter(|(kind, _)| *kind != LineKind::Added)
+            .map(|(_, line)| line.as_str())
+            .collect()
+    }
+
+    fn new_lines(&self, fuzz: usize) -> Vec<String> {
+        self.trimmed(fuzz)
+            .iter()
+            .filter(|(kind, _)| *kind != LineKind::Removed)
+            .map(|(_, line)| line.clone())
+            .collect()
+    }
+
+    /// The hunk without up to `fuzz` context lines at either end.
+    fn trimmed(&self, fuzz: usize) -> &[(LineKind, String)] {
+        let context = |lines: &mut dyn Iterator<Item = &(LineKind, String)>| {
+            lines
+                .take_while(|(kind, _)| *kind == LineKind::Context)
+                .count()
+        };
+        let leading = context(&mut self.lines.iter()).min(fuzz);
+        let trailing = context(&mut self.lines.iter().rev()).min(fuzz);
+        let end = self.lines.len().saturating_sub(trailing).max(leading);
+        &self.lines[leading..end]
+    }
+
+    /// How many of the fuzzed lines are leading context, which moves where
+    /// the hunk starts.
+    fn leading_trimmed(&self, fuzz: usize) -> usize {
+        self.lines
+            .iter()
+            .take_while(|(kind, _)| *kind == LineKind::Context)
+            .count()
+            .min(fuzz)
+    }
+}
+
+struct FilePatch {
+    /// The file patched, or `None` when it is being created.
+    old_path: Option<String>,
+    /// The file written, or `None` when it is being deleted.
+    new_path: Option<String>,
+    text: String,
+    hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    fn target(&self) -> &str {
+        self.new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap()
+    }
+}
+
+/// The path in a `---` or `+++` line, without any timestamp or the `a/` or
+/// `b/` prefix git adds.
+fn parse_path(line: &str, prefix: &str) -> Option<String> {
+    let path = line.split('\t').next().unwrap_or(line).trim();
+    if path == "/dev/null" {
+        return None;
+    }
+    Some(path.strip_prefix(prefix).unwrap_or(path).to_string())
+}
+
+/// The original start line from a header such as `@@ -12,7 +12,8 @@`.
+/// Models often leave the numbers out, in which case the hunk is searched
+/// for from the end of the last one.
+fn parse_hunk_start(header: &str) -> Option<usize> {
+    let range = header.strip_prefix("@@ -")?.split_whitespace().next()?;
+    let (start, len) = range.split_once(',').unwrap_or((range, "1"));
+    let start: usize = start.parse().ok()?;
+    // A hunk that only adds lines names the line it follows.
+    Some(if len == "0" { start + 1 } else { start })
+}
+
+fn is_file_header(lines: &[&str], i: usize) -> bool {
+    lines[i].starts_with("--- ")
+        && lines.get(i + 1).is_some_and(|l| l.starts_with("+++ "))
+}
+
+/// Parses the unified diffs in some text, ignoring anything around them.
+fn parse_diff(text: &str) -> Vec<FilePatch> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut patches: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if is_file_header(&lines, i) {
+            patches.push(FilePatch {
+                old_path: parse_path(&lines[i][4..], "a/"),
+                new_path: parse_path(&lines[i + 1][4..], "b/"),
+                text: format!("{}\n{}\n", lines[i], lines[i + 1]),
+                hunks: Vec::new(),
+            });
+            i += 2;
+            continue;
+        }
+        let Some(patch) = patches.last_mut() else {
+            i += 1;
+            continue;
+        };
+        if !lines[i].starts_with("@@") {
+            i += 1;
+            continue;
+        }
+        let mut hunk = Hunk {
+            header: lines[i].to_string(),
+            old_start: parse_hunk_start(lines[i]),
+            lines: Vec::new(),
+        };
+        patch.text.push_str(lines[i]);
+        patch.text.push('\n');
+        i += 1;
+        while i < lines.len()
+            && !lines[i].starts_with("@@")
+            && !is_file_header(&lines, i)
+        {
+            let line = lines[i];
+            let (kind, content) = match line.chars().next() {
+                Some(' ') => (LineKind::Context, &line[1..]),
+                Some('-') => (LineKind::Removed, &line[1..]),
+                Some('+') => (LineKind::Added, &line[1..]),
+                Some('\\') => {
+                    i += 1;
+                    continue;
+                }
+                // Blank context lines often lose their leading space.
+                None => (LineKind::Context, ""),
+                Some(_) => break,
+            };
+            hunk.lines.push((kind, content.to_string()));
+            patch.text.push_str(line);
+            patch.text.push('\n');
+            i += 1;
+        }
+        while hunk.lines.last().is_some_and(|(kind, line)| {
+            *kind == LineKind::Context && line.is_empty()
+        }) {
+            hunk.lines.pop();
+        }
+        patch.hunks.push(hunk);
+    }
+    patches.retain(|patch| {
+        !patch.hunks.is_empty()
+            && (patch.old_path.is_some() || patch.new_path.is_some())
+    });
+    patches
+}
+
+/// The diffs in each fenced code block of a response that holds one.
+fn response_patches(markdown: &str) -> Vec<FilePatch> {
+    fenced_code_blocks(markdown)
+        .iter()
+        .flat_map(|(_, code)| parse_diff(code))
+        .collect()
+}
+
+/// Finds where `old` appears in `lines` at or after `min`, nearest to
+/// `expected`, first exactly and then ignoring trailing whitespace.
+fn find_hunk(
+    lines: &[String],
+    old: &[&str],
+    expected: usize,
+    min: usize,
+) -> Option<usize> {
+    if old.len() > lines.len() {
+        return None;
+    }
+    let last = lines.len() - old.len();
+    let expected = expected.clamp(min, last.max(min));
+    let matches_at = |pos: usize, exact: bool| {
+        lines[pos..pos + old.len()].iter().zip(old).all(|(a, b)| {
+            if exact {
+                a == b
+            } else {
+                a.trim_end() == b.trim_end()
+            }
+        })
+    };
+    for exact in [true, false] {
+        for distance in 0..=lines.len() {
+            let candidates = [
+                expected.checked_add(distance),
+                expected.checked_sub(distance).filter(|_| distance > 0),
+            ];
+            for pos in candidates.into_iter().flatten() {
+                if pos >= min && pos <= last && matches_at(pos, exact) {
+                    return Some(pos);
+                }
+            }
+            if expected + distance > last && expected < distance + min {
+                break;
+            }
+        }
+    }
+    None
+}
+
+struct Applied {
+    text: String,
+    notes: Vec<String>,
+}
+
+/// Applies the hunks to a file's contents, or explains which don't fit.
+fn apply_hunks(original: &str, patch: &FilePatch) -> Result<Applied, String> {
+    let mut lines: Vec<String> = original.lines().map(String::from).collect();
+    let mut notes = Vec::new();
+    // Where the next hunk may start, and how far hunks were found from
+    // where their headers said, to adjust where the next is looked for.
+    let mut min = 0;
+    let mut shift: isize = 0;
+    // How many lines earlier hunks added, for reporting offsets as patch
+    // does.
+    let mut added: isize = 0;
+    for (n, hunk) in patch.hunks.iter().enumerate() {
+        let expected = hunk
+            .old_start
+            .map(|start| (start.saturating_sub(1) as isize + shift).max(0))
+            .map_or(min, |start| start as usize);
+        let found = (0..=MAX_FUZZ).find_map(|fuzz| {
+            let old = hunk.old_lines(fuzz);
+            if old.is_empty() && fuzz > 0 {
+                return None;
+            }
+            let skipped = hunk.leading_trimmed(fuzz);
+            find_hunk(&lines, &old, expected + skipped, min)
+                .map(|pos| (pos, fuzz, old.len()))
+        });
+        let Some((pos, fuzz, old_len)) = found else {
+            return Err(format!(
+                "hunk {} ({}) does not apply: its context was not found",
+                n + 1,
+                hunk.header
+            ));
+        };
+        let new = hunk.new_lines(fuzz);
+        let start = pos.saturating_sub(hunk.leading_trimmed(fuzz));
+        let offset = hunk.old_start.map_or(0, |old_start| {
+            start as isize + 1 - old_start as isize - added
+        });
+        if offset != 0 || fuzz > 0 {
+            notes.push(format!(
+                "hunk {} applied at line {} (offset {}, fuzz {})",
+                n + 1,
+                start + 1,
+                offset,
+                fuzz
+            ));
+        }
+        if let Some(old_start) = hunk.old_start {
+            shift = start as isize - old_start.saturating_sub(1) as isize
+                + new.len() as isize
+                - old_len as isize;
+        }
+        min = pos + new.len();
+        added += new.len() as isize - old_len as isize;
+        lines.splice(pos..pos + old_len, new);
+    }
+    let mut text = lines.join("\n");
+    if !lines.is_empty() && (original.ends_with('\n') || original.is_empty()) {
+        text.push('\n');
+    }
+    Ok(Applied { text, notes })
+}
+
+/// Refuses paths that would reach outside the working directory, whether
+/// by name or through a symlink.
+fn checked_path(path: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    let refused = || {
+        format!(
+            "refusing to patch {} outside this directory",
+            path.display()
+        )
+    };
+    let inside = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !inside {
+        Err(refused())?
+    }
+    let root = env::current_dir()
+        .and_then(|dir| dir.canonicalize())
+        .map_err(|e| e.to_string())?;
+    let joined = root.join(path);
+    let existing = joined
+        .ancestors()
+        .find(|p| p.symlink_metadata().is_ok())
+        .unwrap_or(&root);
+    let resolved = existing
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !resolved.starts_with(&root) {
+        Err(refused())?
+    }
+    Ok(path.to_path_buf())
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".orig");
+    PathBuf::from(backup)
+}
+
+fn print_diff(text: &str, styled: bool) {
+    for line in text.lines() {
+        let color = match line.chars().next() {
+            _ if !styled => None,
+            _ if line.starts_with("---") || line.starts_with("+++") => {
+                Some("1")
+            }
+            Some('@') => Some("36"),
+            Some('-') => Some("31"),
+            Some('+') => Some("32"),
+            _ => None,
+        };
+        match color {
+            Some(color) => eprintln!("\x1b[{}m{}\x1b[0m", color, line),
+            None => eprintln!("{}", line),
+        }
+    }
+}
+
+enum Change {
+    Write(PathBuf, String),
+    Delete(PathBuf),
+}
+
+/// Works out what a file's patch would do, without touching anything.
+fn plan(patch: &FilePatch) -> Result<(Change, Vec<String>), String> {
+    let target = checked_path(patch.target())?;
+    let original = match &patch.old_path {
+        Some(old) => {
+            let old = checked_path(old)?;
+            fs::read_to_string(&old).map_err(|e| {
+                format!("could not read {}: {}", old.display(), e)
+            })?
+        }
+        None if target.exists() => {
+            Err(format!("{} already exists", target.display()))?
+        }
+        None => String::new(),
+    };
+    let applied = apply_hunks(&original, patch)?;
+    let change = match patch.new_path {
+        Some(_) => Change::Write(target, applied.text),
+        None => Change::Delete(target),
+    };
+    Ok((change, applied.notes))
+}
+
+/// Backs up the file as `FILE.orig` and makes the change.
+fn make_change(change: &Change) -> io::Result<()> {
+    match change {
+        Change::Write(path, text) => {
+            if path.exists() {
+                fs::copy(path, backup_path(path))?;
+            } else if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, text)
+        }
+        Change::Delete(path) => fs::rename(path, backup_path(path)),
+    }
+}
+
+/// Offers to apply each diff in a response to the file it names, asking
+/// about each file in turn. Files whose hunks don't all apply are left
+/// alone. With `dry_run`, only reports what would change.
+pub fn apply_response(
+    markdown: &str,
+    dry_run: bool,
+    styled: bool,
+) -> Result<(), Box<dyn Error>> {
+    let patches = response_patches(markdown);
+    if patches.is_empty() {
+        Err("the response has no diffs to apply")?
+    }
+    let mut rejected = 0;
+    for patch in &patches {
+        let target = patch.target();
+        let (change, notes) = match plan(patch) {
+            Ok(plan) => plan,
+            Err(e) => {
+                eprintln!("{}: {}", target, e);
+                rejected += 1;
+                continue;
+            }
+        };
+        let verb = match (&change, &patch.old_path) {
+            (Change::Delete(_), _) => "delete",
+            (Change::Write(..), None) => "create",
+            (Change::Write(..), Some(_)) => "patch",
+        };
+        print_diff(&patch.text, styled);
+        for note in &notes {
+            eprintln!("{}", note);
+        }
+        if dry_run {
+            eprintln!("would {} {}", verb, target);
+            continue;
+        }
+        let question = format!("{} {}?", capitalize(verb), target);
+        match ask_terminal(&question)? {
+            Some(true) => {
+                make_change(&change)?;
+                match (&change, &patch.old_path) {
+                    (Change::Write(..), None) => {
+                        eprintln!("created {}", target)
+                    }
+                    _ => eprintln!(
+                        "{} {}; the original is in {}.orig",
+                        if verb == "delete" {
+                            "deleted"
+                        } else {
+                            "patched"
+                        },
+                        target,
+                        target
+                    ),
+                }
+            }
+            Some(false) => eprintln!("skipped {}", target),
+            None => Err("there is no terminal to confirm changes on")?,
+        }
+    }
+    if rejected > 0 {
+        Err(format!(
+            "{} of {} files could not be patched",
+            rejected,
+            patches.len()
+        ))?
+    }
+    Ok(())
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .map(|c| c.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch(diff: &str) -> FilePatch {
+        let mut patches = parse_diff(diff);
+        assert_eq!(patches.len(), 1);
+        patches.pop().unwrap()
+    }
+
+    fn apply(original: &str, diff: &str) -> Result<Applied, String> {
+        apply_hunks(original, &patch(diff))
+    }
+
+    #[test]
+    fn context_that_does_not_match_at_the_ends_is_fuzzed() {
+        let applied = apply(
+            "one\ntwo\nthree\nfour\nfive\n",
+            "--- a/f\n+++ b/f\n@@ -1,5 +1,5 @@\n \
+             ONE\n two\n-three\n+3\n four\n FIVE\n",
+        )
+        .unwrap();
+        assert_eq!(applied.text, "one\ntwo\n3\nfour\nfive\n");
+        assert_eq!(
+            applied.notes,
+            ["hunk 1 applied at line 1 (offset 0, fuzz 1)"]
+        );
+    }
+
+    #[test]
+    fn offsets_carry_over_from_one_hunk_to_the_next() {
+        let diff = "--- a/f\n+++ b/f\n\
+                    @@ -1,2 +1,3 @@\n a\n+a2\n b\n\
+                    @@ -6,2 +7,2 @@\n f\n-g\n+G\n";
+        let applied = apply("a\nb\nc\nd\ne\nf\ng\nh\n", diff).unwrap();
+        assert_eq!(applied.text, "a\na2\nb\nc\nd\ne\nf\nG\nh\n");
+        assert!(applied.notes.is_empty());
+
+        let applied = apply("x\na\nb\nc\nd\ne\nf\ng\nh\n", diff).unwrap();
+        assert_eq!(applied.text, "x\na\na2\nb\nc\nd\ne\nf\nG\nh\n");
+        assert_eq!(
+            applied.notes,
+            [
+                "hunk 1 applied at line 2 (offset 1, fuzz 0)",
+                "hunk 2 applied at line 8 (offset 1, fuzz 0)",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_hunk_that_only_adds_goes_after_the_line_it_names() {
+        let applied =
+            apply("a\nb\nc\n", "--- a/f\n+++ b/f\n@@ -2,0 +3 @@\n+new\n")
+                .unwrap();
+        assert_eq!(applied.text, "a\nb\nnew\nc\n");
+    }
+
+    #[test]
+    fn files_can_be_created_and_deleted() {
+        let created = patch(
+            "--- /dev/null\n+++ b/src/new.rs\n\
+             @@ -0,0 +1,2 @@\n+fn a() {}\n+fn b() {}\n",
+        );
+        assert_eq!(created.old_path, None);
+        assert_eq!(created.target(), "src/new.rs");
+        let applied = apply_hunks("", &created).unwrap();
+        assert_eq!(applied.text, "fn a() {}\nfn b() {}\n");
+
+        let deleted =
+            patch("--- a/old.txt\n+++ /dev/null\n@@ -1,2 +0,0 @@\n-x\n-y\n");
+        assert_eq!(deleted.new_path, None);
+        assert_eq!(deleted.target(), "old.txt");
+        assert_eq!(apply_hunks("x\ny\n", &deleted).unwrap().text, "");
+
+        let existing =
+            patch("--- /dev/null\n+++ b/Cargo.toml\n@@ -0,0 +1 @@\n+x\n");
+        assert_eq!(plan(&existing).err().unwrap(), "Cargo.toml already exists");
+    }
+
+    #[test]
+    fn diffs_of_no_file_are_ignored() {
+        let diff = "--- /dev/null\n+++ /dev/null\n@@ -0,0 +1 @@\n+x\n";
+        assert!(parse_diff(diff).is_empty());
+    }
+
+    #[test]
+    fn paths_outside_the_directory_are_refused() {
+        assert!(checked_path("src/main.rs").is_ok());
+        assert!(checked_path("./src/main.rs").is_ok());
+        assert!(checked_path("/etc/passwd").is_err());
+        assert!(checked_path("src/../../secret").is_err());
+        let outside =
+            patch("--- a/../secret\n+++ b/../secret\n@@ -1 +1 @@\n-a\n+b\n");
+        assert_eq!(
+            plan(&outside).err().unwrap(),
+            "refusing to patch ../secret outside this directory"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_through_symlinks_outside_the_directory_are_refused() {
+        let dir = tempfile::tempdir_in(".").unwrap();
+        std::os::unix::fs::symlink("/etc", dir.path().join("docs")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.path().join("passwd"))
+            .unwrap();
+        let name = dir.path().file_name().unwrap();
+        let path =
+            |file: &str| Path::new(name).join(file).display().to_string();
+        assert!(checked_path(&path("docs/passwd")).is_err());
+        assert!(checked_path(&path("docs/new/file")).is_err());
+        assert!(checked_path(&path("passwd")).is_err());
+        assert!(checked_path(&path("new/file")).is_ok());
+    }
+
+    #[test]
+    fn blank_lines_after_a_hunk_are_not_taken_as_context() {
+        let patch =
+            patch("--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n a\n-b\n+B\n\n\n\n");
+        assert_eq!(patch.hunks[0].lines.len(), 3);
+        let applied = apply_hunks("a\nb\n", &patch).unwrap();
+        assert_eq!(applied.text, "a\nB\n");
+        assert!(applied.notes.is_empty());
+    }
+
+    #[test]
+    fn a_hunk_that_does_not_apply_leaves_the_file_alone() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n a\n-b\n+B\n";
+        assert_eq!(
+            apply("x\ny\n", diff).err().unwrap(),
+            "hunk 1 (@@ -1,2 +1,2 @@) does not apply: its context was not found"
+        );
+
+        let dir = tempfile::tempdir_in(".").unwrap();
+        let file = Path::new(dir.path().file_name().unwrap()).join("f.txt");
+        fs::write(&file, "x\ny\n").unwrap();
+        let name = file.to_str().unwrap();
+        let markdown = format!(
+            "```diff\n--- a/{}\n+++ b/{}\n@@ -1,2 +1,2 @@\n a\n-b\n+B\n```\n",
+            name, name
+        );
+        assert!(apply_response(&markdown, false, false).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "x\ny\n");
+        assert!(!backup_path(&file).exists());
+    }
+}
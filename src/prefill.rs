@@ -0,0 +1,156 @@
+use std::error::Error;
+use termgpt::api::{ChatApi, ChatGptParams, ChatGptResponse};
+use termgpt::message::{ChatGptMessage, Role};
+
+/// Sends requests to `api` ending with an assistant message that begins the
+/// response, for --prefill and `/prefill`, and puts it back at the start of
+/// each answer so that it reads as the model's own.
+pub struct Prefilled<'a, A> {
+    pub api: &'a A,
+    pub prefill: Option<&'a str>,
+}
+
+fn with_prefill(
+    messages: &[ChatGptMessage],
+    prefill: &str,
+) -> Vec<ChatGptMessage> {
+    let mut messages = messages.to_vec();
+    messages.push(ChatGptMessage {
+        prefill: Some(prefill.to_string()),
+        ..ChatGptMessage::new(Role::Assistant, prefill.to_string())
+    });
+    messages
+}
+
+/// Joins the prefill and each answer, marking the answers with it so a
+/// session shows where the model took over.
+fn prefill_answers(response: &mut ChatGptResponse, prefill: &str) {
+    for choice in &mut response.choices {
+        let message = &mut choice.message;
+        message.content.insert_str(0, prefill);
+        message.prefill = Some(prefill.to_string());
+    }
+}
+
+impl<A: ChatApi> ChatApi for Prefilled<'_, A> {
+    async fn get_chatgpt_response(
+        &self,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+    ) -> Result<ChatGptResponse, Box<dyn Error>> {
+        let Some(prefill) = self.prefill else {
+            return self.api.get_chatgpt_response(params, messages).await;
+        };
+        let messages = with_prefill(messages, prefill);
+        let mut response =
+            self.api.get_chatgpt_response(params, &messages).await?;
+        prefill_answers(&mut response, prefill);
+        Ok(response)
+    }
+
+    async fn stream_chatgpt_response<F>(
+        &self,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+        include_usage: bool,
+        mut on_chunk: F,
+    ) -> Result<ChatGptResponse, Box<dyn Error>>
+    where
+        F: FnMut(&str) -> Result<(), Box<dyn Error>>,
+    {
+        let Some(prefill) = self.prefill else {
+            return self
+                .api
+                .stream_chatgpt_response(
+                    params,
+                    messages,
+                    include_usage,
+                    on_chunk,
+                )
+                .await;
+        };
+        let messages = with_prefill(messages, prefill);
+        // The prefill goes out with the first of the answer, so that it
+        // isn't counted as having arrived before it.
+        let mut unsent = Some(prefill);
+        let chunk = |text: &str| match unsent.take() {
+            Some(prefill) => on_chunk(&format!("{}{}", prefill, text)),
+            None => on_chunk(text),
+        };
+        let mut response = self
+            .api
+            .stream_chatgpt_response(params, &messages, include_usage, chunk)
+            .await?;
+        if let Some(prefill) = unsent {
+            on_chunk(prefill)?;
+        }
+        prefill_answers(&mut response, prefill);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termgpt::api::ChatGptChoice;
+
+    /// Answers with what the last message sent was, to show it was sent.
+    struct Echo;
+
+    impl ChatApi for Echo {
+        async fn get_chatgpt_response(
+            &self,
+            _: &ChatGptParams,
+            messages: &[ChatGptMessage],
+        ) -> Result<ChatGptResponse, Box<dyn Error>> {
+            let last = messages.last().unwrap();
+            let content = format!("\"{:?}\"}}", last.role);
+            Ok(ChatGptResponse {
+                model: None,
+                choices: vec![ChatGptChoice {
+                    message: ChatGptMessage::new(Role::Assistant, content),
+                    finish_reason: None,
+                }],
+                usage: None,
+                rate_limits: None,
+            })
+        }
+
+        async fn stream_chatgpt_response<F>(
+            &self,
+            params: &ChatGptParams,
+            messages: &[ChatGptMessage],
+            _: bool,
+            mut on_chunk: F,
+        ) -> Result<ChatGptResponse, Box<dyn Error>>
+        where
+            F: FnMut(&str) -> Result<(), Box<dyn Error>>,
+        {
+            let response = self.get_chatgpt_response(params, messages).await?;
+            on_chunk(&response.choices[0].message.content)?;
+            Ok(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_carry_on_from_the_prefill() {
+        let api = Prefilled {
+            api: &Echo,
+            prefill: Some("{\"role\": "),
+        };
+        let params = ChatGptParams::default();
+        let messages = [ChatGptMessage::new(Role::User, "JSON?".into())];
+        let mut streamed = String::new();
+        let response = api
+            .stream_chatgpt_response(&params, &messages, false, |text| {
+                streamed.push_str(text);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        let message = &response.choices[0].message;
+        assert_eq!(message.content, "{\"role\": \"Assistant\"}");
+        assert_eq!(streamed, message.content);
+        assert_eq!(message.prefill.as_deref(), Some("{\"role\": "));
+    }
+}
@@ -0,0 +1,72 @@
+use crate::check_context_size;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+/// Reads the files named with --prepend-file and matched by
+/// --prepend-glob into one message, each under its path in a code fence.
+/// Files that aren't UTF-8 text are skipped, and there must be no more
+/// than `max_files` of them, or `max_bytes` between them.
+pub fn read_prepended(
+    files: &[String],
+    globs: &[String],
+    max_files: usize,
+    max_bytes: usize,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let mut paths: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+    for pattern in globs {
+        let matches = glob::glob(pattern)
+            .map_err(|e| format!("invalid glob `{}`: {}", pattern, e))?;
+        let before = paths.len();
+        for path in matches {
+            let path = path?;
+            if path.is_file() && !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        if paths.len() == before {
+            eprintln!("termgpt: no files match {}", pattern);
+        }
+    }
+    if paths.len() > max_files {
+        Err(format!(
+            "{} files to prepend is more than {} \
+             (use --max-context-files to raise it)",
+            paths.len(),
+            max_files
+        ))?
+    }
+
+    let mut sections = Vec::new();
+    let mut total = 0;
+    for path in paths {
+        let bytes = fs::read(&path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let text = match String::from_utf8(bytes) {
+            Ok(text) if !text.contains('\0') => text,
+            _ => {
+                eprintln!(
+                    "termgpt: skipping {}: not UTF-8 text",
+                    path.display()
+                );
+                continue;
+            }
+        };
+        total += text.len();
+        check_context_size("prepended context", total, max_bytes)?;
+        let language = path.extension().and_then(|e| e.to_str());
+        sections.push(format!(
+            "{}:\n\n{}",
+            path.display(),
+            fence(&text, language.unwrap_or(""))
+        ));
+    }
+    Ok((!sections.is_empty()).then(|| sections.join("\n\n")))
+}
+
+/// Puts text in a code fence longer than any run of backticks inside it.
+pub fn fence(text: &str, language: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{}{}\n{}\n{}", fence, language, text.trim_end(), fence)
+}
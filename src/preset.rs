@@ -0,0 +1,96 @@
+use crate::config::{config_dir, read_toml};
+use clap::Subcommand;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use termgpt::api::ReasoningEffort;
+
+/// A reusable set of instructions, from a `[presets.NAME]` config table or a
+/// `NAME.toml` file in the presets directory.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preset {
+    pub description: Option<String>,
+    pub system: Option<String>,
+    /// User message template, with `{{arg}}` for the prompt argument and
+    /// `{{stdin}}` for piped input.
+    pub template: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+#[derive(Subcommand)]
+pub enum PresetsCommand {
+    /// List the available presets
+    List,
+}
+
+pub fn presets_dir() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("presets"))
+}
+
+/// Loads presets from the config table, overridden by any preset files.
+pub fn load_presets(
+    configured: &HashMap<String, Preset>,
+) -> Result<BTreeMap<String, Preset>, Box<dyn Error>> {
+    let mut presets: BTreeMap<_, _> = configured.clone().into_iter().collect();
+    let dir = match presets_dir() {
+        Some(dir) => dir,
+        None => return Ok(presets),
+    };
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(presets),
+        Err(e) => Err(format!("could not read {}: {}", dir.display(), e))?,
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some("toml".as_ref()) {
+            continue;
+        }
+        if let (Some(name), Some(preset)) =
+            (path.file_stem().and_then(|s| s.to_str()), read_toml(&path)?)
+        {
+            presets.insert(name.to_string(), preset);
+        }
+    }
+    Ok(presets)
+}
+
+pub fn find_preset(
+    presets: &BTreeMap<String, Preset>,
+    name: &str,
+) -> Result<Preset, Box<dyn Error>> {
+    match presets.get(name) {
+        Some(preset) => Ok(preset.clone()),
+        None => Err(format!(
+            "unknown preset `{}` (see `termgpt presets list`)",
+            name
+        ))?,
+    }
+}
+
+pub fn run_presets_command(
+    command: &PresetsCommand,
+    presets: &BTreeMap<String, Preset>,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        PresetsCommand::List => {
+            let width = presets.keys().map(String::len).max().unwrap_or(0);
+            for (name, preset) in presets {
+                let summary = preset
+                    .description
+                    .as_deref()
+                    .or(preset.system.as_deref())
+                    .and_then(|text| text.lines().next())
+                    .unwrap_or("");
+                println!("{:width$}  {}", name, summary, width = width);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use reedline::{
+    Color, Prompt, PromptEditMode, PromptHistorySearch,
+    PromptHistorySearchStatus,
+};
+use std::borrow::Cow;
+use termimad::crossterm::style::Color as TermColor;
+
+/// Reedline's own indicator, used unless another is configured.
+pub const DEFAULT_INDICATOR: &str = "〉";
+
+/// The REPL prompt: an indicator in which `{model}` and `{turn}` are
+/// replaced by the model and the number of the turn being typed.
+#[derive(Clone)]
+pub struct ReplPrompt {
+    indicator: String,
+    color: Color,
+    pub model: String,
+    pub turn: usize,
+}
+
+impl ReplPrompt {
+    pub fn new(indicator: &str, color: Option<TermColor>) -> ReplPrompt {
+        ReplPrompt {
+            indicator: indicator.to_string(),
+            color: color.map_or(Color::Cyan, reedline_color),
+            model: String::new(),
+            turn: 1,
+        }
+    }
+}
+
+/// Converts a color parsed for termimad to reedline's crossterm version.
+fn reedline_color(color: TermColor) -> Color {
+    match color {
+        TermColor::Reset => Color::Reset,
+        TermColor::Black => Color::Black,
+        TermColor::DarkGrey => Color::DarkGrey,
+        TermColor::Red => Color::Red,
+        TermColor::DarkRed => Color::DarkRed,
+        TermColor::Green => Color::Green,
+        TermColor::DarkGreen => Color::DarkGreen,
+        TermColor::Yellow => Color::Yellow,
+        TermColor::DarkYellow => Color::DarkYellow,
+        TermColor::Blue => Color::Blue,
+        TermColor::DarkBlue => Color::DarkBlue,
+        TermColor::Magenta => Color::Magenta,
+        TermColor::DarkMagenta => Color::DarkMagenta,
+        TermColor::Cyan => Color::Cyan,
+        TermColor::DarkCyan => Color::DarkCyan,
+        TermColor::White => Color::White,
+        TermColor::Grey => Color::Grey,
+        TermColor::Rgb { r, g, b } => Color::Rgb { r, g, b },
+        TermColor::AnsiValue(value) => Color::AnsiValue(value),
+    }
+}
+
+impl Prompt for ReplPrompt {
+    fn render_prompt_left(&self) -> Cow<'_, str> {
+        Cow::Borrowed("")
+    }
+
+    fn render_prompt_right(&self) -> Cow<'_, str> {
+        Cow::Borrowed("")
+    }
+
+    fn render_prompt_indicator(&self, _mode: PromptEditMode) -> Cow<'_, str> {
+        Cow::Owned(
+            self.indicator
+                .replace("{model}", &self.model)
+                .replace("{turn}", &self.turn.to_string()),
+        )
+    }
+
+    fn render_prompt_multiline_indicator(&self) -> Cow<'_, str> {
+        Cow::Borrowed("::: ")
+    }
+
+    fn render_prompt_history_search_indicator(
+        &self,
+        search: PromptHistorySearch,
+    ) -> Cow<'_, str> {
+        let prefix = match search.status {
+            PromptHistorySearchStatus::Passing => "",
+            PromptHistorySearchStatus::Failing => "failing ",
+        };
+        Cow::Owned(format!("({}reverse-search: {}) ", prefix, search.term))
+    }
+
+    fn get_indicator_color(&self) -> Color {
+        self.color
+    }
+}
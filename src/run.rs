@@ -0,0 +1,196 @@
+use crate::{ask_terminal, fenced_code_blocks};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::io::{Read, Write};
+use std::process::{self, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use termgpt::message::{ChatGptMessage, Role};
+use termimad::crossterm::style::Stylize;
+
+/// Interpreters for common fence languages, which the `interpreters` table
+/// in the config file can add to or override.
+const DEFAULT_INTERPRETERS: &[(&str, &str)] = &[
+    ("sh", "sh"),
+    ("shell", "sh"),
+    ("bash", "bash"),
+    ("zsh", "zsh"),
+    ("fish", "fish"),
+    ("python", "python3"),
+    ("py", "python3"),
+    ("python3", "python3"),
+    ("ruby", "ruby"),
+    ("rb", "ruby"),
+    ("perl", "perl"),
+    ("javascript", "node"),
+    ("js", "node"),
+    ("node", "node"),
+    ("lua", "lua"),
+];
+
+/// The most output added to the conversation, so that a noisy program
+/// doesn't blow up the context.
+const MAX_OUTPUT_BYTES: usize = 8000;
+
+fn interpreter(
+    lang: &str,
+    config: &HashMap<String, String>,
+) -> Result<String, String> {
+    if lang.is_empty() {
+        Err(
+            "the code block has no language, so there is no telling how \
+             to run it",
+        )?
+    }
+    let lang = lang.to_ascii_lowercase();
+    config
+        .get(&lang)
+        .cloned()
+        .or_else(|| {
+            DEFAULT_INTERPRETERS
+                .iter()
+                .find(|(name, _)| *name == lang)
+                .map(|(_, command)| command.to_string())
+        })
+        .ok_or_else(|| {
+            format!(
+                "no interpreter for `{}` code; add one under [interpreters] \
+                 in the config file, such as {} = \"{}\"",
+                lang, lang, lang
+            )
+        })
+}
+
+/// Copies a child's output to ours as it arrives, keeping a copy.
+fn tee(
+    mut from: impl Read + Send + 'static,
+    mut to: impl Write + Send + 'static,
+    captured: Arc<Mutex<Vec<u8>>>,
+) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            let n = from.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            to.write_all(&buf[..n])?;
+            to.flush()?;
+            captured.lock().unwrap().extend_from_slice(&buf[..n]);
+        }
+    })
+}
+
+/// Runs the code through the interpreter from a temporary file, returning
+/// what it printed and how it exited.
+fn execute(
+    command: &str,
+    lang: &str,
+    code: &str,
+) -> Result<(String, String), Box<dyn Error>> {
+    // A file of our own, created afresh and readable only by us, so that
+    // nobody else can swap in other code between the confirmation and the
+    // interpreter reading it. It is deleted when `path` is dropped.
+    let mut file = tempfile::Builder::new()
+        .prefix("termgpt-run-")
+        .suffix(&format!(".{}", lang))
+        .tempfile()?;
+    file.write_all(code.as_bytes())?;
+    let path = file.into_temp_path();
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or("the interpreter is empty")?;
+    let child = process::Command::new(program)
+        .args(words)
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => Err(format!("could not run {}: {}", program, e))?,
+    };
+
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let stdout =
+        tee(child.stdout.take().unwrap(), io::stdout(), captured.clone());
+    let stderr =
+        tee(child.stderr.take().unwrap(), io::stderr(), captured.clone());
+    let status = child.wait();
+    let _ = stdout.join();
+    let _ = stderr.join();
+    drop(path);
+
+    let outcome = match status?.code() {
+        Some(code) => format!("exited with status {}", code),
+        None => "was killed by a signal".to_string(),
+    };
+    let output =
+        String::from_utf8_lossy(&captured.lock().unwrap()).into_owned();
+    Ok((output, outcome))
+}
+
+/// The message telling the model what running its code printed.
+fn output_message(output: &str, outcome: &str) -> String {
+    let mut end = output.len().min(MAX_OUTPUT_BYTES);
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    let truncated = if end < output.len() {
+        "\n[output truncated]"
+    } else {
+        ""
+    };
+    format!(
+        "Output of running the code:\n\n```\n{}{}\n```\n\nIt {}.",
+        output[..end].trim_end(),
+        truncated,
+        outcome
+    )
+}
+
+/// Offers to run the last code block of the latest response, showing it
+/// and the interpreter first. Nothing runs without an explicit yes. If
+/// `offer_append` is set, then afterwards asks whether to add the output
+/// to the conversation, and returns the message to add.
+pub fn run_last_block(
+    messages: &[ChatGptMessage],
+    interpreters: &HashMap<String, String>,
+    offer_append: bool,
+    styled: bool,
+) -> Result<Option<ChatGptMessage>, Box<dyn Error>> {
+    let last = messages
+        .iter()
+        .rev()
+        .find(|m| m.role == Role::Assistant)
+        .ok_or("there is no response to run code from yet")?;
+    let (lang, code) = fenced_code_blocks(&last.content)
+        .pop()
+        .ok_or("the last response has no code blocks")?;
+    let command = interpreter(&lang, interpreters)?;
+
+    eprintln!("This {} code will be run with `{}`:\n", lang, command);
+    for line in code.lines() {
+        if styled {
+            eprintln!("    {}", line.bold());
+        } else {
+            eprintln!("    {}", line);
+        }
+    }
+    eprintln!();
+    match ask_terminal("Run it?")? {
+        Some(true) => {}
+        Some(false) => return Ok(None),
+        None => Err("there is no terminal to confirm running the code on")?,
+    }
+
+    let (output, outcome) = execute(&command, &lang, &code)?;
+    eprintln!("termgpt: code {}", outcome);
+    if offer_append
+        && ask_terminal("Add the output to the conversation?")? == Some(true)
+    {
+        let content = output_message(&output, &outcome);
+        return Ok(Some(ChatGptMessage::new(Role::User, content)));
+    }
+    Ok(None)
+}
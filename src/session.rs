@@ -0,0 +1,369 @@
+//! Session files, which keep a conversation as JSON Lines, and the
+//! settings it was started with.
+
+use crate::api::{ChatGptParams, ReasoningEffort};
+use crate::listener::{open_file_for_appending, ChatMessageListener};
+use crate::message::{assign_missing_ids, ChatGptMessage, Role};
+use serde::{Deserialize, Serialize};
+use serde_jsonlines::json_lines;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Settings a session was created with, kept beside the session file so
+/// that resuming it uses them again.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SessionMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+    /// Whether the session file leaves out system prompts, which are then
+    /// supplied afresh each time the session is resumed.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub no_persist_system: bool,
+}
+
+fn metadata_path(session: &str) -> PathBuf {
+    PathBuf::from(format!("{}.meta.json", session))
+}
+
+impl SessionMetadata {
+    /// Reads the metadata of a session, if it has any.
+    pub fn load(
+        session: &str,
+    ) -> Result<Option<SessionMetadata>, Box<dyn Error>> {
+        let path = metadata_path(session);
+        match fs::read_to_string(&path) {
+            Ok(text) => Ok(Some(serde_json::from_str(&text).map_err(|e| {
+                format!("invalid session metadata {}: {}", path.display(), e)
+            })?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(
+        session: &str,
+        params: &ChatGptParams,
+        no_persist_system: bool,
+    ) -> io::Result<()> {
+        let metadata = SessionMetadata {
+            model: Some(params.model.clone()),
+            temperature: params.temperature,
+            max_tokens: params.token_limit(),
+            reasoning_effort: params.reasoning_effort,
+            no_persist_system,
+        };
+        let json = serde_json::to_string_pretty(&metadata)?;
+        fs::write(metadata_path(session), json)
+    }
+}
+
+/// Reads the messages of a session file, which is empty if it doesn't
+/// exist yet.
+pub fn read_session_messages(
+    filename: &str,
+) -> io::Result<Vec<ChatGptMessage>> {
+    let path = Path::new(filename);
+    let mut messages = if path.try_exists()? {
+        json_lines::<ChatGptMessage, _>(path)?
+            .collect::<io::Result<Vec<_>>>()?
+    } else {
+        Vec::new()
+    };
+    assign_missing_ids(&mut messages);
+    Ok(messages)
+}
+
+/// The least time between syncs of a session file with `--sync`.
+const SYNC_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Appends each message to a session file as a line of JSON.
+pub struct SessionAppendListener {
+    file: File,
+    sync: bool,
+    /// Whether system prompts are written along with the rest.
+    persist_system: bool,
+    last_sync: Option<Instant>,
+    /// Whether a line has been written since the last sync.
+    unsynced: bool,
+}
+
+/// Takes an advisory lock on a session file for as long as it is open, so
+/// that two termgpts can't interleave their lines in it. Filesystems that
+/// can't lock are used without one.
+#[cfg(unix)]
+fn lock_session(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let locked =
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    let error = io::Error::last_os_error();
+    if locked != 0 && error.kind() == io::ErrorKind::WouldBlock {
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "it is in use by another termgpt",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lock_session(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+impl SessionAppendListener {
+    /// With `sync` set, the file is synced to disk at most every
+    /// `SYNC_INTERVAL`, and before the conversation waits on the user.
+    /// Without `persist_system`, system prompts are left out. Fails if
+    /// another process has the file.
+    pub fn new(
+        filename: &str,
+        sync: bool,
+        persist_system: bool,
+    ) -> io::Result<SessionAppendListener> {
+        let file = open_file_for_appending(filename)?;
+        lock_session(&file)?;
+        Ok(SessionAppendListener {
+            file,
+            sync,
+            persist_system,
+            last_sync: None,
+            unsynced: false,
+        })
+    }
+
+    /// Appends to a log that is never read back, such as an archive that
+    /// many runs write to at once. It isn't locked, as each message is
+    /// written in a single append, and system prompts are kept.
+    pub fn for_log(
+        filename: &str,
+        sync: bool,
+    ) -> io::Result<SessionAppendListener> {
+        Ok(SessionAppendListener {
+            file: open_file_for_appending(filename)?,
+            sync,
+            persist_system: true,
+            last_sync: None,
+            unsynced: false,
+        })
+    }
+}
+
+impl ChatMessageListener for SessionAppendListener {
+    fn on_message(
+        &mut self,
+        message: &ChatGptMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        if message.role == Role::System && !self.persist_system {
+            return Ok(());
+        }
+        // Written in one go, so that being killed part way through can't
+        // leave half a line behind.
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.unsynced = true;
+        self.sync_if_due()?;
+        Ok(())
+    }
+
+    fn on_chunk(&mut self, _text: &str) -> Result<(), Box<dyn Error>> {
+        self.sync_if_due()?;
+        Ok(())
+    }
+
+    fn on_idle(&mut self) -> Result<(), Box<dyn Error>> {
+        self.sync_now()?;
+        Ok(())
+    }
+}
+
+impl SessionAppendListener {
+    /// Messages that come close together share a sync. A line held back
+    /// is synced with the next message or chunk after `SYNC_INTERVAL`, or
+    /// at the latest when the conversation waits on the user.
+    fn sync_if_due(&mut self) -> io::Result<()> {
+        let due = self.last_sync.is_none_or(|t| t.elapsed() >= SYNC_INTERVAL);
+        if due {
+            self.sync_now()?;
+        }
+        Ok(())
+    }
+
+    fn sync_now(&mut self) -> io::Result<()> {
+        if self.sync && self.unsynced {
+            self.file.sync_data()?;
+            self.last_sync = Some(Instant::now());
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SessionAppendListener {
+    fn drop(&mut self) {
+        let _ = self.sync_now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ChatMessages, Role};
+
+    fn session_path(dir: &tempfile::TempDir) -> String {
+        dir.path().join("chat.jsonl").to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn messages_read_back_as_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = session_path(&dir);
+        let mut chat = ChatMessages::new();
+        chat.register(
+            "session",
+            SessionAppendListener::new(&path, false, true).unwrap(),
+        );
+        chat.push(ChatGptMessage::new(Role::System, "Be brief.".into()))
+            .unwrap();
+        chat.push(ChatGptMessage::new(Role::User, "Hi\nthere".into()))
+            .unwrap();
+        chat.push(ChatGptMessage::new(Role::Assistant, "Hello!".into()))
+            .unwrap();
+        let written = chat.messages.clone();
+        drop(chat);
+
+        assert_eq!(read_session_messages(&path).unwrap(), written);
+        let resumed = ChatMessages::from_file(&path).unwrap();
+        assert_eq!(resumed.messages, written);
+    }
+
+    #[test]
+    fn system_prompts_can_be_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = session_path(&dir);
+        let mut chat = ChatMessages::new();
+        chat.register(
+            "session",
+            SessionAppendListener::new(&path, false, false).unwrap(),
+        );
+        chat.push(ChatGptMessage::new(Role::User, "Hi".into()))
+            .unwrap();
+        chat.prepend(ChatGptMessage::new(Role::System, "Be brief.".into()))
+            .unwrap();
+        drop(chat);
+
+        let messages = read_session_messages(&path).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, Role::User);
+        assert_eq!(messages[0].id, Some(1));
+    }
+
+    #[test]
+    fn lines_held_back_from_a_sync_are_synced_before_waiting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = session_path(&dir);
+        let mut session =
+            SessionAppendListener::new(&path, true, true).unwrap();
+        let message = ChatGptMessage::new(Role::User, "Hi".into());
+        session.on_message(&message).unwrap();
+        assert!(!session.unsynced);
+        session.on_message(&message).unwrap();
+        assert!(session.unsynced);
+        session.on_idle().unwrap();
+        assert!(!session.unsynced);
+    }
+
+    #[test]
+    fn unknown_roles_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = session_path(&dir);
+        fs::write(
+            &path,
+            "{\"id\":1,\"role\":\"user\",\"content\":\"a\"}\n\
+             {\"id\":2,\"role\":\"function\",\"content\":\"b\"}\n",
+        )
+        .unwrap();
+        let messages = read_session_messages(&path).unwrap();
+        assert_eq!(messages[0].role, Role::User);
+        assert_eq!(messages[1].role, Role::Other("function".into()));
+        let line = serde_json::to_string(&messages[1]).unwrap();
+        assert_eq!(line, "{\"id\":2,\"role\":\"function\",\"content\":\"b\"}");
+    }
+
+    #[test]
+    fn logs_are_shared_between_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = session_path(&dir);
+        let mut first = ChatMessages::new();
+        first.register(
+            "log",
+            SessionAppendListener::for_log(&path, false).unwrap(),
+        );
+        let mut second = ChatMessages::new();
+        second.register(
+            "log",
+            SessionAppendListener::for_log(&path, false).unwrap(),
+        );
+        first
+            .push(ChatGptMessage::new(Role::User, "a".into()))
+            .unwrap();
+        second
+            .push(ChatGptMessage::new(Role::User, "b".into()))
+            .unwrap();
+        let contents: Vec<String> = read_session_messages(&path)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(contents, ["a", "b"]);
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_session() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_session_messages(&session_path(&dir))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn messages_without_ids_are_given_them_on_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = session_path(&dir);
+        fs::write(
+            &path,
+            "{\"role\":\"user\",\"content\":\"a\"}\n\
+             {\"id\":5,\"role\":\"assistant\",\"content\":\"b\"}\n\
+             {\"role\":\"user\",\"content\":\"c\"}\n",
+        )
+        .unwrap();
+        let ids: Vec<_> = read_session_messages(&path)
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, [Some(1), Some(5), Some(6)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_session_file_can_only_be_open_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = session_path(&dir);
+        let _first = SessionAppendListener::new(&path, false, true).unwrap();
+        let second = SessionAppendListener::new(&path, false, true);
+        assert_eq!(second.err().unwrap().kind(), io::ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,199 @@
+use std::error::Error;
+use std::fmt;
+use std::future::{self, Future};
+use std::process;
+use tokio::sync::broadcast;
+
+/// The exit code of a shell after Ctrl-C, 128 plus SIGINT.
+const CTRL_C_CODE: i32 = 130;
+
+/// A request given up on because termgpt was told to stop. It is returned
+/// as an error so that everything is dropped, and so flushed, on the way
+/// out; `main` then exits with `code` as a shell would for the signal.
+#[derive(Debug)]
+pub struct Interrupted {
+    pub code: i32,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl Error for Interrupted {}
+
+/// Watches for Ctrl-C, and on Unix for SIGTERM and SIGHUP, for as long as
+/// the runtime it was started in. A signal during a request stops it
+/// through `interruptible`, and one while the REPL holds `Signals` is left
+/// for it to return. Only with neither does termgpt put the terminal back
+/// as it was and exit there and then, which skips dropping the listeners;
+/// they have written out each message as it came, though any a webhook
+/// was still to deliver are lost.
+pub struct Shutdown {
+    sender: broadcast::Sender<i32>,
+}
+
+impl Shutdown {
+    pub fn listen() -> Shutdown {
+        let (sender, _) = broadcast::channel(1);
+        let notify = sender.clone();
+        let terminal = saved_terminal();
+        tokio::spawn(async move {
+            loop {
+                let code = signalled().await;
+                if notify.send(code).is_err() {
+                    restore_terminal(&terminal);
+                    process::exit(code);
+                }
+            }
+        });
+        Shutdown { sender }
+    }
+
+    /// Holds on to each signal until it is asked for, rather than letting
+    /// termgpt exit when it arrives.
+    pub fn subscribe(&self) -> Signals {
+        Signals(self.sender.subscribe())
+    }
+
+    /// Runs a request, stopping it if a signal arrives first.
+    pub async fn interruptible<T>(
+        &self,
+        request: impl Future<Output = Result<T, Box<dyn Error>>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let mut receiver = self.sender.subscribe();
+        tokio::select! {
+            result = request => result,
+            Ok(code) = receiver.recv() => Err(Box::new(Interrupted { code })),
+        }
+    }
+
+    /// Runs a request that can be stopped, with Ctrl-C or when `stop`
+    /// finishes, returning `None` if it is. Any other signal stops it as
+    /// with `interruptible`.
+    pub async fn stoppable<T>(
+        &self,
+        request: impl Future<Output = Result<T, Box<dyn Error>>>,
+        stop: impl Future<Output = ()>,
+    ) -> Result<Option<T>, Box<dyn Error>> {
+        let mut receiver = self.sender.subscribe();
+        tokio::select! {
+            result = request => result.map(Some),
+            _ = stop => Ok(None),
+            Ok(code) = receiver.recv() => match code {
+                CTRL_C_CODE => Ok(None),
+                code => Err(Box::new(Interrupted { code })),
+            },
+        }
+    }
+}
+
+/// Signals held for the REPL, from `Shutdown::subscribe`.
+pub struct Signals(broadcast::Receiver<i32>);
+
+impl Signals {
+    /// Waits for a signal, returning it to stop termgpt with.
+    pub async fn next(&mut self) -> Interrupted {
+        loop {
+            match self.0.recv().await {
+                Ok(code) => return Interrupted { code },
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => {
+                    return future::pending().await
+                }
+            }
+        }
+    }
+
+    /// Forgets the signals so far, such as a Ctrl-C that only stopped a
+    /// response.
+    pub fn clear(&mut self) {
+        self.0 = self.0.resubscribe();
+    }
+}
+
+#[cfg(unix)]
+async fn signalled() -> i32 {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).ok();
+    let mut hangup = signal(SignalKind::hangup()).ok();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => CTRL_C_CODE,
+        Some(_) = async { terminate.as_mut()?.recv().await } => {
+            128 + libc::SIGTERM
+        }
+        Some(_) = async { hangup.as_mut()?.recv().await } => {
+            128 + libc::SIGHUP
+        }
+    }
+}
+
+#[cfg(windows)]
+async fn signalled() -> i32 {
+    use tokio::signal::windows::{ctrl_break, ctrl_close};
+    let mut close = ctrl_close().ok();
+    let mut brk = ctrl_break().ok();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => CTRL_C_CODE,
+        Some(_) = async { close.as_mut()?.recv().await } => 130,
+        Some(_) = async { brk.as_mut()?.recv().await } => 130,
+    }
+}
+
+#[cfg(unix)]
+type Terminal = Option<libc::termios>;
+
+#[cfg(not(unix))]
+type Terminal = ();
+
+/// The terminal's settings before the line editor puts it in raw mode.
+#[cfg(unix)]
+fn saved_terminal() -> Terminal {
+    let mut termios = std::mem::MaybeUninit::uninit();
+    unsafe {
+        (libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) == 0)
+            .then(|| termios.assume_init())
+    }
+}
+
+#[cfg(not(unix))]
+fn saved_terminal() -> Terminal {}
+
+#[cfg(unix)]
+fn restore_terminal(terminal: &Terminal) {
+    if let Some(termios) = terminal {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+        }
+        eprintln!();
+    }
+}
+
+#[cfg(not(unix))]
+fn restore_terminal(_terminal: &Terminal) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future;
+
+    #[tokio::test]
+    async fn ctrl_c_stops_a_request_where_other_signals_interrupt_it() {
+        let (sender, _) = broadcast::channel(1);
+        let shutdown = Shutdown { sender };
+        let never = || future::pending::<Result<(), Box<dyn Error>>>();
+        let stopped = shutdown.stoppable(never(), future::ready(())).await;
+        assert!(stopped.unwrap().is_none());
+
+        for (code, interrupted) in [(CTRL_C_CODE, false), (143, true)] {
+            let request = shutdown.stoppable(never(), future::pending());
+            let signal = async {
+                tokio::task::yield_now().await;
+                shutdown.sender.send(code).unwrap();
+            };
+            let (result, _) = tokio::join!(request, signal);
+            assert_eq!(result.is_err(), interrupted);
+        }
+    }
+}
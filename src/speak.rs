@@ -0,0 +1,173 @@
+use std::env;
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use tempfile::TempPath;
+use termgpt::api::{ChatGptClient, SpeechRequest, DEFAULT_SPEECH_MODEL};
+use termgpt::listener::strip_markdown;
+
+/// The most text the speech endpoint reads in one request.
+const MAX_CHUNK_BYTES: usize = 4096;
+
+/// The voice used unless another is given.
+pub const DEFAULT_VOICE: &str = "alloy";
+
+/// Players tried in turn when none is given, with the arguments that make
+/// them play a file and exit without a window.
+const PLAYERS: &[&str] = &[
+    "afplay",
+    "paplay",
+    "ffplay -nodisp -autoexit -loglevel quiet",
+];
+
+/// Reads responses aloud, for --speak and /speak.
+pub struct Speaker {
+    pub voice: String,
+    pub speed: Option<f32>,
+    /// The command that plays a WAV file, given as its last argument.
+    pub player: Option<String>,
+}
+
+fn on_path(program: &str) -> bool {
+    env::var_os("PATH").is_some_and(|paths| {
+        env::split_paths(&paths).any(|dir| dir.join(program).is_file())
+    })
+}
+
+/// The first of the usual players that is installed.
+pub fn detect_player() -> Option<String> {
+    PLAYERS
+        .iter()
+        .find(|player| on_path(player.split(' ').next().unwrap()))
+        .map(|player| player.to_string())
+}
+
+/// Splits text into pieces of at most `max` bytes, at the ends of
+/// sentences where it can, so that each request reads whole sentences.
+fn split_sentences(text: &str, max: usize) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, c)| c);
+        let ends = c == '\n'
+            || (matches!(c, '.' | '!' | '?')
+                && next.is_none_or(char::is_whitespace));
+        if ends {
+            let end = i + c.len_utf8();
+            sentences.push(&text[start..end]);
+            start = end;
+        }
+    }
+    sentences.push(&text[start..]);
+
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    for mut sentence in sentences {
+        while !sentence.is_empty() {
+            if chunk.len() + sentence.len() <= max {
+                chunk.push_str(sentence);
+                break;
+            }
+            if !chunk.trim().is_empty() {
+                chunks.push(chunk.trim().to_string());
+            }
+            chunk.clear();
+            if sentence.len() <= max {
+                continue;
+            }
+            // A sentence too long on its own is cut at a space.
+            let mut cut = max;
+            while !sentence.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            let cut =
+                sentence[..cut].rfind(' ').filter(|&i| i > 0).unwrap_or(cut);
+            chunks.push(sentence[..cut].trim().to_string());
+            sentence = &sentence[cut..];
+        }
+    }
+    if !chunk.trim().is_empty() {
+        chunks.push(chunk.trim().to_string());
+    }
+    chunks
+}
+
+impl Speaker {
+    /// Reads a response aloud, a chunk at a time, once it has been shown.
+    /// The markdown is stripped first, so that the symbols aren't read.
+    pub async fn speak(
+        &self,
+        client: &ChatGptClient,
+        text: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let player = self.player.as_deref().ok_or(
+            "no audio player found (install ffplay or use --audio-player)",
+        )?;
+        let text = strip_markdown(text);
+        for chunk in split_sentences(&text, MAX_CHUNK_BYTES) {
+            let request = SpeechRequest {
+                model: DEFAULT_SPEECH_MODEL,
+                input: &chunk,
+                voice: &self.voice,
+                speed: self.speed,
+                response_format: "wav",
+            };
+            let audio = client.speech(&request).await?;
+            let path = temp_file(&audio)?;
+            play(player, &path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes audio to a new file only we can read, which is deleted when the
+/// path is dropped.
+fn temp_file(audio: &[u8]) -> io::Result<TempPath> {
+    let mut file = tempfile::Builder::new()
+        .prefix("termgpt-speech-")
+        .suffix(".wav")
+        .tempfile()?;
+    file.write_all(audio)?;
+    Ok(file.into_temp_path())
+}
+
+fn play(player: &str, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut words = player.split_whitespace();
+    let program = words.next().ok_or("the audio player is empty")?;
+    let status = process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .stdin(process::Stdio::null())
+        .status()
+        .map_err(|e| format!("could not run {}: {}", program, e))?;
+    if !status.success() {
+        Err(format!("{} exited with {}", program, status))?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_split_between_sentences() {
+        let chunks = split_sentences("One. Two! Three? Four.", 12);
+        assert_eq!(chunks, ["One. Two!", "Three?", "Four."]);
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        let chunks = split_sentences("One.\nTwo. Version 1.5 is out.", 100);
+        assert_eq!(chunks, ["One.\nTwo. Version 1.5 is out."]);
+    }
+
+    #[test]
+    fn long_sentences_are_cut_at_spaces() {
+        let chunks = split_sentences("aaa bbb ccc ddd", 8);
+        assert_eq!(chunks, ["aaa bbb", "ccc ddd"]);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 8));
+    }
+}
@@ -0,0 +1,86 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const FRAMES: [char; 8] = ['⣾', '⣽', '⣻', '⢿', '⡿', '⣟', '⣯', '⣷'];
+const INTERVAL: Duration = Duration::from_millis(80);
+
+/// What the spinner says while a request is waited on.
+const MESSAGE: &str = "thinking…";
+
+/// Spins on stdout, along with how long it has been spinning, while a
+/// request is waited on. It spins on a thread of its own, so that it keeps
+/// going whatever the request's task does, and stops when dropped.
+pub struct Spinner {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// The line shown after `elapsed`, with the time only once it is worth
+/// showing.
+fn status_line(frame: char, elapsed: Duration) -> String {
+    match elapsed.as_secs() {
+        0 => format!("{} {}", frame, MESSAGE),
+        secs => format!("{} {} {}s", frame, MESSAGE, secs),
+    }
+}
+
+impl Spinner {
+    pub fn start() -> Spinner {
+        let (stop, stopped) = mpsc::channel();
+        let start = Instant::now();
+        let thread = thread::spawn(move || {
+            let mut stdout = io::stdout();
+            for frame in FRAMES.iter().cycle() {
+                let line = status_line(*frame, start.elapsed());
+                let _ = write!(stdout, "\r\x1b[2K{}", line);
+                let _ = stdout.flush();
+                match stopped.recv_timeout(INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+            }
+            let _ = write!(stdout, "\r\x1b[2K");
+            let _ = stdout.flush();
+        });
+        Spinner {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops spinning and clears the line.
+    pub fn stop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Stops spinning and prints `text` in its place.
+    pub fn stop_with_message(&mut self, text: String) {
+        self.stop();
+        println!("{}", text);
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elapsed_time_is_shown_after_a_second() {
+        assert_eq!(status_line('⣾', Duration::from_millis(400)), "⣾ thinking…");
+        assert_eq!(
+            status_line('⣽', Duration::from_millis(4200)),
+            "⣽ thinking… 4s"
+        );
+    }
+}
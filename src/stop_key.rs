@@ -0,0 +1,156 @@
+use std::future;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use termimad::crossterm::tty::IsTty;
+use tokio::sync::oneshot;
+
+/// How often the watching thread checks whether it is still wanted.
+#[cfg(unix)]
+const POLL_MILLIS: i32 = 50;
+
+/// Watches the terminal for Esc while a response streams in the REPL, so
+/// that it can be stopped. The terminal is taken out of line mode to see
+/// the key as it is pressed, and put back when this is dropped. Keys other
+/// than Esc pressed meanwhile are lost, as they would have been typed over
+/// the response.
+pub struct StopKey {
+    pressed: Option<oneshot::Receiver<()>>,
+    done: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    terminal: Terminal,
+}
+
+impl StopKey {
+    pub fn watch() -> StopKey {
+        let done = Arc::new(AtomicBool::new(false));
+        if !io::stdin().is_tty() {
+            return StopKey {
+                pressed: None,
+                done,
+                thread: None,
+                terminal: Terminal::default(),
+            };
+        }
+        let terminal = key_mode();
+        let (sender, receiver) = oneshot::channel();
+        let watching = done.clone();
+        let thread = thread::spawn(move || {
+            while !watching.load(Ordering::Relaxed) {
+                match read_key() {
+                    Key::Esc => {
+                        let _ = sender.send(());
+                        break;
+                    }
+                    Key::Closed => break,
+                    Key::Other => {}
+                }
+            }
+        });
+        StopKey {
+            pressed: Some(receiver),
+            done,
+            thread: Some(thread),
+            terminal,
+        }
+    }
+
+    /// Waits for Esc, which never comes if stdin isn't a terminal.
+    pub async fn pressed(&mut self) {
+        if let Some(pressed) = self.pressed.as_mut() {
+            if pressed.await.is_ok() {
+                return;
+            }
+        }
+        future::pending().await
+    }
+}
+
+impl Drop for StopKey {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        restore_terminal(&self.terminal);
+    }
+}
+
+enum Key {
+    Esc,
+    /// Any other key, or none.
+    Other,
+    /// stdin can't be read any more.
+    Closed,
+}
+
+#[cfg(unix)]
+type Terminal = Option<libc::termios>;
+
+#[cfg(not(unix))]
+type Terminal = ();
+
+/// Has the terminal pass on each key as it is pressed, without echoing it,
+/// returning its settings before. Ctrl-C still sends SIGINT.
+#[cfg(unix)]
+fn key_mode() -> Terminal {
+    let mut termios = std::mem::MaybeUninit::uninit();
+    let saved = unsafe {
+        (libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) == 0)
+            .then(|| termios.assume_init())
+    }?;
+    let mut keys = saved;
+    keys.c_lflag &= !(libc::ICANON | libc::ECHO);
+    keys.c_cc[libc::VMIN] = 1;
+    keys.c_cc[libc::VTIME] = 0;
+    unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &keys);
+    }
+    Some(saved)
+}
+
+#[cfg(not(unix))]
+fn key_mode() -> Terminal {}
+
+#[cfg(unix)]
+fn restore_terminal(terminal: &Terminal) {
+    if let Some(termios) = terminal {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn restore_terminal(_terminal: &Terminal) {}
+
+/// Reads what the terminal sends, if anything, within `POLL_MILLIS`. Esc
+/// comes alone, where keys such as the arrows send it at the start of a
+/// sequence.
+#[cfg(unix)]
+fn read_key() -> Key {
+    let mut stdin = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut stdin, 1, POLL_MILLIS) } <= 0 {
+        return Key::Other;
+    }
+    let mut bytes = [0u8; 16];
+    let read = unsafe {
+        libc::read(libc::STDIN_FILENO, bytes.as_mut_ptr().cast(), bytes.len())
+    };
+    match read {
+        1 if bytes[0] == 0x1b => Key::Esc,
+        1.. => Key::Other,
+        _ => Key::Closed,
+    }
+}
+
+/// Keys can't be read one at a time here, so only Ctrl-C stops a response.
+#[cfg(not(unix))]
+fn read_key() -> Key {
+    Key::Closed
+}
@@ -0,0 +1,74 @@
+use std::io;
+use std::io::Write;
+use std::time::Instant;
+use termimad::crossterm::tty::IsTty;
+
+/// A live count of streamed tokens and their rate, written to stderr.
+///
+/// When stdout is the same terminal, the status is drawn just after the
+/// cursor with line wrapping off, and erased before each chunk is printed
+/// over it. Otherwise it is redrawn on its own line.
+pub struct StreamStats {
+    start: Option<Instant>,
+    tokens: usize,
+    inline: bool,
+    enabled: bool,
+}
+
+impl StreamStats {
+    pub fn new(enabled: bool) -> StreamStats {
+        StreamStats {
+            start: None,
+            tokens: 0,
+            inline: io::stdout().is_tty(),
+            enabled: enabled && io::stderr().is_tty(),
+        }
+    }
+
+    fn clear(&self) {
+        if self.inline {
+            eprint!("\x1b[K");
+        } else {
+            eprint!("\r\x1b[2K");
+        }
+    }
+
+    /// Erases the status so that a chunk can be printed.
+    pub fn before_chunk(&mut self) {
+        if self.enabled && self.start.is_some() {
+            self.clear();
+        }
+    }
+
+    /// Counts a chunk, each of which is usually one token, and redraws
+    /// the status.
+    pub fn after_chunk(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.tokens += 1;
+        // The rate is measured from the first chunk, so it only means
+        // something once a second one has arrived.
+        let elapsed = start.elapsed().as_secs_f64();
+        let status = if self.tokens > 1 && elapsed > 0.0 {
+            let rate = (self.tokens - 1) as f64 / elapsed;
+            format!("{} tokens, {:.1} tok/s", self.tokens, rate)
+        } else {
+            format!("{} tokens", self.tokens)
+        };
+        if self.inline {
+            eprint!("\x1b7\x1b[?7l  \x1b[2m[{}]\x1b[0m\x1b[?7h\x1b8", status);
+        } else {
+            eprint!("\r\x1b[2K{}", status);
+        }
+        let _ = io::stderr().flush();
+    }
+
+    /// Erases the status once the response is complete.
+    pub fn finish(&self) {
+        if self.enabled && self.start.is_some() {
+            self.clear();
+        }
+    }
+}
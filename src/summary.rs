@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use termgpt::api::{ChatApi, ChatGptParams};
+use termgpt::message::{ChatGptMessage, Role};
+
+const SUMMARY_PROMPT: &str = "\
+You maintain a running summary of a conversation between a user and an \
+assistant. Given the current summary and the messages that follow it, \
+reply with an updated summary that keeps every fact, decision, name and \
+open question needed to continue the conversation. Reply with only the \
+summary.";
+
+#[derive(Default, Deserialize, Serialize)]
+struct SummaryState {
+    summary: String,
+    /// The id of the last message folded into the summary.
+    through_id: u64,
+}
+
+/// A summary of the older part of a conversation, sent in its place so
+/// that only the most recent turns are sent verbatim.
+pub struct RollingSummary {
+    state: SummaryState,
+    path: Option<PathBuf>,
+    recent_turns: usize,
+}
+
+fn split_system(
+    messages: &[ChatGptMessage],
+) -> (&[ChatGptMessage], &[ChatGptMessage]) {
+    let split = messages
+        .iter()
+        .position(|m| m.role != Role::System)
+        .unwrap_or(messages.len());
+    messages.split_at(split)
+}
+
+impl RollingSummary {
+    /// Creates a summary, loading the one saved beside the session file.
+    pub fn new(
+        session: Option<&str>,
+        recent_turns: usize,
+    ) -> Result<RollingSummary, Box<dyn Error>> {
+        let path =
+            session.map(|s| PathBuf::from(format!("{}.summary.json", s)));
+        let state = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(text) => serde_json::from_str(&text).map_err(|e| {
+                    format!("invalid summary file {}: {}", path.display(), e)
+                })?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    SummaryState::default()
+                }
+                Err(e) => Err(e)?,
+            },
+            None => SummaryState::default(),
+        };
+        Ok(RollingSummary {
+            state,
+            path,
+            recent_turns,
+        })
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// The messages to send: the leading system messages, the summary, and
+    /// every message not yet folded into the summary.
+    pub fn context(&self, messages: &[ChatGptMessage]) -> Vec<ChatGptMessage> {
+        let (system, rest) = split_system(messages);
+        let mut context = system.to_vec();
+        if !self.state.summary.is_empty() {
+            context.push(ChatGptMessage::new(
+                Role::System,
+                format!(
+                    "Summary of the conversation so far:\n\n{}",
+                    self.state.summary
+                ),
+            ));
+        }
+        context.extend(
+            rest.iter()
+                .filter(|m| m.id.unwrap_or(0) > self.state.through_id)
+                .cloned(),
+        );
+        context
+    }
+
+    /// Folds everything but the most recent turns into the summary with a
+    /// separate request, saving the result if there is a session.
+    pub async fn update(
+        &mut self,
+        client: &impl ChatApi,
+        params: &ChatGptParams,
+        messages: &[ChatGptMessage],
+    ) -> Result<(), Box<dyn Error>> {
+        let (_, rest) = split_system(messages);
+        let pending: Vec<&ChatGptMessage> = rest
+            .iter()
+            .filter(|m| m.id.unwrap_or(0) > self.state.through_id)
+            .collect();
+        let keep = self.recent_turns * 2;
+        if pending.len() <= keep {
+            return Ok(());
+        }
+        let fold = &pending[..pending.len() - keep];
+
+        let mut transcript = String::new();
+        for message in fold {
+            let label = message.role.title();
+            transcript.push_str(&format!("{}: {}\n\n", label, message.content));
+        }
+        let request = [
+            ChatGptMessage::new(Role::System, SUMMARY_PROMPT.to_string()),
+            ChatGptMessage::new(
+                Role::User,
+                format!(
+                    "Current summary:\n\n{}\n\nNew messages:\n\n{}",
+                    self.state.summary, transcript
+                ),
+            ),
+        ];
+        let mut response =
+            client.get_chatgpt_response(params, &request).await?;
+        let choice = response.choices.pop().ok_or("no choices returned")?;
+        self.state.summary = choice.message.content.trim().to_string();
+        self.state.through_id = fold.last().and_then(|m| m.id).unwrap_or(0);
+
+        if let Some(path) = &self.path {
+            fs::write(path, serde_json::to_string_pretty(&self.state)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use termgpt::api::{ChatGptChoice, ChatGptResponse};
+
+    /// Replies with a fixed summary, keeping the requests it was sent.
+    struct FakeApi {
+        reply: &'static str,
+        requests: RefCell<Vec<Vec<ChatGptMessage>>>,
+    }
+
+    impl ChatApi for FakeApi {
+        async fn get_chatgpt_response(
+            &self,
+            _params: &ChatGptParams,
+            messages: &[ChatGptMessage],
+        ) -> Result<ChatGptResponse, Box<dyn Error>> {
+            self.requests.borrow_mut().push(messages.to_vec());
+            Ok(ChatGptResponse {
+                model: None,
+                choices: vec![ChatGptChoice {
+                    message: ChatGptMessage::new(
+                        Role::Assistant,
+                        self.reply.to_string(),
+                    ),
+                    finish_reason: Some("stop".to_string()),
+                }],
+                usage: None,
+                rate_limits: None,
+            })
+        }
+
+        async fn stream_chatgpt_response<F>(
+            &self,
+            params: &ChatGptParams,
+            messages: &[ChatGptMessage],
+            _include_usage: bool,
+            _on_chunk: F,
+        ) -> Result<ChatGptResponse, Box<dyn Error>>
+        where
+            F: FnMut(&str) -> Result<(), Box<dyn Error>>,
+        {
+            self.get_chatgpt_response(params, messages).await
+        }
+    }
+
+    fn params() -> ChatGptParams {
+        ChatGptParams {
+            model: "gpt-4o-mini".into(),
+            ..Default::default()
+        }
+    }
+
+    fn conversation(turns: u64) -> Vec<ChatGptMessage> {
+        let mut messages =
+            vec![ChatGptMessage::new(Role::System, "Be brief.".into())];
+        for i in 0..turns * 2 {
+            let role = if i % 2 == 0 {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            messages.push(ChatGptMessage::new(role, format!("message {}", i)));
+        }
+        for (id, message) in messages.iter_mut().enumerate() {
+            message.id = Some(id as u64 + 1);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn older_turns_are_folded_into_the_summary() {
+        let api = FakeApi {
+            reply: "They talked.",
+            requests: RefCell::new(Vec::new()),
+        };
+        let messages = conversation(3);
+        let mut summary = RollingSummary::new(None, 1).unwrap();
+        summary.update(&api, &params(), &messages).await.unwrap();
+
+        let requests = api.requests.borrow();
+        assert_eq!(
+            *requests,
+            [[
+                ChatGptMessage::new(Role::System, SUMMARY_PROMPT.into()),
+                ChatGptMessage::new(
+                    Role::User,
+                    "Current summary:\n\n\n\nNew messages:\n\n\
+                     User: message 0\n\nAssistant: message 1\n\n\
+                     User: message 2\n\nAssistant: message 3\n\n"
+                        .into()
+                ),
+            ]]
+        );
+
+        let context = summary.context(&messages);
+        let contents: Vec<_> =
+            context.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            [
+                "Be brief.",
+                "Summary of the conversation so far:\n\nThey talked.",
+                "message 4",
+                "message 5",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn recent_turns_are_left_alone() {
+        let api = FakeApi {
+            reply: "unused",
+            requests: RefCell::new(Vec::new()),
+        };
+        let messages = conversation(2);
+        let mut summary = RollingSummary::new(None, 2).unwrap();
+        summary.update(&api, &params(), &messages).await.unwrap();
+        assert!(api.requests.borrow().is_empty());
+        assert_eq!(summary.context(&messages), messages);
+    }
+}
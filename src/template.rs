@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Returns true if the template contains a `{{name}}` placeholder.
+pub fn references(template: &str, name: &str) -> bool {
+    template.contains(&format!("{{{{{}}}}}", name))
+}
+
+/// Substitutes `{{name}}` placeholders with their values. `\{\{` produces a
+/// literal `{{`. Unknown names are an error unless `allow_missing` is set,
+/// in which case the placeholder is left as it is.
+pub fn render(
+    template: &str,
+    vars: &HashMap<String, String>,
+    allow_missing: bool,
+) -> Result<String, Box<dyn Error>> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['\\', '{']) {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("\\{\\{") {
+            output.push_str("{{");
+            rest = after;
+            continue;
+        }
+
+        let placeholder = rest
+            .strip_prefix("{{")
+            .and_then(|after| after.find("}}").map(|end| &after[..end]))
+            .filter(|name| !name.is_empty() && name.chars().all(is_name_char));
+
+        match placeholder {
+            Some(name) => {
+                match vars.get(name) {
+                    Some(value) => output.push_str(value),
+                    None if allow_missing => {
+                        output.push_str(&rest[..name.len() + 4])
+                    }
+                    None => Err(format!(
+                        "undefined template variable `{}` \
+                         (set it with --var or use --allow-missing-vars)",
+                        name
+                    ))?,
+                }
+                rest = &rest[name.len() + 4..];
+            }
+            None => {
+                output.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Parses a `name=value` command-line variable.
+pub fn parse_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => {
+            Ok((name.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=VALUE, got `{}`", arg)),
+    }
+}
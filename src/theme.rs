@@ -0,0 +1,309 @@
+use crate::highlight;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::env;
+use std::error::Error;
+use termimad::crossterm::style::Color;
+use termimad::MadSkin;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ThemeName {
+    /// Light text for dark terminals
+    Dark,
+    /// Dark text for light terminals
+    Light,
+    /// The Dracula palette
+    Dracula,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ColorPair {
+    fg: Option<String>,
+    bg: Option<String>,
+}
+
+/// The `[theme]` section of the config file: a built-in theme to start
+/// from and colors to override in it.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    pub base: Option<String>,
+    code_theme: Option<String>,
+    paragraph: ColorPair,
+    headers: ColorPair,
+    bold: ColorPair,
+    italic: ColorPair,
+    inline_code: ColorPair,
+    code_block: ColorPair,
+    quote: ColorPair,
+}
+
+/// Parses a color name such as `dark_cyan`, a 256-color palette index or
+/// a `#rrggbb` truecolor value.
+pub fn parse_color(value: &str) -> Option<Color> {
+    if let Ok(index) = value.parse::<u8>() {
+        return Some(Color::AnsiValue(index));
+    }
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        return Some(Color::Rgb {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        });
+    }
+    Color::try_from(value).ok()
+}
+
+fn rgb(hex: u32) -> Color {
+    Color::Rgb {
+        r: (hex >> 16) as u8,
+        g: (hex >> 8) as u8,
+        b: hex as u8,
+    }
+}
+
+fn base_skin(name: ThemeName) -> MadSkin {
+    match name {
+        ThemeName::Dark => {
+            let mut skin = MadSkin::default_dark();
+            skin.paragraph.set_fg(Color::AnsiValue(249));
+            skin
+        }
+        ThemeName::Light => MadSkin::default_light(),
+        ThemeName::Dracula => {
+            let mut skin = MadSkin::default_dark();
+            skin.paragraph.set_fg(rgb(0xf8f8f2));
+            skin.set_headers_fg(rgb(0xbd93f9));
+            skin.bold.set_fg(rgb(0xff79c6));
+            skin.italic.set_fg(rgb(0xf1fa8c));
+            skin.inline_code.set_fgbg(rgb(0x50fa7b), rgb(0x44475a));
+            skin.code_block.set_fgbg(rgb(0xf8f8f2), rgb(0x44475a));
+            skin.quote_mark.set_fg(rgb(0x8be9fd));
+            skin
+        }
+    }
+}
+
+/// How responses are rendered: the markdown skin and the syntax
+/// highlighting theme for code blocks.
+pub struct Style {
+    pub skin: MadSkin,
+    pub code_theme: String,
+}
+
+fn default_code_theme(name: ThemeName) -> &'static str {
+    match name {
+        ThemeName::Dark => "base16-ocean.dark",
+        ThemeName::Light => "InspiredGitHub",
+        ThemeName::Dracula => "base16-mocha.dark",
+    }
+}
+
+/// Builds the style from `--theme`, or the base theme in the config, with
+/// the config's colors applied over it.
+pub fn build_style(
+    name: Option<ThemeName>,
+    config: &ThemeConfig,
+) -> Result<Style, Box<dyn Error>> {
+    let name = match (name, &config.base) {
+        (Some(name), _) => name,
+        (None, Some(base)) => ThemeName::from_str(base, true)
+            .map_err(|_| format!("invalid theme `{}` for theme.base", base))?,
+        (None, None) => ThemeName::Dark,
+    };
+    let mut skin = base_skin(name);
+    let code_theme = match &config.code_theme {
+        Some(code_theme) => {
+            highlight::check_theme(code_theme)?;
+            code_theme.clone()
+        }
+        None => default_code_theme(name).to_string(),
+    };
+
+    let color = |key: &str, value: &Option<String>| {
+        value
+            .as_deref()
+            .map(|v| {
+                parse_color(v).ok_or_else(|| {
+                    format!("invalid color `{}` for theme.{}", v, key)
+                })
+            })
+            .transpose()
+    };
+    let pair = |key: &str, pair: &ColorPair| {
+        Ok::<_, String>((
+            color(&format!("{}.fg", key), &pair.fg)?,
+            color(&format!("{}.bg", key), &pair.bg)?,
+        ))
+    };
+
+    let (fg, bg) = pair("paragraph", &config.paragraph)?;
+    if let Some(c) = fg {
+        skin.paragraph.set_fg(c);
+    }
+    if let Some(c) = bg {
+        skin.paragraph.set_bg(c);
+    }
+    let (fg, bg) = pair("headers", &config.headers)?;
+    if let Some(c) = fg {
+        skin.set_headers_fg(c);
+    }
+    if let Some(c) = bg {
+        skin.set_headers_bg(c);
+    }
+    let (fg, bg) = pair("bold", &config.bold)?;
+    if let Some(c) = fg {
+        skin.bold.set_fg(c);
+    }
+    if let Some(c) = bg {
+        skin.bold.set_bg(c);
+    }
+    let (fg, bg) = pair("italic", &config.italic)?;
+    if let Some(c) = fg {
+        skin.italic.set_fg(c);
+    }
+    if let Some(c) = bg {
+        skin.italic.set_bg(c);
+    }
+    let (fg, bg) = pair("inline_code", &config.inline_code)?;
+    if let Some(c) = fg {
+        skin.inline_code.set_fg(c);
+    }
+    if let Some(c) = bg {
+        skin.inline_code.set_bg(c);
+    }
+    let (fg, bg) = pair("code_block", &config.code_block)?;
+    if let Some(c) = fg {
+        skin.code_block.set_fg(c);
+    }
+    if let Some(c) = bg {
+        skin.code_block.set_bg(c);
+    }
+    let (fg, bg) = pair("quote", &config.quote)?;
+    if let Some(c) = fg {
+        skin.quote_mark.set_fg(c);
+    }
+    if let Some(c) = bg {
+        skin.quote_mark.set_bg(c);
+    }
+    Ok(Style { skin, code_theme })
+}
+
+/// Whether a color in an OSC 11 reply such as `rgb:ffff/ffff/ffff` is
+/// light, by its relative luminance.
+fn is_light_rgb(reply: &str) -> Option<bool> {
+    let rgb = reply.split("rgb:").nth(1)?;
+    let channels: Vec<f64> = rgb
+        .split('/')
+        .take(3)
+        .map(|c| {
+            let digits: String =
+                c.chars().take_while(char::is_ascii_hexdigit).collect();
+            let max = 16f64.powi(digits.len() as i32) - 1.0;
+            u32::from_str_radix(&digits, 16).map(|v| v as f64 / max)
+        })
+        .collect::<Result<_, _>>()
+        .ok()?;
+    if channels.len() != 3 {
+        return None;
+    }
+    let luminance =
+        0.2126 * channels[0] + 0.7152 * channels[1] + 0.0722 * channels[2];
+    Some(luminance > 0.5)
+}
+
+/// Asks the terminal for its background color, giving up after 100ms so
+/// that terminals which don't answer don't hang.
+#[cfg(unix)]
+fn query_background() -> Option<String> {
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use std::os::fd::AsRawFd;
+    use std::time::{Duration, Instant};
+    use termimad::crossterm::terminal;
+
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .ok()?;
+    terminal::enable_raw_mode().ok()?;
+    let mut reply = Vec::new();
+    if tty.write_all(b"\x1b]11;?\x1b\\").and(tty.flush()).is_ok() {
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let mut buf = [0; 64];
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            let mut fd = libc::pollfd {
+                fd: tty.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ready =
+                unsafe { libc::poll(&mut fd, 1, left.as_millis() as i32) };
+            if ready <= 0 {
+                break;
+            }
+            match tty.read(&mut buf) {
+                Ok(n) if n > 0 => reply.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+            // The reply ends with BEL or ST.
+            if reply.ends_with(b"\x07") || reply.ends_with(b"\x1b\\") {
+                break;
+            }
+        }
+    }
+    let _ = terminal::disable_raw_mode();
+    String::from_utf8(reply).ok().filter(|r| r.contains("rgb:"))
+}
+
+#[cfg(not(unix))]
+fn query_background() -> Option<String> {
+    None
+}
+
+/// Picks the dark or light theme for the terminal's background, from an
+/// OSC 11 query, then `COLORFGBG`, falling back to dark.
+pub fn detect_background(verbose: bool) -> ThemeName {
+    let theme = |light| {
+        if light {
+            ThemeName::Light
+        } else {
+            ThemeName::Dark
+        }
+    };
+    if let Some(reply) = query_background() {
+        if let Some(light) = is_light_rgb(&reply) {
+            if verbose {
+                eprintln!(
+                    "termgpt: background {} from OSC 11 query",
+                    if light { "light" } else { "dark" }
+                );
+            }
+            return theme(light);
+        }
+    }
+    // COLORFGBG is "fg;bg" or "fg;default;bg" with palette indices.
+    if let Ok(value) = env::var("COLORFGBG") {
+        if let Some(Ok(bg)) = value.rsplit(';').next().map(str::parse::<u8>) {
+            let light = bg == 7 || bg > 8;
+            if verbose {
+                eprintln!(
+                    "termgpt: background {} from COLORFGBG={}",
+                    if light { "light" } else { "dark" },
+                    value
+                );
+            }
+            return theme(light);
+        }
+    }
+    if verbose {
+        eprintln!("termgpt: background not detected; using the dark theme");
+    }
+    ThemeName::Dark
+}
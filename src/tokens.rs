@@ -0,0 +1,33 @@
+use tiktoken_rs::{bpe_for_model, o200k_base_singleton, CoreBPE};
+
+/// The tokenizer of a model. Models tiktoken doesn't know, such as those
+/// of other providers behind --base-url, get that of recent OpenAI models,
+/// so that their count is at least close.
+fn tokenizer(model: &str) -> &'static CoreBPE {
+    bpe_for_model(model).unwrap_or_else(|_| o200k_base_singleton())
+}
+
+/// How many tokens some text is for a model, for --count-tokens.
+pub fn count(model: &str, text: &str) -> usize {
+    tokenizer(model).count_ordinary(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_counted_with_the_model_tokenizer() {
+        assert_eq!(count("gpt-4o", "hello world"), 2);
+        assert_eq!(count("gpt-3.5-turbo", "hello world"), 2);
+        assert_eq!(count("gpt-4o", ""), 0);
+    }
+
+    #[test]
+    fn unknown_models_are_counted_all_the_same() {
+        assert_eq!(
+            count("llama3", "hello world"),
+            count("gpt-4o", "hello world")
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use crate::mcp::{McpServer, McpServerConfig};
+use crate::{ask_terminal, exec};
+use chrono::Local;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use termgpt::message::{ChatGptMessage, ToolCall};
+
+/// How many rounds of tool calls a response may take before termgpt stops
+/// answering them, in case the model keeps calling tools forever.
+pub const MAX_TOOL_ROUNDS: usize = 10;
+
+/// The most of a file or command output given back to the model.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// A tool the model may call, given with --tools.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Tool {
+    /// Read a file under the current directory
+    #[value(name = "read_file")]
+    ReadFile,
+    /// List a directory under the current directory
+    #[value(name = "list_directory")]
+    ListDirectory,
+    /// Get the current date, time and timezone
+    #[value(name = "get_datetime")]
+    GetDatetime,
+    /// Run a shell command, once it has been confirmed
+    #[value(name = "shell")]
+    Shell,
+}
+
+impl Tool {
+    fn name(self) -> &'static str {
+        match self {
+            Tool::ReadFile => "read_file",
+            Tool::ListDirectory => "list_directory",
+            Tool::GetDatetime => "get_datetime",
+            Tool::Shell => "shell",
+        }
+    }
+
+    /// The tool as offered to the model, in the chat completions format.
+    pub fn definition(self) -> Value {
+        let (description, parameters) = match self {
+            Tool::ReadFile => (
+                "Read a text file under the current directory.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Path relative to the current directory"
+                        }
+                    },
+                    "required": ["path"]
+                }),
+            ),
+            Tool::ListDirectory => (
+                "List the entries of a directory under the current \
+                 directory. Directories end with a slash.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Path relative to the current directory, . by default"
+                        }
+                    }
+                }),
+            ),
+            Tool::GetDatetime => (
+                "Get the current local date, time and timezone offset.",
+                json!({"type": "object", "properties": {}}),
+            ),
+            Tool::Shell => (
+                "Run a command in the user's shell, after the user confirms \
+                 it, and get its output and exit status.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {"type": "string"}
+                    },
+                    "required": ["command"]
+                }),
+            ),
+        };
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.name(),
+                "description": description,
+                "parameters": parameters
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct PathArgs {
+    #[serde(default)]
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ShellArgs {
+    command: String,
+}
+
+/// The tools offered to the model: the built-in ones given with --tools,
+/// and those of the MCP servers in the config file.
+#[derive(Default)]
+pub struct Toolbox {
+    builtin: Vec<Tool>,
+    servers: Vec<McpServer>,
+    /// Servers that couldn't be started, and why.
+    unavailable: Vec<(String, String)>,
+}
+
+/// The name a server's tool is offered to the model as, which has to be
+/// unique across servers and fit the API's pattern for names.
+fn qualified_name(server: &str, tool: &str) -> String {
+    format!("{}__{}", server, tool)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .take(64)
+        .collect()
+}
+
+impl Toolbox {
+    pub fn new(builtin: Vec<Tool>) -> Toolbox {
+        Toolbox {
+            builtin,
+            ..Default::default()
+        }
+    }
+
+    /// Starts each server, warning about any that can't be started rather
+    /// than failing, so that termgpt can still be used without its tools.
+    pub fn connect(
+        &mut self,
+        servers: &BTreeMap<String, McpServerConfig>,
+        verbose: bool,
+    ) {
+        for (name, config) in servers {
+            match McpServer::start(name, config, verbose) {
+                Ok(server) => self.servers.push(server),
+                Err(e) => {
+                    eprintln!(
+                        "termgpt: tools of MCP server {} unavailable: {}",
+                        name, e
+                    );
+                    self.unavailable.push((name.clone(), e.to_string()));
+                }
+            }
+        }
+    }
+
+    /// The tool definitions to send with each request.
+    pub fn definitions(&self) -> Vec<Value> {
+        let builtin = self.builtin.iter().map(|tool| tool.definition());
+        let served = self.servers.iter().flat_map(|server| {
+            server.tools.iter().map(|tool| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": qualified_name(&server.name, &tool.name),
+                        "description": tool.description,
+                        "parameters": tool.input_schema
+                    }
+                })
+            })
+        });
+        builtin.chain(served).collect()
+    }
+
+    /// Makes a call, and returns its result for the model. A tool that
+    /// fails says so in its result, so that the model can try something
+    /// else.
+    pub fn call(&self, call: &ToolCall) -> ChatGptMessage {
+        let name = &call.function.name;
+        let arguments = &call.function.arguments;
+        let builtin = self.builtin.iter().find(|tool| tool.name() == name);
+        let served = self.servers.iter().find_map(|server| {
+            let tool = server.tools.iter().find(|tool| {
+                qualified_name(&server.name, &tool.name) == *name
+            })?;
+            Some((server, &tool.name))
+        });
+        let result = match (builtin, served) {
+            (Some(&tool), _) => {
+                eprintln!("termgpt: calling {}({})", name, arguments);
+                run(tool, arguments)
+            }
+            (None, Some((server, tool))) => {
+                eprintln!("termgpt: calling {}({})", name, arguments);
+                let arguments = if arguments.trim().is_empty() {
+                    Ok(json!({}))
+                } else {
+                    serde_json::from_str(arguments)
+                };
+                arguments
+                    .map_err(Into::into)
+                    .and_then(|arguments| server.call_tool(tool, arguments))
+            }
+            (None, None) => {
+                Err(format!("there is no tool called {}", name).into())
+            }
+        };
+        let content = match result {
+            Ok(output) => truncate(output),
+            Err(e) => format!("error: {}", e),
+        };
+        ChatGptMessage::tool_result(call, content)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.builtin.is_empty() && self.servers.is_empty()
+    }
+
+    /// Lists the tools the model may call, for `/tools`.
+    pub fn print(&self) {
+        if self.is_empty() && self.unavailable.is_empty() {
+            eprintln!(
+                "no tools are enabled (use --tools, or add MCP servers to \
+                 the config file)"
+            );
+            return;
+        }
+        if !self.builtin.is_empty() {
+            let names: Vec<&str> =
+                self.builtin.iter().map(|tool| tool.name()).collect();
+            eprintln!("built-in: {}", names.join(", "));
+        }
+        for server in &self.servers {
+            let names: Vec<&str> =
+                server.tools.iter().map(|tool| tool.name.as_str()).collect();
+            eprintln!("{} (MCP): {}", server.name, names.join(", "));
+        }
+        for (name, reason) in &self.unavailable {
+            eprintln!("{} (MCP): unavailable, {}", name, reason);
+        }
+    }
+}
+
+fn run(tool: Tool, arguments: &str) -> Result<String, Box<dyn Error>> {
+    let arguments = if arguments.trim().is_empty() {
+        "{}"
+    } else {
+        arguments
+    };
+    match tool {
+        Tool::ReadFile => {
+            let args: PathArgs = serde_json::from_str(arguments)?;
+            let path = sandboxed(args.path.as_deref().ok_or("missing path")?)?;
+            let bytes = fs::read(&path)?;
+            Ok(
+                String::from_utf8(bytes)
+                    .map_err(|_| "not a UTF-8 text file")?,
+            )
+        }
+        Tool::ListDirectory => {
+            let args: PathArgs = serde_json::from_str(arguments)?;
+            let path = sandboxed(args.path.as_deref().unwrap_or("."))?;
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let mut name = entry.file_name().to_string_lossy().into_owned();
+                if entry.file_type()?.is_dir() {
+                    name.push('/');
+                }
+                entries.push(name);
+            }
+            entries.sort();
+            Ok(entries.join("\n"))
+        }
+        Tool::GetDatetime => Ok(Local::now()
+            .format("%Y-%m-%d (%A) %H:%M:%S %:z")
+            .to_string()),
+        Tool::Shell => {
+            let args: ShellArgs = serde_json::from_str(arguments)?;
+            eprintln!("The model wants to run:\n\n    {}\n", args.command);
+            match ask_terminal("Run it?")? {
+                Some(true) => {}
+                Some(false) => return Ok("the user declined to run it".into()),
+                None => Err("there is no terminal to confirm it on")?,
+            }
+            let (shell, _) = exec::user_shell();
+            let flag = if cfg!(windows) { "/C" } else { "-c" };
+            let output = process::Command::new(shell)
+                .args([flag, &args.command])
+                .output()?;
+            Ok(format!(
+                "exit status: {}\n\nstdout:\n{}\n\nstderr:\n{}",
+                output
+                    .status
+                    .code()
+                    .map_or("none".into(), |c| c.to_string()),
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ))
+        }
+    }
+}
+
+/// Resolves a path the model gave, refusing any outside the current
+/// directory, through `..` or a symlink alike.
+fn sandboxed(path: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let root = env::current_dir()?.canonicalize()?;
+    let resolved = root
+        .join(Path::new(path))
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if !resolved.starts_with(&root) {
+        Err(format!("{} is outside the current directory", path))?
+    }
+    Ok(resolved)
+}
+
+fn truncate(mut output: String) -> String {
+    if output.len() > MAX_OUTPUT_BYTES {
+        let mut end = MAX_OUTPUT_BYTES;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output.push_str("\n[truncated]");
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termgpt::message::FunctionCall;
+
+    #[test]
+    fn paths_outside_the_current_directory_are_refused() {
+        assert!(sandboxed("..").is_err());
+        assert!(sandboxed("/").is_err());
+        assert!(sandboxed("src/../Cargo.toml").is_ok());
+    }
+
+    #[test]
+    fn unknown_tools_are_reported_to_the_model() {
+        let call = ToolCall {
+            id: "call_1".into(),
+            kind: "function".into(),
+            function: FunctionCall {
+                name: "shell".into(),
+                arguments: "{}".into(),
+            },
+        };
+        let result = Toolbox::new(vec![Tool::GetDatetime]).call(&call);
+        assert_eq!(result.tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(result.content, "error: there is no tool called shell");
+    }
+
+    #[test]
+    fn server_tool_names_fit_the_api() {
+        assert_eq!(qualified_name("fs", "read_file"), "fs__read_file");
+        assert_eq!(qualified_name("my.server", "get it"), "my_server__get_it");
+        assert_eq!(qualified_name("s", &"x".repeat(100)).len(), 64);
+    }
+}
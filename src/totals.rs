@@ -0,0 +1,82 @@
+use termgpt::api::{estimate_cost, ChatGptUsage};
+
+/// What a REPL session has used, summed over its responses for the
+/// summary printed when it exits.
+#[derive(Default)]
+pub struct SessionTotals {
+    turns: usize,
+    tokens: u64,
+    cost: f64,
+    /// Whether any response reported no usage, or was of a model with no
+    /// known price, so that the totals are only a lower bound.
+    incomplete: bool,
+}
+
+impl SessionTotals {
+    /// Counts a message sent, whether or not its answer is kept.
+    pub fn turn(&mut self) {
+        self.turns += 1;
+    }
+
+    pub fn turns(&self) -> usize {
+        self.turns
+    }
+
+    /// Adds the usage of a response from `model`.
+    pub fn add(&mut self, model: &str, usage: Option<&ChatGptUsage>) {
+        let Some(usage) = usage else {
+            self.incomplete = true;
+            return;
+        };
+        self.tokens += u64::from(usage.total_tokens);
+        match estimate_cost(model, usage) {
+            Some(cost) => self.cost += cost,
+            None => self.incomplete = true,
+        }
+    }
+
+    /// One line for stderr, such as `3 turns, 1234 tokens (~$0.0021),
+    /// saved to notes.jsonl`.
+    pub fn describe(&self, session: Option<&str>) -> String {
+        let plural = if self.turns == 1 { "" } else { "s" };
+        let mut line = format!("{} turn{}", self.turns, plural);
+        if self.tokens > 0 {
+            let at_least = if self.incomplete { "at least " } else { "" };
+            line.push_str(&format!(
+                ", {}{} tokens (~${:.4})",
+                at_least, self.tokens, self.cost
+            ));
+        }
+        if let Some(session) = session {
+            line.push_str(&format!(", saved to {}", session));
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_without_usage_make_the_totals_a_lower_bound() {
+        let mut totals = SessionTotals::default();
+        assert_eq!(totals.describe(None), "0 turns");
+
+        totals.turn();
+        let usage = ChatGptUsage {
+            prompt_tokens: 800,
+            completion_tokens: 200,
+            total_tokens: 1000,
+        };
+        totals.add("gpt-4o", Some(&usage));
+        assert_eq!(totals.describe(None), "1 turn, 1000 tokens (~$0.0040)");
+
+        totals.turn();
+        totals.add("gpt-4o", None);
+        assert_eq!(
+            totals.describe(Some("notes.jsonl")),
+            "2 turns, at least 1000 tokens (~$0.0040), saved to notes.jsonl"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! A wire-level log of API requests, for debugging.
+
+use chrono::{SecondsFormat, Utc};
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Headers whose values are left out of the trace.
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "set-cookie",
+    "openai-organization",
+    "openai-project",
+];
+
+/// Appends each request made, and what came back, to a file as a line of
+/// JSON. Retries are traced as requests of their own.
+pub struct TraceFile {
+    file: Mutex<File>,
+}
+
+impl TraceFile {
+    pub fn open(filename: &str) -> io::Result<TraceFile> {
+        Ok(TraceFile {
+            file: Mutex::new(
+                File::options().create(true).append(true).open(filename)?,
+            ),
+        })
+    }
+
+    /// Writes out an exchange. The trace is only a debugging aid, so a
+    /// failure to write it is reported rather than failing the request.
+    pub fn record(&self, entry: &TraceEntry) {
+        let mut line = serde_json::to_string(entry).unwrap_or_default();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            eprintln!("termgpt: could not write trace: {}", e);
+        }
+    }
+}
+
+/// One request and its response, or the error that came of it.
+#[derive(Serialize)]
+pub struct TraceEntry {
+    time: String,
+    url: String,
+    request: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip)]
+    start: Option<Instant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elapsed_ms: Option<u128>,
+}
+
+impl TraceEntry {
+    pub fn new(url: &str, request: Value) -> TraceEntry {
+        TraceEntry {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            url: url.to_string(),
+            request,
+            status: None,
+            headers: BTreeMap::new(),
+            response: None,
+            error: None,
+            start: Some(Instant::now()),
+            elapsed_ms: None,
+        }
+    }
+
+    pub fn set_status(&mut self, status: u16, headers: &HeaderMap) {
+        self.status = Some(status);
+        self.headers = headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                    "[REDACTED]".to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+    }
+
+    /// Sets the response body, as JSON if it is JSON and as text if not,
+    /// as with a stream of server-sent events.
+    pub fn set_response(&mut self, body: &[u8]) {
+        self.response =
+            Some(serde_json::from_slice(body).unwrap_or_else(|_| {
+                Value::String(String::from_utf8_lossy(body).into_owned())
+            }));
+        self.finish();
+    }
+
+    pub fn set_error(&mut self, error: &dyn std::fmt::Display) {
+        self.error = Some(error.to_string());
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        self.elapsed_ms = self.start.map(|start| start.elapsed().as_millis());
+    }
+}
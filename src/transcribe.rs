@@ -0,0 +1,134 @@
+use crate::spinner::Spinner;
+use clap::{Args, ValueHint};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+use termgpt::api::{
+    ChatGptClient, TranscriptionRequest, DEFAULT_TRANSCRIPTION_MODEL,
+};
+use termimad::crossterm::tty::IsTty;
+
+/// The extensions of the audio formats the transcription endpoint takes.
+const FORMATS: &[&str] = &[
+    "flac", "m4a", "mp3", "mp4", "mpeg", "mpga", "oga", "ogg", "wav", "webm",
+];
+
+/// The largest file the transcription endpoint takes.
+const MAX_AUDIO_BYTES: u64 = 25 * 1024 * 1024;
+
+#[derive(Args)]
+pub struct TranscribeArgs {
+    /// The audio file to transcribe
+    #[arg(value_name = "AUDIO_FILE", value_hint = ValueHint::FilePath)]
+    pub file: String,
+
+    /// The language spoken, such as "en", to help the model along
+    #[arg(long, value_name = "CODE")]
+    language: Option<String>,
+
+    /// The speech-to-text model to use
+    #[arg(long, value_name = "MODEL")]
+    transcription_model: Option<String>,
+
+    /// Send the transcript to the chat model as the prompt
+    #[arg(long)]
+    pub chat: bool,
+}
+
+/// How to turn a file into one the endpoint takes.
+fn conversion_hint(path: &Path) -> String {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    format!(
+        "convert it first, such as with: ffmpeg -i {} {}.mp3",
+        path.display(),
+        stem
+    )
+}
+
+/// Refuses files the endpoint wouldn't take, before they're uploaded.
+fn check_audio(path: &Path, size: u64) -> Result<(), Box<dyn Error>> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if !FORMATS.contains(&extension.as_str()) {
+        Err(format!(
+            "{} isn't in a format the API takes ({}); {}",
+            path.display(),
+            FORMATS.join(", "),
+            conversion_hint(path)
+        ))?
+    }
+    if size > MAX_AUDIO_BYTES {
+        Err(format!(
+            "{} is {} MB, more than the API's limit of {} MB; split it or \
+             compress it first, such as with: ffmpeg -i {} -b:a 32k {}.mp3",
+            path.display(),
+            size.div_ceil(1024 * 1024),
+            MAX_AUDIO_BYTES / (1024 * 1024),
+            path.display(),
+            path.file_stem().unwrap_or_default().to_string_lossy()
+        ))?
+    }
+    Ok(())
+}
+
+/// Uploads an audio file to be transcribed, returning its text.
+#[tokio::main]
+pub async fn run_transcribe(
+    client: &ChatGptClient,
+    args: &TranscribeArgs,
+) -> Result<String, Box<dyn Error>> {
+    let path = Path::new(&args.file);
+    let metadata = fs::metadata(path)
+        .map_err(|e| format!("could not read {}: {}", args.file, e))?;
+    check_audio(path, metadata.len())?;
+    let audio = fs::read(path)
+        .map_err(|e| format!("could not read {}: {}", args.file, e))?;
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+    let request = TranscriptionRequest {
+        model: args
+            .transcription_model
+            .as_deref()
+            .unwrap_or(DEFAULT_TRANSCRIPTION_MODEL),
+        filename: &filename,
+        audio,
+        language: args.language.as_deref(),
+    };
+    let spinner = io::stdout().is_tty().then(Spinner::start);
+    let result = client.transcribe(request).await;
+    drop(spinner);
+    // A file whose contents aren't what its extension says is only caught
+    // by the API, which just says the format is invalid.
+    result.map_err(|e| {
+        let message = e.to_string();
+        if message.to_lowercase().contains("format") {
+            format!("{}; {}", message, conversion_hint(path)).into()
+        } else {
+            e
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_formats_are_refused_with_a_hint() {
+        let err = check_audio(Path::new("memo.aiff"), 100).unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("memo.aiff isn't in a format"));
+        assert!(message.ends_with("ffmpeg -i memo.aiff memo.mp3"));
+        assert!(check_audio(Path::new("memo.M4A"), 100).is_ok());
+    }
+
+    #[test]
+    fn files_over_the_limit_are_refused() {
+        let err =
+            check_audio(Path::new("talk.wav"), 30 * 1024 * 1024).unwrap_err();
+        assert!(err.to_string().starts_with("talk.wav is 30 MB"));
+        assert!(check_audio(Path::new("talk.wav"), MAX_AUDIO_BYTES).is_ok());
+    }
+}
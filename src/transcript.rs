@@ -0,0 +1,198 @@
+use clap::ValueEnum;
+use pulldown_cmark::{html, Event, Parser as MarkdownParser, Tag, TagEnd};
+use pulldown_cmark_escape::escape_html;
+use std::error::Error;
+use std::fs;
+use termgpt::listener::ChatMessageListener;
+use termgpt::message::ChatGptMessage;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum TranscriptFormat {
+    /// Markdown with a heading for each message
+    Markdown,
+    /// A self-contained HTML page with the markdown rendered
+    Html,
+}
+
+const CSS: &str = "\
+body { max-width: 48em; margin: 2em auto; padding: 0 1em;
+       font: 16px/1.5 system-ui, sans-serif; color: #222; }
+section { margin: 1em 0; padding: 0.5em 1em; border-radius: 6px; }
+section.user { background: #eef4ff; }
+section.assistant { background: #f5f5f5; }
+section.system { background: #fff8e6; font-size: 0.9em; }
+h2 { margin: 0.2em 0; font-size: 0.8em; text-transform: uppercase;
+     letter-spacing: 0.05em; color: #666; }
+pre { background: #272822; color: #f8f8f2; padding: 0.8em;
+      overflow-x: auto; border-radius: 4px; }
+code { font-family: ui-monospace, monospace; font-size: 0.9em; }
+";
+
+fn render_markdown_transcript(messages: &[ChatGptMessage]) -> String {
+    let mut out = String::new();
+    for message in messages {
+        out.push_str(&format!(
+            "## {}\n\n{}\n\n",
+            message.role.title(),
+            message.content.trim()
+        ));
+        for image in &message.images {
+            out.push_str(&format!("{}\n\n", image.note()));
+        }
+    }
+    out
+}
+
+fn escaped(text: &str) -> String {
+    let mut out = String::new();
+    let _ = escape_html(&mut out, text);
+    out
+}
+
+/// Whether a link may be followed from a page meant to be shared: one to
+/// the web or an email address, or a relative one, but never `javascript:`
+/// or `data:`. Browsers ignore whitespace and control characters in the
+/// scheme, so they are ignored here too.
+fn is_safe_url(url: &str) -> bool {
+    let url: String = url.chars().filter(|c| !c.is_ascii_control()).collect();
+    let url = url.trim();
+    match url.find([':', '/', '?', '#']) {
+        Some(end) if url[end..].starts_with(':') => {
+            let scheme = url[..end].replace(' ', "").to_ascii_lowercase();
+            matches!(scheme.as_str(), "http" | "https" | "mailto")
+        }
+        _ => true,
+    }
+}
+
+/// A link or image whose URL isn't safe to follow, pointed nowhere.
+fn defused(tag: Tag) -> Tag {
+    match tag {
+        Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        } if !is_safe_url(&dest_url) => Tag::Link {
+            link_type,
+            dest_url: "#".into(),
+            title,
+            id,
+        },
+        Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        } if !is_safe_url(&dest_url) => Tag::Image {
+            link_type,
+            dest_url: "#".into(),
+            title,
+            id,
+        },
+        tag => tag,
+    }
+}
+
+fn render_html_transcript(messages: &[ChatGptMessage]) -> String {
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str("<title>termgpt transcript</title>\n");
+    out.push_str(&format!("<style>\n{}</style>\n</head>\n<body>\n", CSS));
+    for message in messages {
+        out.push_str(&format!(
+            "<section class=\"{}\">\n<h2>{}</h2>\n",
+            escaped(message.role.name()),
+            escaped(message.role.title())
+        ));
+        // Raw HTML in a message is shown as text rather than rendered, and
+        // links that would run script are defused.
+        let events =
+            MarkdownParser::new(&message.content).map(|event| match event {
+                Event::Html(html) | Event::InlineHtml(html) => {
+                    Event::Text(html)
+                }
+                Event::Start(tag) => Event::Start(defused(tag)),
+                event => event,
+            });
+        html::push_html(&mut out, events);
+        for image in &message.images {
+            let note = [
+                Event::Start(Tag::Paragraph),
+                Event::Text(image.note().into()),
+                Event::End(TagEnd::Paragraph),
+            ];
+            html::push_html(&mut out, note.into_iter());
+        }
+        out.push_str("</section>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Keeps the whole conversation and rewrites the transcript file with it
+/// after every message, so the file is complete whenever termgpt exits.
+pub struct TranscriptListener {
+    filename: String,
+    format: TranscriptFormat,
+    messages: Vec<ChatGptMessage>,
+}
+
+impl TranscriptListener {
+    pub fn new(
+        filename: &str,
+        format: TranscriptFormat,
+        messages: &[ChatGptMessage],
+    ) -> TranscriptListener {
+        TranscriptListener {
+            filename: filename.to_string(),
+            format,
+            messages: messages.to_vec(),
+        }
+    }
+}
+
+impl ChatMessageListener for TranscriptListener {
+    fn on_message(
+        &mut self,
+        message: &ChatGptMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        self.messages.push(message.clone());
+        let text = match self.format {
+            TranscriptFormat::Markdown => {
+                render_markdown_transcript(&self.messages)
+            }
+            TranscriptFormat::Html => render_html_transcript(&self.messages),
+        };
+        // Replaced rather than rewritten in place, so that the file is
+        // never left half written.
+        let temporary = format!("{}.tmp", self.filename);
+        fs::write(&temporary, text)?;
+        fs::rename(&temporary, &self.filename)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termgpt::message::Role;
+
+    #[test]
+    fn html_transcripts_run_no_script() {
+        let messages = [ChatGptMessage::new(
+            Role::Other("x\"><script>".into()),
+            "[docs](https://example.com) [x](JavaScript:alert(1)) \
+                 [y](<vbscript:msgbox(1)>) ![z](data:text/html,hi) \
+                 [notes](notes.md)"
+                .into(),
+        )];
+        let html = render_html_transcript(&messages);
+        assert!(!html.contains("<script>"), "{}", html);
+        assert!(html.contains("class=\"x&quot;&gt;&lt;script&gt;\""));
+        assert!(html.contains("href=\"https://example.com\""));
+        assert!(html.contains("href=\"notes.md\""));
+        assert_eq!(html.matches("href=\"#\"").count(), 2, "{}", html);
+        assert!(html.contains("src=\"#\""), "{}", html);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::prepend::fence;
+use crate::shutdown::Shutdown;
+use clap::{Args, ValueHint};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use termgpt::api::{ChatApi, ChatGptClient, ChatGptParams};
+use termgpt::message::{ChatGptMessage, Role};
+use termimad::crossterm::tty::IsTty;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::time::{self, Instant};
+
+/// What is asked about the file without --prompt.
+const DEFAULT_PROMPT: &str = "Review this file.";
+
+#[derive(Args)]
+pub struct WatchArgs {
+    /// The file to send each time it changes
+    #[arg(value_hint = ValueHint::FilePath)]
+    file: String,
+
+    /// What to ask about the file
+    #[arg(short, long)]
+    prompt: Option<String>,
+
+    /// How long the file must go unchanged before it is sent
+    #[arg(long, value_name = "MS", default_value_t = 300)]
+    debounce: u64,
+}
+
+/// The prompt followed by the file under its path, as --prepend-file
+/// sends it.
+fn request_text(prompt: &str, path: &Path, contents: &str) -> String {
+    let language = path.extension().and_then(|e| e.to_str());
+    format!(
+        "{}\n\n{}:\n\n{}",
+        prompt,
+        path.display(),
+        fence(contents, language.unwrap_or(""))
+    )
+}
+
+async fn ask(
+    client: ChatGptClient,
+    params: ChatGptParams,
+    system: Option<String>,
+    text: String,
+) -> Result<String, String> {
+    let mut messages: Vec<ChatGptMessage> = system
+        .into_iter()
+        .map(|system| ChatGptMessage::new(Role::System, system))
+        .collect();
+    messages.push(ChatGptMessage::new(Role::User, text));
+    let response = client
+        .get_chatgpt_response(&params, &messages)
+        .await
+        .map_err(|e| e.to_string())?;
+    match response.choices.into_iter().next() {
+        Some(choice) => Ok(choice.message.content),
+        None => Err("the API returned no answer".to_string()),
+    }
+}
+
+/// Sends the file with the prompt whenever it changes, each time as a new
+/// conversation, and shows the answer in place of the last one. A change
+/// while an answer is awaited cancels the request and starts over, and an
+/// error is shown and waits for the next change, until Ctrl-C or another
+/// signal to stop.
+#[tokio::main]
+pub async fn run_watch(
+    client: &ChatGptClient,
+    params: &ChatGptParams,
+    system: Option<&str>,
+    args: &WatchArgs,
+    render: impl Fn(&str) -> String,
+) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(&args.file);
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("{} is not a file", args.file))?
+        .to_os_string();
+    // Editors often save by replacing the file, so it is the directory
+    // that is watched.
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (changed, mut changes) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let touched = event.is_ok_and(|event| {
+                matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_)
+                ) && event.paths.iter().any(|p| p.file_name() == Some(&name))
+            });
+            if touched {
+                let _ = changed.send(());
+            }
+        })?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .map_err(|e| format!("could not watch {}: {}", args.file, e))?;
+
+    let prompt = args.prompt.as_deref().unwrap_or(DEFAULT_PROMPT);
+    let debounce = Duration::from_millis(args.debounce);
+    let clear = io::stdout().is_tty();
+    let (answered, mut answers) = mpsc::unbounded_channel();
+    // When the file is next due to be sent, which it is to begin with.
+    let mut due = Some(Instant::now());
+    let mut request: Option<AbortHandle> = None;
+    // Which request is current, so that an answer to one cancelled just
+    // as it arrived is ignored.
+    let mut generation = 0;
+    // Subscribed to once, so that a signal that comes while a change is
+    // being dealt with is still waiting on the next time round.
+    let shutdown = Shutdown::listen();
+    let mut signals = shutdown.subscribe();
+    loop {
+        tokio::select! {
+            _ = signals.next() => break,
+            Some(()) = changes.recv() => {
+                if let Some(request) = request.take() {
+                    request.abort();
+                }
+                due = Some(Instant::now() + debounce);
+            }
+            _ = time::sleep_until(due.unwrap_or_else(Instant::now)),
+                if due.is_some() =>
+            {
+                due = None;
+                generation += 1;
+                if clear {
+                    print!("\x1b[2J\x1b[H");
+                    io::stdout().flush()?;
+                }
+                let contents = match fs::read_to_string(path) {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        let file = &args.file;
+                        eprintln!("termgpt: could not read {}: {}", file, e);
+                        continue;
+                    }
+                };
+                eprintln!("termgpt: asking about {}…", args.file);
+                let text = request_text(prompt, path, &contents);
+                let answer = ask(
+                    client.clone(),
+                    params.clone(),
+                    system.map(String::from),
+                    text,
+                );
+                let answered = answered.clone();
+                let id = generation;
+                let task = tokio::spawn(async move {
+                    let _ = answered.send((id, answer.await));
+                });
+                request = Some(task.abort_handle());
+            }
+            Some((id, answer)) = answers.recv() => {
+                if id != generation {
+                    continue;
+                }
+                request = None;
+                match answer {
+                    Ok(answer) => println!("{}", render(&answer)),
+                    Err(e) => eprintln!("termgpt: {}", e),
+                }
+                eprintln!(
+                    "termgpt: watching {} for changes (Ctrl-C to stop)",
+                    args.file
+                );
+            }
+        }
+    }
+    if let Some(request) = request {
+        request.abort();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_file_is_sent_under_its_path() {
+        assert_eq!(
+            request_text("Review this.", Path::new("src/a.rs"), "fn a() {}\n"),
+            "Review this.\n\nsrc/a.rs:\n\n```rs\nfn a() {}\n```"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::error::Error;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use termgpt::listener::ChatMessageListener;
+use termgpt::message::{ChatGptMessage, Role};
+
+/// How long each attempt to deliver a message may take.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times a message is sent before giving up on it.
+const ATTEMPTS: u32 = 3;
+
+#[derive(Serialize)]
+struct WebhookMessage {
+    id: Option<u64>,
+    role: Role,
+    content: String,
+    timestamp: String,
+    model: String,
+    session: Option<String>,
+}
+
+/// Posts each message as JSON to a URL. Messages are delivered in order
+/// from a background thread so that a slow or failing endpoint doesn't
+/// hold up the conversation; failures are reported on stderr.
+pub struct WebhookListener {
+    sender: Option<mpsc::Sender<WebhookMessage>>,
+    worker: Option<thread::JoinHandle<()>>,
+    model: String,
+    session: Option<String>,
+}
+
+impl WebhookListener {
+    /// Uses the given client, so that connections are shared with it.
+    pub fn new(
+        http: reqwest::Client,
+        url: String,
+        token: Option<String>,
+        model: &str,
+        session: Option<&str>,
+    ) -> Result<WebhookListener, Box<dyn Error>> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let (sender, receiver) = mpsc::channel::<WebhookMessage>();
+        let worker = thread::spawn(move || {
+            for message in receiver {
+                let delivery = deliver(&http, &url, token.as_deref(), &message);
+                if let Err(e) = runtime.block_on(delivery) {
+                    eprintln!("termgpt: webhook {}: {}", url, e);
+                }
+            }
+        });
+        Ok(WebhookListener {
+            sender: Some(sender),
+            worker: Some(worker),
+            model: model.to_string(),
+            session: session.map(String::from),
+        })
+    }
+}
+
+async fn deliver(
+    http: &reqwest::Client,
+    url: &str,
+    token: Option<&str>,
+    message: &WebhookMessage,
+) -> Result<(), Box<dyn Error>> {
+    let mut attempt = 1;
+    loop {
+        let mut request = http.post(url).json(message).timeout(TIMEOUT);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let error = match request.send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response)
+                if !response.status().is_server_error()
+                    && response.status().as_u16() != 429 =>
+            {
+                return Err(format!("status {}", response.status()).into());
+            }
+            Ok(response) => format!("status {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+        if attempt == ATTEMPTS {
+            return Err(format!("{} after {} attempts", error, ATTEMPTS).into());
+        }
+        tokio::time::sleep(Duration::from_millis(500 << attempt)).await;
+        attempt += 1;
+    }
+}
+
+impl ChatMessageListener for WebhookListener {
+    fn on_message(
+        &mut self,
+        message: &ChatGptMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        let message = WebhookMessage {
+            id: message.id,
+            role: message.role.clone(),
+            content: message.content.clone(),
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            model: self.model.clone(),
+            session: self.session.clone(),
+        };
+        // The worker only stops once the listener is dropped.
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(message);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WebhookListener {
+    /// Waits for messages still being delivered before exiting.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
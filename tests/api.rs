@@ -0,0 +1,511 @@
+use serde_json::json;
+use std::sync::Arc;
+use termgpt::api::{
+    ChatApi, ChatGptClient, ChatGptParams, ImageRequest, TranscriptionRequest,
+};
+use termgpt::images::ImageAttachment;
+use termgpt::message::{ChatGptMessage, Role};
+use termgpt::trace::TraceFile;
+use wiremock::matchers::{
+    body_partial_json, body_string_contains, header, method, path,
+};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn client(server: &MockServer) -> ChatGptClient {
+    ChatGptClient::new("test-key".into(), server.uri(), "termgpt-test")
+}
+
+fn params() -> ChatGptParams {
+    ChatGptParams {
+        model: "gpt-4o-mini".into(),
+        ..Default::default()
+    }
+}
+
+fn conversation() -> Vec<ChatGptMessage> {
+    vec![ChatGptMessage::new(Role::User, "Hello".into())]
+}
+
+fn completion(content: &str) -> serde_json::Value {
+    json!({
+        "model": "gpt-4o-mini",
+        "choices": [{
+            "message": {"role": "assistant", "content": content},
+            "finish_reason": "stop"
+        }],
+        "usage": {
+            "prompt_tokens": 5,
+            "completion_tokens": 2,
+            "total_tokens": 7
+        }
+    })
+}
+
+#[tokio::test]
+async fn completion_is_returned() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .and(header("authorization", "Bearer test-key"))
+        .and(body_partial_json(json!({
+            "model": "gpt-4o-mini",
+            "messages": [{"role": "user", "content": "Hello"}]
+        })))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(completion("Hi!")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let response = client(&server)
+        .get_chatgpt_response(&params(), &conversation())
+        .await
+        .unwrap();
+    assert_eq!(response.model.as_deref(), Some("gpt-4o-mini"));
+    assert_eq!(response.choices[0].message.role, Role::Assistant);
+    assert_eq!(response.choices[0].message.content, "Hi!");
+    assert_eq!(response.choices[0].finish_reason.as_deref(), Some("stop"));
+    assert_eq!(response.usage.unwrap().total_tokens, 7);
+}
+
+#[tokio::test]
+async fn attached_images_are_sent_as_content_parts() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({
+            "messages": [{
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {
+                        "type": "image_url",
+                        "image_url": {"url": "data:image/png;base64,AAAA"}
+                    },
+                    {
+                        "type": "text",
+                        "text": "[an image attached here, /gone.png, is no \
+                                 longer available]"
+                    }
+                ]
+            }]
+        })))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(completion("A dot.")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut message = ChatGptMessage::new(Role::User, "What is this?".into());
+    message.images = vec![
+        ImageAttachment {
+            path: "/dot.png".into(),
+            sha256: String::new(),
+            data_url: Some("data:image/png;base64,AAAA".into()),
+        },
+        ImageAttachment {
+            path: "/gone.png".into(),
+            sha256: String::new(),
+            data_url: None,
+        },
+    ];
+    let response = client(&server)
+        .get_chatgpt_response(&params(), &[message])
+        .await
+        .unwrap();
+    assert_eq!(response.choices[0].message.content, "A dot.");
+}
+
+#[tokio::test]
+async fn api_error_message_is_reported() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "error": {"message": "Incorrect API key provided"}
+        })))
+        .mount(&server)
+        .await;
+
+    let error = client(&server)
+        .get_chatgpt_response(&params(), &conversation())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "API error (401 Unauthorized): Incorrect API key provided"
+    );
+}
+
+#[tokio::test]
+async fn rate_limited_request_is_retried_after_the_given_delay() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(429).insert_header("retry-after", "0"),
+        )
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(completion("Hi!")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let response = client(&server)
+        .get_chatgpt_response(&params(), &conversation())
+        .await
+        .unwrap();
+    assert_eq!(response.choices[0].message.content, "Hi!");
+}
+
+#[tokio::test]
+async fn invalid_json_is_an_error() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"{"choices": [{"message": {"ro"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+
+    let result = client(&server)
+        .get_chatgpt_response(&params(), &conversation())
+        .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn streamed_response_is_passed_on_as_it_arrives() {
+    let server = MockServer::start().await;
+    let chunk = |content: &str, finish_reason: Option<&str>| {
+        json!({
+            "model": "gpt-4o-mini",
+            "choices": [{
+                "delta": {"content": content},
+                "finish_reason": finish_reason
+            }]
+        })
+    };
+    let usage = json!({
+        "choices": [],
+        "usage": {
+            "prompt_tokens": 5,
+            "completion_tokens": 3,
+            "total_tokens": 8
+        }
+    });
+    let body = [
+        chunk("Hel", None),
+        chunk("lo", None),
+        chunk(" there", Some("stop")),
+        usage,
+    ]
+    .iter()
+    .map(|event| format!("data: {}\n\n", event))
+    .collect::<String>()
+        + "data: [DONE]\n\n";
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({
+            "stream": true,
+            "stream_options": {"include_usage": true}
+        })))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut chunks = Vec::new();
+    let response = client(&server)
+        .stream_chatgpt_response(&params(), &conversation(), true, |text| {
+            chunks.push(text.to_string());
+            Ok(())
+        })
+        .await
+        .unwrap();
+    assert_eq!(chunks, ["Hel", "lo", " there"]);
+    assert_eq!(response.choices[0].message.content, "Hello there");
+    assert_eq!(response.choices[0].finish_reason.as_deref(), Some("stop"));
+    assert_eq!(response.usage.unwrap().completion_tokens, 3);
+}
+
+#[tokio::test]
+async fn flagged_categories_are_collected() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/moderations"))
+        .and(body_partial_json(json!({
+            "model": "omni-moderation-latest",
+            "input": ["first", "second"]
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "results": [
+                {
+                    "flagged": false,
+                    "categories": {"harassment": false, "violence": false}
+                },
+                {
+                    "flagged": true,
+                    "categories": {"harassment": false, "violence": true}
+                }
+            ]
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let moderation = client(&server)
+        .moderate("omni-moderation-latest", &["first", "second"])
+        .await
+        .unwrap();
+    assert!(moderation.flagged);
+    assert_eq!(moderation.categories, ["violence"]);
+}
+
+#[tokio::test]
+async fn trace_file_records_errors_and_retries() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(429).insert_header("retry-after", "0"),
+        )
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "error": {"message": "Bad request"}
+        })))
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("trace.jsonl");
+    let mut client = client(&server);
+    client.trace =
+        Some(Arc::new(TraceFile::open(path.to_str().unwrap()).unwrap()));
+    let result = client
+        .get_chatgpt_response(&params(), &conversation())
+        .await;
+    assert!(result.is_err());
+
+    let trace = std::fs::read_to_string(&path).unwrap();
+    let entries: Vec<serde_json::Value> = trace
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["status"], 429);
+    assert_eq!(entries[1]["status"], 400);
+    assert_eq!(entries[1]["request"]["messages"][0]["content"], "Hello");
+    assert_eq!(entries[1]["response"]["error"]["message"], "Bad request");
+}
+
+fn image_request(prompt: &str) -> ImageRequest<'_> {
+    ImageRequest {
+        model: "gpt-image-1",
+        prompt,
+        size: Some("1024x1024"),
+        n: None,
+        response_format: None,
+    }
+}
+
+#[tokio::test]
+async fn generated_images_are_decoded() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .and(body_partial_json(json!({
+            "model": "gpt-image-1",
+            "prompt": "a heron",
+            "size": "1024x1024"
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": [{
+                "b64_json": "iVBORw0KGgo=",
+                "revised_prompt": "a grey heron in watercolor"
+            }]
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let images = client(&server)
+        .generate_images(&image_request("a heron"))
+        .await
+        .unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0].data, b"\x89PNG\r\n\x1a\n");
+    assert_eq!(
+        images[0].revised_prompt.as_deref(),
+        Some("a grey heron in watercolor")
+    );
+}
+
+#[tokio::test]
+async fn image_refusals_are_reported() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "error": {
+                "message": "Your request was rejected by the safety system.",
+                "code": "content_policy_violation"
+            }
+        })))
+        .mount(&server)
+        .await;
+
+    let error = client(&server)
+        .generate_images(&image_request("something"))
+        .await
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("rejected by the safety system"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn audio_is_uploaded_as_a_form() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/audio/transcriptions"))
+        .and(header("Authorization", "Bearer test-key"))
+        .and(body_string_contains("name=\"model\"\r\n\r\nwhisper-1"))
+        .and(body_string_contains("name=\"language\"\r\n\r\nen"))
+        .and(body_string_contains("filename=\"memo.mp3\""))
+        .and(body_string_contains("ID3 audio"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "text": "Buy milk."
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let text = client(&server)
+        .transcribe(TranscriptionRequest {
+            model: "whisper-1",
+            filename: "memo.mp3",
+            audio: b"ID3 audio".to_vec(),
+            language: Some("en"),
+        })
+        .await
+        .unwrap();
+    assert_eq!(text, "Buy milk.");
+}
+
+#[tokio::test]
+async fn cacheable_messages_get_cache_hints() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(completion("Hi!")),
+        )
+        .mount(&server)
+        .await;
+
+    let cacheable = |role, content: &str| ChatGptMessage {
+        cacheable: true,
+        ..ChatGptMessage::new(role, content.into())
+    };
+    let mut messages = vec![cacheable(Role::System, "Be brief.")];
+    messages.extend((1..=4).map(|i| cacheable(Role::User, &format!("f{}", i))));
+    messages.push(ChatGptMessage::new(Role::User, "Hello".into()));
+    let mut client = client(&server);
+    client
+        .get_chatgpt_response(&params(), &messages)
+        .await
+        .unwrap();
+    client.cache_hints = true;
+    client
+        .get_chatgpt_response(&params(), &messages)
+        .await
+        .unwrap();
+
+    let requests = server.received_requests().await.unwrap();
+    let sent: Vec<serde_json::Value> = requests
+        .iter()
+        .map(|request| request.body_json::<serde_json::Value>().unwrap())
+        .collect();
+    assert_eq!(sent[0]["messages"][0]["content"], "Be brief.");
+    // Only the last four of the five cacheable messages are breakpoints.
+    assert_eq!(sent[1]["messages"][0]["content"], "Be brief.");
+    assert_eq!(
+        sent[1]["messages"][4]["content"],
+        json!([{
+            "type": "text",
+            "text": "f4",
+            "cache_control": {"type": "ephemeral"}
+        }])
+    );
+    assert_eq!(sent[1]["messages"][5]["content"], "Hello");
+}
+
+#[tokio::test]
+async fn embeddings_are_returned_in_input_order() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/embeddings"))
+        .and(body_partial_json(json!({
+            "model": "text-embedding-3-small",
+            "input": ["one", "two"]
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": [
+                {"index": 1, "embedding": [0.5, 0.25]},
+                {"index": 0, "embedding": [1.0, 0.0]}
+            ],
+            "usage": {"prompt_tokens": 2, "total_tokens": 2}
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let embeddings = client(&server)
+        .embed("text-embedding-3-small", &["one", "two"])
+        .await
+        .unwrap();
+    assert_eq!(embeddings.vectors, [vec![1.0, 0.0], vec![0.5, 0.25]]);
+    assert_eq!(embeddings.usage.unwrap().total_tokens, 2);
+}
+
+#[tokio::test]
+async fn examples_are_left_out_of_requests_without_them() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("Classify: 2 + 2"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(completion("math")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(completion("Hi!")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut client = client(&server);
+    client.examples = vec![
+        ChatGptMessage::new(Role::User, "Classify: 2 + 2".into()),
+        ChatGptMessage::new(Role::Assistant, "math".into()),
+    ];
+    let messages = conversation();
+    let with = client.get_chatgpt_response(&params(), &messages).await;
+    assert_eq!(with.unwrap().choices[0].message.content, "math");
+    let without = client.without_examples();
+    let response = without.get_chatgpt_response(&params(), &messages).await;
+    assert_eq!(response.unwrap().choices[0].message.content, "Hi!");
+}
@@ -0,0 +1,544 @@
+use assert_cmd::Command;
+use serde_json::json;
+use wiremock::matchers::{
+    body_partial_json, body_string_contains, method, path,
+};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn termgpt(server: &MockServer) -> Command {
+    let mut command = Command::cargo_bin("termgpt").unwrap();
+    command.env("OPENAI_API_KEY", "test-key").args([
+        "--no-config",
+        "--base-url",
+        &server.uri(),
+    ]);
+    command
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn piped_input_is_sent_with_the_prompt() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .and(body_partial_json(json!({
+            "messages": [{
+                "role": "user",
+                "content": "Summarize\n\n```\nsome notes\n```"
+            }]
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": "Notes."},
+                "finish_reason": "stop"
+            }]
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let output = termgpt(&server)
+        .arg("Summarize")
+        .write_stdin("some notes\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "Notes.");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn api_errors_fail_the_command() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(json!({
+            "error": {"message": "The server had an error"}
+        })))
+        .mount(&server)
+        .await;
+
+    let output = termgpt(&server)
+        .arg("Hello")
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("The server had an error"), "{}", stderr);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn responses_without_choices_fail_the_command() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({"choices": []})),
+        )
+        .mount(&server)
+        .await;
+
+    let output = termgpt(&server)
+        .arg("Hello")
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("no choices returned"), "{}", stderr);
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn unwritable_session_metadata_fails_the_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = dir.path().join("chat.jsonl");
+    std::os::unix::fs::symlink(
+        dir.path().join("missing/meta.json"),
+        dir.path().join("chat.jsonl.meta.json"),
+    )
+    .unwrap();
+    let server = MockServer::start().await;
+
+    let output = termgpt(&server)
+        .arg("--session")
+        .arg(&session)
+        .arg("Hello")
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("could not write"), "{}", stderr);
+    assert!(stderr.contains("chat.jsonl.meta.json"), "{}", stderr);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tool_calls_are_answered_until_the_model_replies() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "buy milk").unwrap();
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("\"tool_call_id\":\"call_1\""))
+        .and(body_string_contains("buy milk"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": "Buy milk."},
+                "finish_reason": "stop"
+            }],
+            "usage": {
+                "prompt_tokens": 30,
+                "completion_tokens": 3,
+                "total_tokens": 33
+            }
+        })))
+        .with_priority(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({
+            "tools": [{"function": {"name": "read_file"}}]
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {
+                            "name": "read_file",
+                            "arguments": "{\"path\":\"notes.txt\"}"
+                        }
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {
+                "prompt_tokens": 20,
+                "completion_tokens": 10,
+                "total_tokens": 30
+            }
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let output = termgpt(&server)
+        .current_dir(dir.path())
+        .args(["--tools", "read_file", "--show-usage"])
+        .arg("What do my notes say?")
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().trim(),
+        "Buy milk."
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("tokens: 50 prompt + 13 completion = 63 total"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn tokens_are_counted_without_an_api_key() {
+    let output = Command::cargo_bin("termgpt")
+        .unwrap()
+        .env_remove("OPENAI_API_KEY")
+        .args([
+            "--no-config",
+            "--count-tokens",
+            "--model",
+            "gpt-4o",
+            "hello",
+        ])
+        .write_stdin("hello world")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "3");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transcripts_can_be_sent_to_the_chat_model() {
+    let dir = tempfile::tempdir().unwrap();
+    let audio = dir.path().join("memo.wav");
+    std::fs::write(&audio, b"RIFF audio").unwrap();
+    let session = dir.path().join("session.jsonl");
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/audio/transcriptions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "text": "What's the capital of France?\n"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .and(body_partial_json(json!({
+            "messages": [{
+                "role": "user",
+                "content": "What's the capital of France?"
+            }]
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": "Paris."},
+                "finish_reason": "stop"
+            }]
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    // Global options have to come after the subcommand.
+    let output = Command::cargo_bin("termgpt")
+        .unwrap()
+        .env("OPENAI_API_KEY", "test-key")
+        .arg("transcribe")
+        .arg(&audio)
+        .args(["--chat", "--no-config", "--base-url", &server.uri()])
+        .arg("--session")
+        .arg(&session)
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "Paris.");
+    let saved = std::fs::read_to_string(&session).unwrap();
+    assert!(saved.contains("What's the capital of France?"), "{}", saved);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_embeddings_are_reported_by_line() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/embeddings"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "error": {"message": "'$.input' is invalid"}
+        })))
+        .mount(&server)
+        .await;
+
+    let output = Command::cargo_bin("termgpt")
+        .unwrap()
+        .env("OPENAI_API_KEY", "test-key")
+        .args([
+            "embed",
+            "--lines",
+            "--no-config",
+            "--base-url",
+            &server.uri(),
+        ])
+        .write_stdin("first\n\nsecond\n")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "[]");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("lines 1 to 3 failed: API error"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("2 of 2 inputs failed"), "{}", stderr);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compared_models_are_keyed_in_the_order_given() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = dir.path().join("session.jsonl");
+    let server = MockServer::start().await;
+    for (model, content) in [("gpt-4o", "Paris."), ("gpt-4o-mini", "Paris!")] {
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_partial_json(json!({"model": model})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "choices": [{
+                    "message": {"role": "assistant", "content": content},
+                    "finish_reason": "stop"
+                }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let output = termgpt(&server)
+        .args(["--compare", "gpt-4o-mini,gpt-4o", "--format", "json"])
+        .args(["--keep-choice", "2", "--session"])
+        .arg(&session)
+        .arg("Capital of France?")
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mini = stdout.find("\"gpt-4o-mini\"").unwrap();
+    let full = stdout.find("\"gpt-4o\"").unwrap();
+    assert!(mini < full, "{}", stdout);
+    let answers: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(answers["gpt-4o"]["content"], "Paris.");
+    let saved = std::fs::read_to_string(&session).unwrap();
+    assert!(
+        saved.contains("Paris.") && !saved.contains("Paris!"),
+        "{}",
+        saved
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cached_responses_are_reused_without_a_request() {
+    let cache = tempfile::tempdir().unwrap();
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": "Paris."},
+                "finish_reason": "stop"
+            }]
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    for note in [false, true] {
+        let output = termgpt(&server)
+            .env("XDG_CACHE_HOME", cache.path())
+            .args(["--cache", "--temperature", "0", "Capital of France?"])
+            .write_stdin("")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "Paris.");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(stderr.contains("using a cached response"), note);
+    }
+
+    let output = Command::cargo_bin("termgpt")
+        .unwrap()
+        .env("XDG_CACHE_HOME", cache.path())
+        .args(["cache", "stats", "--no-config"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("1 responses"), "{}", stdout);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn raw_output_is_printed_byte_for_byte() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": "# hash me\n\n"},
+                "finish_reason": "stop"
+            }]
+        })))
+        .mount(&server)
+        .await;
+
+    let output = termgpt(&server)
+        .args(["--raw-output", "--color", "always", "Hello"])
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"# hash me\n\n");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn model_aliases_from_the_config_are_resolved() {
+    let config = tempfile::tempdir().unwrap();
+    std::fs::create_dir(config.path().join("termgpt")).unwrap();
+    std::fs::write(
+        config.path().join("termgpt/config.toml"),
+        "[models]\nfast = \"gpt-4o-mini\"\n",
+    )
+    .unwrap();
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({"model": "gpt-4o-mini"})))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": "Hi."},
+                "finish_reason": "stop"
+            }]
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let output = Command::cargo_bin("termgpt")
+        .unwrap()
+        .env("OPENAI_API_KEY", "test-key")
+        .env("XDG_CONFIG_HOME", config.path())
+        .args(["--base-url", &server.uri(), "--model", "fast", "Hello"])
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn prefilled_responses_start_with_the_prefill() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = dir.path().join("chat.jsonl");
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({
+            "messages": [
+                {"role": "user", "content": "Capital of France as JSON?"},
+                {"role": "assistant", "content": "{\""}
+            ]
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": "capital\": \"Paris\"}"
+                },
+                "finish_reason": "stop"
+            }]
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let output = termgpt(&server)
+        .args(["--prefill", "{\"", "--session"])
+        .arg(&session)
+        .arg("Capital of France as JSON?")
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().trim(),
+        "{\"capital\": \"Paris\"}"
+    );
+    let saved = std::fs::read_to_string(session).unwrap();
+    let last: serde_json::Value =
+        serde_json::from_str(saved.lines().last().unwrap()).unwrap();
+    assert_eq!(last["content"], "{\"capital\": \"Paris\"}");
+    assert_eq!(last["prefill"], "{\"");
+}
+
+#[test]
+fn one_file_for_the_session_and_output_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = Command::cargo_bin("termgpt")
+        .unwrap()
+        .env("OPENAI_API_KEY", "test-key")
+        .current_dir(dir.path())
+        .args(["--no-config", "--session", "chat.txt"])
+        .args(["--output", "./chat.txt", "Hello"])
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("--session and --output are both"),
+        "{}",
+        stderr
+    );
+    assert!(!dir.path().join("chat.txt").exists());
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn a_session_killed_mid_request_is_left_whole() {
+    use assert_cmd::cargo::CommandCargoExt;
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let session = dir.path().join("notes.jsonl");
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_delay(Duration::from_secs(10))
+                .set_body_json(json!({"choices": []})),
+        )
+        .mount(&server)
+        .await;
+
+    let mut child = std::process::Command::cargo_bin("termgpt")
+        .unwrap()
+        .env("OPENAI_API_KEY", "test-key")
+        .args(["--no-config", "--base-url", &server.uri(), "--session"])
+        .arg(&session)
+        .args(["--stream", "Hello"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Until the request has been sent, and so the message written.
+    for _ in 0..100 {
+        if !server.received_requests().await.unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    unsafe {
+        libc::kill(child.id() as i32, libc::SIGTERM);
+    }
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(128 + libc::SIGTERM));
+
+    let text = std::fs::read_to_string(&session).unwrap();
+    let lines: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1, "{}", text);
+    assert_eq!(lines[0]["content"], "Hello");
+}